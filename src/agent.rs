@@ -1,34 +1,104 @@
+use crate::audit::{AuditEntry, AuditEvent, AuditLog};
 use crate::config::Config;
-use crate::io::{self, IO, IOChan, Input, Output};
-use crate::llm::{DynLLMClient, LLMClient, LLMEventHandler};
-use crate::lua::LuaVM;
+use crate::export::{self, ExportFormat};
+use crate::io::{self, AgentState, IO, IOChan, Input, Output, ScriptState};
+use crate::llm::{DynLLMClient, LLMClient, LLMEventHandler, Status, TranscriptItem};
+use crate::lua::{self, LuaSetup, LuaVM, Snapshot};
+use crate::output;
+use crate::review::{ScriptReview, unified_diff};
+use crate::session::{self, Session};
+use crate::utils::rfc3339;
+use crate::workspace::{self, Workspace};
 use anyhow::{Result, anyhow};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::{Mutex, mpsc};
 
 enum ApprovalTarget {
     All,
+    /// Calls which need no approval.
+    Auto,
 }
 
-struct PendingLua {
-    id: String,
-    code: String,
-    timeout_sec: u64,
-    approved: bool,
-    output: Option<String>,
+/// A call of a native or user-defined tool.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ToolInvocation {
+    pub name: String,
+    pub args: Value,
+}
+
+/// PendingLua is a Lua script or a tool call requested by the LLM.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingLua {
+    pub id: String,
+    /// Lua code, empty for tool calls.
+    pub code: String,
+    /// Code requested by the LLM, if the user modified it.
+    pub original_code: Option<String>,
+    pub timeout_sec: u64,
+    pub approved: bool,
+    pub output: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool: Option<ToolInvocation>,
+    /// Run without asking the user.
+    #[serde(default)]
+    pub auto_approve: bool,
+    /// Named VM to run the script in, instead of the default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vm: Option<String>,
+    /// Audit event which determined the call, once it is determined.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decision: Option<AuditEvent>,
+}
+
+/// Name of the default Lua VM, which also runs tools.
+const DEFAULT_VM: &str = "main";
+
+/// Check the name of a VM, which is given by the LLM.
+fn is_valid_vm_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Returns the setup of the VM running a script, to preview the script.
+fn review_setup(config: &Config, vm: Option<&str>) -> LuaSetup {
+    match vm {
+        Some(name) => config.lua_vm_setup(name),
+        None => config.lua_setup(),
+    }
 }
 
 pub struct AgentResources {
+    /// Record of determined scripts and tool calls.
+    audit: AuditLog,
     pending_lua: Vec<PendingLua>,
     determined_lua: Vec<PendingLua>,
+    /// All determined scripts in the session, kept after results are sent.
+    lua_log: Vec<PendingLua>,
+    /// Native and Lua tools. Calls of other tools are refused.
+    tools: HashSet<String>,
+    /// Tools which run without approval.
+    auto_tools: HashSet<String>,
 }
 
 impl AgentResources {
-    pub fn new() -> Self {
+    pub fn new(audit: AuditLog) -> Self {
         Self {
+            audit,
             pending_lua: Vec::new(),
             determined_lua: Vec::new(),
+            lua_log: Vec::new(),
+            tools: HashSet::new(),
+            auto_tools: HashSet::new(),
         }
     }
 
@@ -41,9 +111,37 @@ impl AgentResources {
         !self.pending_lua.is_empty()
     }
 
+    pub fn script_states(&self) -> Vec<ScriptState> {
+        let state = |p: &PendingLua, approved: Option<bool>| ScriptState {
+            id: p.id.clone(),
+            summary: match p.tool {
+                Some(ref tool) => format!("{}({})", tool.name, tool.args),
+                None => p
+                    .code
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty())
+                    .unwrap_or("")
+                    .to_string(),
+            },
+            approved,
+        };
+        self.determined_lua
+            .iter()
+            .map(|p| state(p, Some(p.approved)))
+            .chain(self.pending_lua.iter().map(|p| state(p, None)))
+            .collect()
+    }
+
     fn get_lua_targets(&self, target: ApprovalTarget) -> Vec<String> {
         match target {
             ApprovalTarget::All => self.pending_lua.iter().map(|p| p.id.clone()).collect(),
+            ApprovalTarget::Auto => self
+                .pending_lua
+                .iter()
+                .filter(|p| p.auto_approve)
+                .map(|p| p.id.clone())
+                .collect(),
         }
     }
 
@@ -51,34 +149,148 @@ impl AgentResources {
         self.pending_lua.iter().position(|p| p.id == id)
     }
 
-    fn pending_lua_job(&self, id: &str) -> Option<(String, u64)> {
+    fn pending_lua_job(&self, id: &str) -> Option<PendingLua> {
+        self.pending_lua.iter().find(|p| p.id == id).cloned()
+    }
+
+    /// Returns the id and code of the pending lua, or the first one if id is empty.
+    /// Tool calls are not included.
+    fn pending_lua_code(&self, id: &str) -> Option<(String, String)> {
         self.pending_lua
             .iter()
+            .filter(|p| p.tool.is_none())
+            .find(|p| id.is_empty() || p.id == id)
+            .map(|p| (p.id.clone(), p.code.clone()))
+    }
+
+    /// Replace the code of a pending lua, keeping the original for the diff.
+    fn modify_lua(&mut self, id: &str, code: &str) -> Result<()> {
+        let pending = self
+            .pending_lua
+            .iter_mut()
             .find(|p| p.id == id)
-            .map(|p| (p.code.clone(), p.timeout_sec))
+            .ok_or_else(|| anyhow!("No pending lua with id {}", id))?;
+        let original = pending
+            .original_code
+            .take()
+            .unwrap_or_else(|| pending.code.clone());
+        if original != code {
+            pending.original_code = Some(original);
+        }
+        pending.code = code.to_string();
+        Ok(())
+    }
+
+    /// Returns the diff of user modification, if the pending lua was modified.
+    fn pending_lua_diff(&self, id: &str) -> Option<String> {
+        let pending = self.pending_lua.iter().find(|p| p.id == id)?;
+        let original = pending.original_code.as_ref()?;
+        let with_newline = |code: &str| format!("{}\n", code.trim_end_matches('\n'));
+        Some(unified_diff(
+            &with_newline(original),
+            &with_newline(&pending.code),
+            "script.lua",
+        ))
     }
 
-    pub fn determine_lua(&mut self, id: &str, approve: bool, output: String) -> Result<()> {
+    /// Append the event of the call to the audit log.
+    /// Returns a warning if the audit log cannot be written.
+    fn audit(
+        &self,
+        pending: &PendingLua,
+        event: AuditEvent,
+        duration: Option<Duration>,
+    ) -> Option<String> {
+        self.audit
+            .append(&AuditEntry::new(pending, event, duration))
+            .err()
+            .map(|err| format!("Failed to write the audit log: {:#}", err))
+    }
+
+    /// Record the decision and the output of the call, also in the audit log.
+    /// Duration is given for executed calls.
+    /// Returns a warning if the audit log cannot be written.
+    pub fn determine_lua(
+        &mut self,
+        id: &str,
+        event: AuditEvent,
+        output: String,
+        duration: Option<Duration>,
+    ) -> Result<Option<String>> {
         let index = self
             .pending_lua_index(id)
             .ok_or_else(|| anyhow!("No pending lua with id {}", id))?;
         let mut pending = self.pending_lua.remove(index);
-        pending.approved = approve;
+        pending.approved = matches!(event, AuditEvent::Completed | AuditEvent::Failed);
+        pending.decision = Some(event);
         pending.output = Some(output);
+        let warning = self.audit(&pending, event, duration);
+        self.lua_log.push(pending.clone());
         self.determined_lua.push(pending);
-        Ok(())
+        Ok(warning)
+    }
+
+    /// Copy the scripts to the session, to be saved.
+    fn save_to(&self, session: &mut Session) {
+        session.lua_log = self.lua_log.clone();
+        session.determined_lua = self.determined_lua.clone();
+        session.pending_lua = self.pending_lua.clone();
+    }
+
+    /// Replace the scripts with ones of the session.
+    fn restore_from(&mut self, session: &Session) {
+        self.lua_log = session.lua_log.clone();
+        self.determined_lua = session.determined_lua.clone();
+        self.pending_lua = session.pending_lua.clone();
     }
 }
 
+/// Run the init script of the VM, and returns the report if it exists.
+fn init_message(vm: &LuaVM) -> Option<String> {
+    match vm.init() {
+        Ok(None) => None,
+        Ok(Some((path, exec))) => {
+            let mut msg = format!("Loaded {}.", path.display());
+            if exec.error.is_some() || !exec.stdout.is_empty() {
+                msg.push('\n');
+                msg.push_str(exec.to_string().trim_end());
+            }
+            Some(msg)
+        }
+        Err(err) => Some(format!("Failed to run Lua init script: {:#}", err)),
+    }
+}
+
+/// Returns the error as a text and a structured result.
+fn error_result(msg: String) -> (String, Value) {
+    let json = serde_json::json!({ "error": msg });
+    (msg, json)
+}
+
 pub struct AgentHandler {
     resources: Arc<Mutex<AgentResources>>,
+    workspace: Workspace,
+    /// Timeout of scripts, if the LLM does not give one.
+    default_timeout_sec: u64,
+    /// Ceiling of timeouts requested by the LLM.
+    max_timeout_sec: u64,
+    /// Used for setups of VMs previewing scripts.
+    config: Config,
     output_tx: mpsc::Sender<Output>,
 }
 
 impl AgentHandler {
-    pub fn new(resources: Arc<Mutex<AgentResources>>, output_tx: mpsc::Sender<Output>) -> Self {
+    pub fn new(
+        config: &Config,
+        resources: Arc<Mutex<AgentResources>>,
+        output_tx: mpsc::Sender<Output>,
+    ) -> Self {
         Self {
             resources,
+            workspace: Workspace::new(config.workspace_dir()),
+            default_timeout_sec: config.lua.default_timeout_sec,
+            max_timeout_sec: config.lua.max_timeout_sec,
+            config: config.clone(),
             output_tx,
         }
     }
@@ -91,31 +303,104 @@ impl LLMEventHandler for AgentHandler {
         Ok(())
     }
 
-    async fn on_lua_call(&self, id: &str, code: &str, timeout_sec: Option<u64>) -> Result<()> {
+    async fn on_tool_call(&self, id: &str, name: &str, args: &Value) -> Result<()> {
+        let code = args.get("code").and_then(|value| value.as_str());
+        let (pending, output) = match code {
+            Some(code) if name == "lua" => {
+                let timeout_sec = args.get("timeout_sec").and_then(parse_timeout);
+                let vm = args
+                    .get("vm")
+                    .and_then(|value| value.as_str())
+                    .filter(|vm| !vm.is_empty() && *vm != DEFAULT_VM)
+                    .map(|vm| vm.to_string());
+                // Scripts for invalid VMs are answered with errors, without asking.
+                let invalid_vm = vm.as_deref().is_some_and(|vm| !is_valid_vm_name(vm));
+                if let Some(vm) = vm.as_ref().filter(|_| !invalid_vm) {
+                    let msg = format!("The next script runs in Lua VM '{}'.", vm);
+                    send_output(&self.output_tx, Output::SystemMsg(msg)).await?;
+                }
+                let setup = review_setup(&self.config, vm.as_deref());
+                let pending = PendingLua {
+                    id: id.to_string(),
+                    code: code.to_string(),
+                    original_code: None,
+                    timeout_sec: timeout_sec
+                        .unwrap_or(self.default_timeout_sec)
+                        .min(self.max_timeout_sec),
+                    approved: false,
+                    output: None,
+                    tool: None,
+                    auto_approve: invalid_vm,
+                    vm,
+                    decision: None,
+                };
+                let output = if invalid_vm {
+                    Output::SystemMsg(
+                        "A script for an invalid Lua VM name is rejected.".to_string(),
+                    )
+                } else {
+                    Output::LuaCode {
+                        id: id.to_string(),
+                        code: code.to_string(),
+                        review: ScriptReview::new(code, &setup).await,
+                    }
+                };
+                (pending, output)
+            }
+            _ => {
+                // Invalid lua calls and unknown tools are answered with errors, without asking.
+                let (unknown, auto_tool) = {
+                    let guard = self.resources.lock().await;
+                    (
+                        name != "lua" && !guard.tools.contains(name),
+                        guard.auto_tools.contains(name),
+                    )
+                };
+                let auto_approve = name == "lua" || unknown || auto_tool;
+                let pending = PendingLua {
+                    id: id.to_string(),
+                    code: String::new(),
+                    original_code: None,
+                    timeout_sec: 0,
+                    approved: false,
+                    output: None,
+                    tool: Some(ToolInvocation {
+                        name: name.to_string(),
+                        args: args.clone(),
+                    }),
+                    auto_approve,
+                    vm: None,
+                    decision: None,
+                };
+                let output = if unknown {
+                    Output::SystemMsg(format!(
+                        "A call of unknown tool '{}' is rejected.",
+                        name.escape_debug()
+                    ))
+                } else {
+                    let described = self.workspace.describe(name, args);
+                    Output::ToolCall {
+                        id: id.to_string(),
+                        name: name.to_string(),
+                        diff: described.is_some(),
+                        args: described.unwrap_or_else(|| {
+                            serde_json::to_string_pretty(args).unwrap_or_default()
+                        }),
+                        auto_approve,
+                    }
+                };
+                (pending, output)
+            }
+        };
+
         {
             let mut guard = self.resources.lock().await;
-            guard.pending_lua.push(PendingLua {
-                id: id.to_string(),
-                code: code.to_string(),
-                timeout_sec: timeout_sec.unwrap_or(10),
-                approved: false,
-                output: None,
-            });
+            guard.pending_lua.push(pending);
         }
-
-        send_output(
-            &self.output_tx,
-            Output::LuaCode {
-                id: id.to_string(),
-                code: code.to_string(),
-            },
-        )
-        .await?;
-        Ok(())
+        send_output(&self.output_tx, output).await
     }
 
     async fn on_llm_finished(&self) -> Result<()> {
-        self.output_tx.send(Output::InputReady).await?;
         Ok(())
     }
 }
@@ -132,9 +417,15 @@ pub struct Agent<I> {
     config: Config,
     resources: Arc<Mutex<AgentResources>>,
 
-    llm: Arc<Mutex<DynLLMClient>>,
+    llm: Rc<Mutex<DynLLMClient>>,
 
+    /// The default VM.
     lua: LuaVM,
+    /// VMs named by the LLM, created when first used.
+    vms: BTreeMap<String, LuaVM>,
+    workspace: Workspace,
+
+    session: Session,
 
     input_rx: mpsc::Receiver<Input>,
     signal_rx: mpsc::Receiver<io::Signal>,
@@ -153,13 +444,21 @@ where
         io: I,
         io_chan: IOChan,
     ) -> Self {
+        let session = Session::new(
+            &session::sessions_dir(&config.workspace_dir()),
+            &config.default_llm,
+            &llm.get_model_name(),
+        );
         Self {
             running: false,
             resources,
             io,
             config: config.clone(),
-            llm: Arc::new(Mutex::new(llm)),
-            lua: lua,
+            llm: Rc::new(Mutex::new(llm)),
+            lua,
+            vms: BTreeMap::new(),
+            workspace: Workspace::new(config.workspace_dir()),
+            session,
             output_tx: io_chan.output_tx,
             input_rx: io_chan.input_rx,
             signal_rx: io_chan.signal_rx,
@@ -167,24 +466,37 @@ where
     }
 
     pub async fn run(&mut self) -> Result<()> {
+        let result = self.run_session().await;
+        // IO should be closed even if the agent fails, to restore the terminal.
+        let closed = self.io.close();
+        result.and(closed)
+    }
+
+    async fn run_session(&mut self) -> Result<()> {
         self.show_status().await?;
         self.pre_run().await?;
-        self.main_loop().await?;
+        let result = self.main_loop().await;
         self.post_run().await?;
-        Ok(())
+        result
     }
 
     async fn pre_run(&mut self) -> Result<()> {
         self.running = true;
+        self.init_lua().await?;
+        self.update_tools().await?;
+        if let Some(id) = self.config.resume.clone() {
+            self.open_session(&id).await?;
+        }
+        self.send_state(None).await?;
         self.output_tx.send(Output::InputReady).await?;
         Ok(())
     }
 
     async fn post_run(&mut self) -> Result<()> {
+        self.save_session().await?;
         self.output_tx
             .send(Output::SystemMsg("Agent stopped.".to_string()))
             .await?;
-        self.io.close()?;
         Ok(())
     }
 
@@ -205,19 +517,44 @@ where
             - LLM Status: {}\n\
             - Token Usage: {}/{}\n\
             - cwd: {}\n\
+            - Lua backend: {}\n\
             - Pending Lua scripts: {}",
             self.config.default_llm,
             llm_model,
-            llm_status.to_str(),
+            llm_status.as_str(),
             token_used,
             token_limit,
             self.config.workspace_dir().display(),
+            lua::BACKEND,
             pending_lua
         );
         send_output(&self.output_tx, Output::SystemMsg(msg)).await?;
         Ok(())
     }
 
+    /// Send the agent state to IO.
+    /// If status is given, it overrides the current LLM status.
+    async fn send_state(&self, status: Option<Status>) -> Result<()> {
+        let (llm_status, model, token_used, token_limit) = {
+            let llm = self.llm.lock().await;
+            let (used, limit) = llm.context_size();
+            (llm.get_status(), llm.get_model_name(), used, limit)
+        };
+        let scripts = {
+            let guard = self.resources.lock().await;
+            guard.script_states()
+        };
+        let state = AgentState {
+            llm: self.config.default_llm.clone(),
+            model,
+            status: status.unwrap_or(llm_status),
+            token_used,
+            token_limit,
+            scripts,
+        };
+        send_output(&self.output_tx, Output::State(state)).await
+    }
+
     async fn main_loop(&mut self) -> Result<()> {
         while self.running {
             tokio::select! {
@@ -227,15 +564,584 @@ where
                         self.running = false;
                         break;
                     }
+                    self.save_session().await?;
+                    self.send_state(None).await?;
+                    self.output_tx.send(Output::InputReady).await?;
                 }
                 Some(input) = self.input_rx.recv() => {
-                    self.handle_input(input).await?;
+                    if self.handle_input(input).await? {
+                        self.running = false;
+                        break;
+                    }
+                    self.save_session().await?;
+                    self.send_state(None).await?;
+                    self.output_tx.send(Output::InputReady).await?;
                 }
             }
         }
         Ok(())
     }
 
+    /// Run the init script of the Lua VM, and report it.
+    async fn init_lua(&mut self) -> Result<()> {
+        match init_message(&self.lua) {
+            Some(msg) => send_output(&self.output_tx, Output::SystemMsg(msg)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Create the named VM if it does not exist yet, and run its init script.
+    /// Returns the reason if it cannot be created.
+    async fn start_vm(&mut self, name: &str) -> Result<Option<String>> {
+        if self.vms.contains_key(name) {
+            return Ok(None);
+        }
+        if !is_valid_vm_name(name) {
+            return Ok(Some(format!(
+                "Tool error: invalid VM name '{}'. Use letters, digits, `-` and `_`.",
+                name
+            )));
+        }
+        let vm = match LuaVM::with_setup(self.config.lua_vm_setup(name)) {
+            Ok(vm) => vm,
+            Err(err) => {
+                return Ok(Some(format!(
+                    "Tool error: failed to create Lua VM '{}': {:#}",
+                    name, err
+                )));
+            }
+        };
+        let mut msg = format!("Lua VM '{}' is created.", name);
+        if let Some(init) = init_message(&vm) {
+            msg.push('\n');
+            msg.push_str(&init);
+        }
+        self.vms.insert(name.to_string(), vm);
+        send_output(&self.output_tx, Output::SystemMsg(msg)).await?;
+        Ok(None)
+    }
+
+    /// Advertise native tools and tools of the Lua VM to the LLM,
+    /// and report tools failed to load.
+    async fn update_tools(&mut self) -> Result<()> {
+        let tools = self.lua.tools();
+        {
+            let mut llm = self.llm.lock().await;
+            let specs = workspace::tool_specs()
+                .into_iter()
+                .chain(tools.iter().map(|t| t.spec.clone()))
+                .collect();
+            llm.set_tools(specs);
+        }
+        {
+            let mut guard = self.resources.lock().await;
+            guard.tools = workspace::TOOL_NAMES
+                .iter()
+                .map(|name| name.to_string())
+                .chain(tools.iter().map(|t| t.spec.name.clone()))
+                .collect();
+            guard.auto_tools = workspace::READ_ONLY_TOOLS
+                .iter()
+                .map(|name| name.to_string())
+                .chain(
+                    tools
+                        .iter()
+                        .filter(|t| t.auto_approve)
+                        .map(|t| t.spec.name.clone()),
+                )
+                .collect();
+        }
+        for err in self.lua.tool_errors() {
+            let msg = format!("Failed to load tool: {}", err);
+            send_output(&self.output_tx, Output::SystemMsg(msg)).await?;
+        }
+        Ok(())
+    }
+
+    fn sessions_dir(&self) -> PathBuf {
+        session::sessions_dir(&self.config.workspace_dir())
+    }
+
+    /// Update the session with the LLM history and scripts.
+    async fn sync_session(&mut self) -> Result<()> {
+        {
+            let llm = self.llm.lock().await;
+            self.session.transcript = llm.export_history()?;
+            self.session.token_used = llm.context_size().0;
+        }
+        let guard = self.resources.lock().await;
+        guard.save_to(&mut self.session);
+        Ok(())
+    }
+
+    /// Save the current session. Failures are reported but not fatal.
+    async fn save_session(&mut self) -> Result<()> {
+        if self.session.is_empty() {
+            return Ok(());
+        }
+        self.sync_session().await?;
+        let dir = self.sessions_dir();
+        if let Err(err) = self.session.save(&dir) {
+            let msg = format!("Failed to save session: {:#}", err);
+            send_output(&self.output_tx, Output::SystemMsg(msg)).await?;
+        }
+        Ok(())
+    }
+
+    /// Load the saved session and continue it. Empty id opens the latest one.
+    async fn open_session(&mut self, id: &str) -> Result<()> {
+        let loaded = match Session::load(&self.sessions_dir(), id) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                let msg = format!("Failed to open session: {:#}", err);
+                return send_output(&self.output_tx, Output::SystemMsg(msg)).await;
+            }
+        };
+        if loaded.id == self.session.id {
+            let msg = format!("Session {} is already open.", loaded.id);
+            return send_output(&self.output_tx, Output::SystemMsg(msg)).await;
+        }
+
+        // Keep the current session, before it is replaced.
+        self.save_session().await?;
+        {
+            let mut llm = self.llm.lock().await;
+            if let Err(err) = llm.import_history(&loaded.transcript, loaded.token_used) {
+                let msg = format!("Failed to open session {}: {:#}", loaded.id, err);
+                return send_output(&self.output_tx, Output::SystemMsg(msg)).await;
+            }
+        }
+        {
+            let mut guard = self.resources.lock().await;
+            guard.restore_from(&loaded);
+        }
+        self.session = loaded;
+
+        let mut msg = format!(
+            "Resumed session {} \"{}\" ({} messages, {} Lua scripts run).\n\
+            Lua VM state is not restored.",
+            self.session.id,
+            self.session.title,
+            self.session.message_count(),
+            self.session.lua_log.len(),
+        );
+        if self.session.llm != self.config.default_llm {
+            msg.push_str(&format!(
+                "\nWarning: the session used LLM '{}' ({}), now '{}' is used.",
+                self.session.llm, self.session.model, self.config.default_llm
+            ));
+            self.session.llm = self.config.default_llm.clone();
+        }
+        send_output(&self.output_tx, Output::SystemMsg(msg)).await?;
+        self.show_pending_lua().await
+    }
+
+    /// Show scripts still waiting for approval, after the session is changed.
+    async fn show_pending_lua(&self) -> Result<()> {
+        for pending in self.session.pending_lua.clone() {
+            send_output(
+                &self.output_tx,
+                Output::LuaCode {
+                    review: ScriptReview::new(
+                        &pending.code,
+                        &review_setup(&self.config, pending.vm.as_deref()),
+                    )
+                    .await,
+                    id: pending.id,
+                    code: pending.code,
+                },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Load the LLM history and scripts from the current branch of the session.
+    async fn load_branch(&mut self) -> Result<()> {
+        {
+            let mut llm = self.llm.lock().await;
+            llm.import_history(&self.session.transcript, self.session.token_used)?;
+        }
+        let mut guard = self.resources.lock().await;
+        guard.restore_from(&self.session);
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() {
+            return send_output(
+                &self.output_tx,
+                Output::SystemMsg("Usage: /checkpoint <name>".to_string()),
+            )
+            .await;
+        }
+        if self.has_pending_lua().await? {
+            return send_output(
+                &self.output_tx,
+                Output::SystemMsg("Approve or reject pending Lua scripts first.".to_string()),
+            )
+            .await;
+        }
+
+        self.sync_session().await?;
+        let snapshot = self.lua.snapshot()?;
+        let notes = snapshot_notes(&snapshot);
+        if let Err(err) = self.session.add_checkpoint(name, Some(snapshot.source)) {
+            return send_output(&self.output_tx, Output::SystemMsg(err.to_string())).await;
+        }
+        let msg = format!(
+            "Checkpoint '{}' is made on branch '{}'.{}",
+            name, self.session.branch, notes
+        );
+        send_output(&self.output_tx, Output::SystemMsg(msg)).await
+    }
+
+    /// Fork a new branch from the checkpoint. With `--vm`, Lua globals are restored too.
+    async fn branch(&mut self, arg: &str) -> Result<()> {
+        let mut restore_vm = false;
+        let mut checkpoint = "";
+        for token in arg.split_whitespace() {
+            match token {
+                "--vm" => restore_vm = true,
+                _ => checkpoint = token,
+            }
+        }
+        if checkpoint.is_empty() {
+            return send_output(&self.output_tx, Output::SystemMsg(self.session.tree())).await;
+        }
+
+        self.sync_session().await?;
+        let vm = match self.session.checkpoint(checkpoint) {
+            Some(cp) => cp.vm.clone(),
+            None => {
+                let msg = format!("No checkpoint '{}'. See /branch for the list.", checkpoint);
+                return send_output(&self.output_tx, Output::SystemMsg(msg)).await;
+            }
+        };
+        let name = self.session.fork(checkpoint)?;
+        self.load_branch().await?;
+
+        let mut msg = format!("Branch '{}' is forked from '{}'.", name, checkpoint);
+        if restore_vm {
+            match vm {
+                Some(source) => {
+                    // Set up like a fresh VM, with the init script and tools, before restoring.
+                    self.reset_vm().await?;
+                    match self.lua.restore(&source) {
+                        Ok(()) => msg.push_str("\nLua VM globals are restored."),
+                        Err(err) => {
+                            msg.push_str(&format!("\nFailed to restore Lua VM globals: {:#}", err))
+                        }
+                    }
+                }
+                None => msg.push_str("\nThe checkpoint has no Lua VM snapshot."),
+            }
+        }
+        send_output(&self.output_tx, Output::SystemMsg(msg)).await
+    }
+
+    async fn switch_branch(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() {
+            return send_output(&self.output_tx, Output::SystemMsg(self.session.tree())).await;
+        }
+        self.sync_session().await?;
+        if let Err(err) = self.session.switch(name) {
+            return send_output(&self.output_tx, Output::SystemMsg(err.to_string())).await;
+        }
+        self.load_branch().await?;
+        let msg = format!(
+            "Switched to branch '{}'. Lua VM state is not changed.",
+            name
+        );
+        send_output(&self.output_tx, Output::SystemMsg(msg)).await?;
+        self.show_pending_lua().await
+    }
+
+    async fn list_sessions(&self) -> Result<()> {
+        let sessions = match session::list(&self.sessions_dir()) {
+            Ok(sessions) => sessions,
+            Err(err) => {
+                let msg = format!("Failed to list sessions: {:#}", err);
+                return send_output(&self.output_tx, Output::SystemMsg(msg)).await;
+            }
+        };
+        if sessions.is_empty() {
+            return send_output(
+                &self.output_tx,
+                Output::SystemMsg("No saved sessions.".to_string()),
+            )
+            .await;
+        }
+
+        let mut msg = String::from("Sessions (newest first, open with /sessions <id>):");
+        for s in &sessions {
+            let marker = if s.id == self.session.id { "*" } else { " " };
+            msg.push_str(&format!(
+                "\n{} {}  {}  {}/{}  {} msgs  {}",
+                marker,
+                s.id,
+                rfc3339(s.updated_at),
+                s.llm,
+                s.model,
+                s.message_count(),
+                s.title
+            ));
+        }
+        send_output(&self.output_tx, Output::SystemMsg(msg)).await
+    }
+
+    /// Export the session to the path, or `.onui/exports/<id>.md` by default.
+    /// The format is guessed from the extension. `/export html` uses the default path.
+    async fn export_session(&mut self, arg: &str) -> Result<()> {
+        self.sync_session().await?;
+        let (transcript, token_limit) = {
+            let llm = self.llm.lock().await;
+            (llm.transcript(), llm.context_size().1)
+        };
+
+        let workspace = self.config.workspace_dir();
+        let (path, format) = match ExportFormat::from_name(arg) {
+            Some(format) => (None, format),
+            None if arg.is_empty() => (None, ExportFormat::Markdown),
+            None => {
+                let path = workspace.join(arg);
+                let format = ExportFormat::from_path(&path);
+                (Some(path), format)
+            }
+        };
+        let path = path.unwrap_or_else(|| {
+            workspace.join(".onui").join("exports").join(format!(
+                "{}.{}",
+                self.session.id,
+                format.extension()
+            ))
+        });
+
+        let content = export::render(&self.session, &transcript, token_limit, format);
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, content));
+        let msg = match written {
+            Ok(()) => format!("Exported session to {}.", path.display()),
+            Err(err) => format!("Failed to export to {}: {}", path.display(), err),
+        };
+        send_output(&self.output_tx, Output::SystemMsg(msg)).await
+    }
+
+    /// Drop the last user turn from the LLM history, with its pending scripts.
+    /// Returns the dropped user message.
+    async fn pop_user_turn(&mut self) -> Result<Option<String>> {
+        let msg = {
+            let mut llm = self.llm.lock().await;
+            llm.pop_user_turn()
+        };
+        let Some(msg) = msg else {
+            send_output(
+                &self.output_tx,
+                Output::SystemMsg("No user message in the history.".to_string()),
+            )
+            .await?;
+            return Ok(None);
+        };
+        let mut guard = self.resources.lock().await;
+        guard.clear_lua();
+        Ok(Some(msg))
+    }
+
+    async fn undo(&mut self) -> Result<()> {
+        if let Some(msg) = self.pop_user_turn().await? {
+            let msg = format!(
+                "Removed the last turn: {}\nLua VM state is not rolled back.",
+                msg.lines().next().unwrap_or("")
+            );
+            send_output(&self.output_tx, Output::SystemMsg(msg)).await?;
+        }
+        Ok(())
+    }
+
+    async fn retry(&mut self) -> Result<()> {
+        if let Some(msg) = self.pop_user_turn().await? {
+            self.handle_user_input(&msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Replace the last user message with the text, or edit it with the editor,
+    /// and send it again.
+    async fn edit_last(&mut self, text: &str) -> Result<()> {
+        let last = {
+            let llm = self.llm.lock().await;
+            llm.transcript()
+                .into_iter()
+                .rev()
+                .find_map(|item| match item {
+                    TranscriptItem::User(msg) => Some(msg),
+                    _ => None,
+                })
+        };
+        let Some(last) = last else {
+            return send_output(
+                &self.output_tx,
+                Output::SystemMsg("No user message in the history.".to_string()),
+            )
+            .await;
+        };
+
+        let new_msg = if text.trim().is_empty() {
+            match self.io.edit_text(&last, "message.md") {
+                Ok(edited) => edited,
+                Err(err) => {
+                    let msg = format!("Failed to edit message: {:#}", err);
+                    return send_output(&self.output_tx, Output::SystemMsg(msg)).await;
+                }
+            }
+        } else {
+            text.to_string()
+        };
+        if new_msg.trim().is_empty() {
+            return send_output(
+                &self.output_tx,
+                Output::SystemMsg("Message is empty, not sent.".to_string()),
+            )
+            .await;
+        }
+
+        if self.pop_user_turn().await?.is_some() {
+            self.handle_user_input(new_msg.trim_end()).await?;
+        }
+        Ok(())
+    }
+
+    /// `/vm save <name>` and `/vm load <name>`, with `.onui/vm/<name>.lua`.
+    async fn vm_command(&mut self, arg: &str) -> Result<()> {
+        let mut parts = arg.split_whitespace();
+        let (action, name) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        match action {
+            "list" => return self.list_vms().await,
+            "reset" if name.is_empty() || name == DEFAULT_VM => return self.reset_vm().await,
+            "reset" => return self.reset_named_vm(name).await,
+            _ => {}
+        }
+        let valid_name = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if !matches!(action, "save" | "load") || !valid_name {
+            return send_output(
+                &self.output_tx,
+                Output::SystemMsg(
+                    "Usage: /vm save <name> | /vm load <name> | /vm list | /vm reset <vm>"
+                        .to_string(),
+                ),
+            )
+            .await;
+        }
+
+        let path = self
+            .config
+            .workspace_dir()
+            .join(".onui")
+            .join("vm")
+            .join(format!("{}.lua", name));
+        let msg = if action == "save" {
+            let snapshot = self.lua.snapshot()?;
+            let written = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(&path, &snapshot.source));
+            match written {
+                Ok(()) => format!(
+                    "Lua VM is saved to {}.{}",
+                    path.display(),
+                    snapshot_notes(&snapshot)
+                ),
+                Err(err) => format!("Failed to save Lua VM to {}: {}", path.display(), err),
+            }
+        } else {
+            match std::fs::read_to_string(&path) {
+                Ok(source) => match self.lua.restore(&source) {
+                    Ok(()) => format!("Lua VM is loaded from {}.", path.display()),
+                    Err(err) => format!("Failed to load Lua VM: {:#}", err),
+                },
+                Err(err) => format!("Failed to read {}: {}", path.display(), err),
+            }
+        };
+        send_output(&self.output_tx, Output::SystemMsg(msg)).await
+    }
+
+    /// Reset the default VM, and load tools again.
+    async fn reset_vm(&mut self) -> Result<()> {
+        {
+            let mut guard = self.resources.lock().await;
+            self.lua.reset()?;
+            guard.clear_lua();
+        }
+        send_output(
+            &self.output_tx,
+            Output::SystemMsg("Lua VM reset.".to_string()),
+        )
+        .await?;
+        self.init_lua().await?;
+        self.update_tools().await
+    }
+
+    async fn reset_named_vm(&mut self, name: &str) -> Result<()> {
+        let msg = match self.vms.get_mut(name) {
+            Some(vm) => {
+                vm.reset()?;
+                let mut msg = format!("Lua VM '{}' reset.", name);
+                if let Some(init) = init_message(vm) {
+                    msg.push('\n');
+                    msg.push_str(&init);
+                }
+                msg
+            }
+            None => format!("No Lua VM '{}' is running. See /vm list.", name),
+        };
+        send_output(&self.output_tx, Output::SystemMsg(msg)).await
+    }
+
+    /// Show the default VM, named VMs, and VMs in the config not started yet.
+    async fn list_vms(&self) -> Result<()> {
+        let describe = |name: &str, setup: &LuaSetup, state: &str| {
+            let root = setup
+                .workspace
+                .as_ref()
+                .map(|root| root.display().to_string())
+                .unwrap_or_default();
+            let sandbox = if setup.sandbox {
+                "sandbox"
+            } else {
+                "no sandbox"
+            };
+            format!("- {}: {}, {}{}", name, root, sandbox, state)
+        };
+        let mut lines = vec!["Lua VMs:".to_string()];
+        lines.push(describe(
+            DEFAULT_VM,
+            &self.config.lua_setup(),
+            " (default, tools)",
+        ));
+        let mut names: Vec<&String> = self.vms.keys().collect();
+        for name in self.config.lua.vms.keys() {
+            if !self.vms.contains_key(name) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        for name in names {
+            let state = if self.vms.contains_key(name) {
+                ""
+            } else {
+                " (not started)"
+            };
+            lines.push(describe(name, &self.config.lua_vm_setup(name), state));
+        }
+        send_output(&self.output_tx, Output::SystemMsg(lines.join("\n"))).await
+    }
+
     async fn has_pending_lua(&self) -> Result<bool> {
         let guard = self.resources.lock().await;
         Ok(guard.has_pending_lua())
@@ -251,19 +1157,17 @@ where
                 }
                 Ok(false)
             }
-            Input::Command { cmd, arg, .. } => match self.handle_command(cmd, &arg).await? {
-                CommandResult::Exit => Ok(true),
-                _ => Ok(false),
-            },
+            Input::Command { cmd, arg, details } => {
+                match self.handle_command(cmd, &arg, &details).await? {
+                    CommandResult::Exit => Ok(true),
+                    _ => Ok(false),
+                }
+            }
         }
     }
 
     async fn handle_text_for_lua(&mut self, line: &str) -> Result<()> {
-        let token = line
-            .trim()
-            .splitn(2, char::is_whitespace)
-            .next()
-            .unwrap_or("");
+        let token = line.trim().split(char::is_whitespace).next().unwrap_or("");
         match token.to_ascii_lowercase().as_str() {
             "y" | "yes" | "approve" | "ok" => self.approve_lua(ApprovalTarget::All).await,
             "n" | "no" | "reject" => self.reject_lua(ApprovalTarget::All).await,
@@ -277,28 +1181,172 @@ where
             guard.get_lua_targets(target)
         };
         for id in targets {
+            self.execute_lua(&id).await?;
+        }
+        self.check_lua().await
+    }
+
+    /// Execute a pending script or tool call, and record the result.
+    /// LLMs accepting structured results get it as JSON, while the user sees the text.
+    async fn execute_lua(&mut self, id: &str) -> Result<()> {
+        let structured = self.llm.lock().await.structured_results();
+        let vm_name = {
+            let guard = self.resources.lock().await;
+            guard.pending_lua_job(id).and_then(|job| job.vm)
+        };
+        let vm_error = match &vm_name {
+            Some(name) => self.start_vm(name).await?,
+            None => None,
+        };
+        let (output, warnings) = {
             let mut guard = self.resources.lock().await;
-            let output = {
-                let (code, timeout_sec) = guard
-                    .pending_lua_job(&id)
-                    .ok_or_else(|| anyhow!("No pending lua with id {}", id))?;
-                let result = match self.lua.execute_script(&code, Some(timeout_sec)) {
-                    Ok(exec) => exec.to_string(),
-                    Err(err) => format!("Lua execution error: {}", err),
-                };
-                guard.determine_lua(&id, true, result.clone())?;
-                result
+            let job = guard
+                .pending_lua_job(id)
+                .ok_or_else(|| anyhow!("No pending lua with id {}", id))?;
+            let vm = match &vm_name {
+                Some(name) => self.vms.get(name),
+                None => Some(&self.lua),
             };
-            send_output(
-                &self.output_tx,
-                Output::LuaResult {
-                    id: id.clone(),
-                    output: output.clone(),
-                },
-            )
-            .await?;
+            // Invalid calls are answered with errors, and nothing runs.
+            let refused = match (&job.tool, vm) {
+                (None, None) => Some(vm_error.unwrap_or_default()),
+                (Some(tool), _) if tool.name == "lua" => {
+                    Some("Tool error: the `code` argument is required.".to_string())
+                }
+                (Some(tool), _) if !guard.tools.contains(&tool.name) => {
+                    Some(format!("Tool error: unknown tool `{}`.", tool.name))
+                }
+                _ => None,
+            };
+            if let Some(msg) = refused {
+                let warning = guard.determine_lua(id, AuditEvent::Errored, msg.clone(), None)?;
+                (msg, warning.into_iter().collect())
+            } else {
+                // Recorded before running, in case the call never returns.
+                let mut warnings: Vec<String> = guard
+                    .audit(&job, AuditEvent::Approved, None)
+                    .into_iter()
+                    .collect();
+                let start = Instant::now();
+                let (mut text, mut json) = match (job.tool, vm) {
+                    (None, Some(vm)) => match vm.execute_script(&job.code, Some(job.timeout_sec)) {
+                        Ok(exec) => (exec.to_string(), exec.to_json()),
+                        Err(err) => error_result(format!("Lua execution error: {}", err)),
+                    },
+                    (Some(ref tool), _) if workspace::is_tool(&tool.name) => {
+                        match self.workspace.call(&tool.name, &tool.args) {
+                            Ok(output) => (output.clone(), serde_json::json!({ "output": output })),
+                            Err(err) => error_result(format!("Tool error: {}", err)),
+                        }
+                    }
+                    (Some(ref tool), _) => match self.lua.call_tool(&tool.name, &tool.args) {
+                        Ok(exec) => (exec.to_string(), exec.to_json()),
+                        Err(err) => error_result(format!("Tool error: {}", err)),
+                    },
+                    (None, None) => unreachable!("refused above"),
+                };
+                let duration = start.elapsed();
+                let event = if json.get("error").is_some_and(|err| !err.is_null()) {
+                    AuditEvent::Failed
+                } else {
+                    AuditEvent::Completed
+                };
+                if let Some(diff) = guard.pending_lua_diff(id) {
+                    text = format!(
+                        "-- Note: The user modified the code before execution.\n```diff\n{}```\n{}",
+                        diff, text
+                    );
+                    json["user_modified_diff"] = Value::String(diff);
+                }
+                let text = self.limit_output(id, text);
+                let result = if structured {
+                    self.limit_json(id, json)
+                } else {
+                    text.clone()
+                };
+                warnings.extend(guard.determine_lua(id, event, result, Some(duration))?);
+                (text, warnings)
+            }
+        };
+        for warning in warnings {
+            send_output(&self.output_tx, Output::SystemMsg(warning)).await?;
         }
-        self.check_lua().await
+        send_output(
+            &self.output_tx,
+            Output::LuaResult {
+                id: id.to_string(),
+                output,
+            },
+        )
+        .await
+    }
+
+    /// Truncate long strings of a structured result, like `limit_output`.
+    /// The full output is saved by `limit_output` with the text.
+    fn limit_json(&self, id: &str, mut json: Value) -> String {
+        let limit = self.config.lua.output_limit_bytes;
+        if limit == 0 {
+            return json.to_string();
+        }
+        let tail_bytes = self.config.lua.output_tail_bytes.min(limit);
+        let mut truncated = false;
+        let mut shorten = |value: &mut Value| {
+            if let Value::String(text) = value
+                && let Some((head, tail, omitted)) =
+                    output::truncate(text, limit - tail_bytes, tail_bytes)
+            {
+                *text = format!(
+                    "{}\n-- ... {} bytes omitted.\n{}",
+                    head.trim_end_matches('\n'),
+                    omitted,
+                    tail
+                );
+                truncated = true;
+            }
+        };
+        for key in ["stdout", "stderr", "output", "error"] {
+            if let Some(value) = json.get_mut(key) {
+                shorten(value);
+            }
+        }
+        if let Some(Value::Array(returns)) = json.get_mut("returns") {
+            returns.iter_mut().for_each(&mut shorten);
+        }
+        if truncated {
+            json["truncated"] = Value::String(format!(
+                "The full output is saved; read it with `onui.output(\"{}\", start_line, count)`.",
+                id
+            ));
+        }
+        json.to_string()
+    }
+
+    /// Truncate a large result, keeping its head and tail.
+    /// The full text is saved in `.onui/outputs/<id>.txt`, to be read by `onui.output`.
+    fn limit_output(&self, id: &str, text: String) -> String {
+        let limit = self.config.lua.output_limit_bytes;
+        if limit == 0 {
+            return text;
+        }
+        let tail = self.config.lua.output_tail_bytes.min(limit);
+        let Some((head, tail, omitted)) = output::truncate(&text, limit - tail, tail) else {
+            return text;
+        };
+        let saved = match output::save(&self.config.workspace_dir(), id, &text) {
+            Ok(_) => format!(
+                "The full output ({} lines) is saved; read it with `onui.output(\"{}\", start_line, count)`.",
+                text.lines().count(),
+                id
+            ),
+            Err(err) => format!("Failed to save the full output: {}", err),
+        };
+        format!(
+            "{}\n-- ... {} bytes omitted. {}\n\n{}",
+            head.trim_end_matches('\n'),
+            omitted,
+            saved,
+            tail
+        )
     }
 
     async fn reject_lua(&mut self, target: ApprovalTarget) -> Result<()> {
@@ -307,31 +1355,115 @@ where
             guard.get_lua_targets(target)
         };
         for id in targets {
-            let mut guard = self.resources.lock().await;
-            let output = "Reject by user.".to_string();
-            guard.determine_lua(&id, false, output.clone())?;
+            let warning = {
+                let mut guard = self.resources.lock().await;
+                let output = "Reject by user.".to_string();
+                guard.determine_lua(&id, AuditEvent::Rejected, output, None)?
+            };
+            if let Some(warning) = warning {
+                send_output(&self.output_tx, Output::SystemMsg(warning)).await?;
+            }
         }
         self.check_lua().await
     }
 
+    /// Run calls which need no approval, and send results to the LLM
+    /// when all calls are determined. Repeats while the LLM makes such calls.
     async fn check_lua(&mut self) -> Result<()> {
-        let mut guard = self.resources.lock().await;
-        if guard.has_pending_lua() {
-            return Ok(());
+        loop {
+            let auto_targets = {
+                let guard = self.resources.lock().await;
+                guard.get_lua_targets(ApprovalTarget::Auto)
+            };
+            for id in auto_targets {
+                self.execute_lua(&id).await?;
+            }
+
+            let results = {
+                let mut guard = self.resources.lock().await;
+                if guard.has_pending_lua() || guard.determined_lua.is_empty() {
+                    return Ok(());
+                }
+                let results = guard
+                    .determined_lua
+                    .iter()
+                    .map(|p| (p.id.clone(), p.output.clone().unwrap_or_default()))
+                    .collect::<Vec<(String, String)>>();
+                guard.clear_lua();
+                results
+            };
+
+            // The guard is released, since the LLM may request new scripts.
+            self.send_state(Some(Status::Generating)).await?;
+            let mut llm = self.llm.lock().await;
+            llm.send_lua_results(&results).await?;
         }
-        let results = guard
-            .determined_lua
-            .iter()
-            .map(|p| (p.id.clone(), p.output.clone().unwrap_or_default()))
-            .collect::<Vec<(String, String)>>();
-        guard.clear_lua();
+    }
 
-        let mut llm = self.llm.lock().await;
-        llm.send_lua_results(&results).await?;
-        Ok(())
+    /// Edit a pending lua with the editor, or replace it with the given code.
+    async fn edit_lua(&mut self, id: &str, code: &str) -> Result<()> {
+        let pending = {
+            let guard = self.resources.lock().await;
+            guard.pending_lua_code(id)
+        };
+        let Some((id, old_code)) = pending else {
+            let msg = if id.is_empty() {
+                "No pending Lua scripts to edit.".to_string()
+            } else {
+                format!("No pending Lua script with id {}.", id)
+            };
+            return send_output(&self.output_tx, Output::SystemMsg(msg)).await;
+        };
+
+        let new_code = if code.trim().is_empty() {
+            match self.io.edit_text(&old_code, &format!("{}.lua", id)) {
+                Ok(edited) => edited,
+                Err(err) => {
+                    let msg = format!("Failed to edit Lua script: {:#}", err);
+                    return send_output(&self.output_tx, Output::SystemMsg(msg)).await;
+                }
+            }
+        } else {
+            code.to_string()
+        };
+
+        if new_code == old_code {
+            return send_output(
+                &self.output_tx,
+                Output::SystemMsg("Lua script is not changed.".to_string()),
+            )
+            .await;
+        }
+
+        let vm_name = {
+            let mut guard = self.resources.lock().await;
+            guard.modify_lua(&id, &new_code)?;
+            guard.pending_lua_job(&id).and_then(|job| job.vm)
+        };
+        let setup = review_setup(&self.config, vm_name.as_deref());
+        let review = ScriptReview::new(&new_code, &setup).await;
+        send_output(
+            &self.output_tx,
+            Output::LuaCode {
+                id: id.clone(),
+                review,
+                code: new_code,
+            },
+        )
+        .await?;
+        send_output(
+            &self.output_tx,
+            Output::SystemMsg(format!("Lua script {} is modified.", id)),
+        )
+        .await
     }
 
-    async fn handle_command(&mut self, cmd: io::Command, _arg: &str) -> Result<CommandResult> {
+    async fn handle_command(
+        &mut self,
+        cmd: io::Command,
+        arg: &str,
+        details: &str,
+    ) -> Result<CommandResult> {
         match cmd {
             io::Command::Exit => {
                 send_output(&self.output_tx, Output::SystemMsg("Goodbye.".to_string())).await?;
@@ -349,7 +1481,7 @@ where
                 send_output(
                     &self.output_tx,
                     Output::SystemMsg(
-                        "Commands: /help, /status, /reset-vm, /cancel, /exit, /approve, /reject"
+                        "Commands: /help, /status, /resetvm, /cancel, /exit, /approve, /reject, /editlua, /sessions, /export, /retry, /undo, /edit, /checkpoint, /branch, /switch, /vm"
                             .to_string(),
                     ),
                 )
@@ -359,16 +1491,7 @@ where
                 self.show_status().await?;
             }
             io::Command::ResetVM => {
-                {
-                    let mut guard = self.resources.lock().await;
-                    self.lua.reset()?;
-                    guard.clear_lua();
-                }
-                send_output(
-                    &self.output_tx,
-                    Output::SystemMsg("Lua VM reset.".to_string()),
-                )
-                .await?;
+                self.reset_vm().await?;
             }
             io::Command::Approve => {
                 if self.has_pending_lua().await? {
@@ -402,6 +1525,46 @@ where
                     .await?;
                 }
             }
+            io::Command::EditLua => {
+                self.edit_lua(arg, details).await?;
+            }
+            io::Command::Sessions => {
+                if arg.is_empty() {
+                    self.list_sessions().await?;
+                } else {
+                    self.open_session(arg).await?;
+                }
+            }
+            io::Command::Export => {
+                self.export_session(arg).await?;
+            }
+            io::Command::Retry => {
+                self.retry().await?;
+            }
+            io::Command::Undo => {
+                self.undo().await?;
+            }
+            io::Command::Edit => {
+                let text = [arg, details]
+                    .iter()
+                    .filter(|s| !s.is_empty())
+                    .copied()
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                self.edit_last(&text).await?;
+            }
+            io::Command::Checkpoint => {
+                self.checkpoint(arg).await?;
+            }
+            io::Command::Branch => {
+                self.branch(arg).await?;
+            }
+            io::Command::Switch => {
+                self.switch_branch(arg).await?;
+            }
+            io::Command::VM => {
+                self.vm_command(arg).await?;
+            }
             io::Command::ApproveAlways => {
                 send_output(
                     &self.output_tx,
@@ -422,34 +1585,388 @@ where
     }
 
     async fn handle_user_input(&mut self, input: &str) -> Result<()> {
-        let llm: Arc<Mutex<DynLLMClient>> = self.llm.clone();
-        let input = input.to_string();
-        let output_tx = self.output_tx.clone();
-        let mut llm = llm.lock().await;
-
-        tokio::select! {
-            _t = self.signal_rx.recv() => {
-                Ok(())
-            }
-            send_result = llm.send_user_msg(&input) => {
-                send_result.map_err(|err| {
-                    let _ = output_tx
-                        .try_send(Output::SystemMsg(format!(
-                            "Failed to send message to LLM: {}",
-                            err
-                        )));
-                    err
-                })
+        self.session.set_title(input);
+        self.send_state(Some(Status::Generating)).await?;
+        {
+            let llm: Rc<Mutex<DynLLMClient>> = self.llm.clone();
+            let input = input.to_string();
+            let output_tx = self.output_tx.clone();
+            let mut llm = llm.lock().await;
+
+            tokio::select! {
+                _t = self.signal_rx.recv() => {
+                    return Ok(());
+                }
+                send_result = llm.send_user_msg(&input) => {
+                    send_result.map_err(|err| {
+                        let _ = output_tx
+                            .try_send(Output::SystemMsg(format!(
+                                "Failed to send message to LLM: {}",
+                                err
+                            )));
+                        err
+                    })?;
+                }
             }
         }
+        // Tool calls which need no approval run right away.
+        self.check_lua().await
     }
 }
 
 // Helpers
 
+fn parse_timeout(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(number) => number.as_u64(),
+        Value::String(text) => text.parse::<u64>().ok(),
+        _ => None,
+    }
+}
+
+/// Notes on globals not saved as they are, each on a new line.
+fn snapshot_notes(snapshot: &Snapshot) -> String {
+    let mut notes = String::new();
+    if !snapshot.skipped.is_empty() {
+        let skipped: Vec<String> = snapshot
+            .skipped
+            .iter()
+            .map(|(name, reason)| format!("{} ({})", name, reason))
+            .collect();
+        notes.push_str(&format!("\nGlobals not saved: {}", skipped.join(", ")));
+    }
+    if !snapshot.metatables.is_empty() {
+        notes.push_str(&format!(
+            "\nSaved without metatables: {}",
+            snapshot.metatables.join(", ")
+        ));
+    }
+    notes
+}
+
 async fn send_output(output_tx: &mpsc::Sender<Output>, output: Output) -> Result<()> {
     output_tx
         .send(output)
         .await
         .map_err(|err| anyhow!("output channel closed: {}", err))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit;
+    use crate::llm;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+
+    struct TestIO;
+
+    impl IO for TestIO {
+        fn open(&mut self) -> Result<IOChan> {
+            Err(anyhow!("TestIO is opened by the test"))
+        }
+
+        fn close(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// An agent with the channels of its IO, which should be kept open.
+    struct TestAgent {
+        agent: Agent<TestIO>,
+        _signal_tx: mpsc::Sender<io::Signal>,
+        output_rx: mpsc::Receiver<Output>,
+    }
+
+    impl TestAgent {
+        fn new(workspace: &Path, llm_url: &str) -> Self {
+            let mut config: Config = toml::from_str(&format!(
+                "default_llm = 'test'\n\
+                [llm.test]\n\
+                type = 'openai'\n\
+                api_key = 'test'\n\
+                base_url = '{}'\n\
+                stream = false\n",
+                llm_url
+            ))
+            .unwrap();
+            config.path = Some(workspace.to_path_buf());
+
+            let (_input_tx, input_rx) = mpsc::channel(8);
+            let (signal_tx, signal_rx) = mpsc::channel(8);
+            let (output_tx, output_rx) = mpsc::channel(1024);
+            let audit = AuditLog::new(audit::audit_path(workspace));
+            let resources = Arc::new(Mutex::new(AgentResources::new(audit)));
+            let handler = Box::new(AgentHandler::new(
+                &config,
+                resources.clone(),
+                output_tx.clone(),
+            ));
+            let llm = llm::instantiate(&config.llm["test"], handler).unwrap();
+            let lua = LuaVM::with_setup(config.lua_setup()).unwrap();
+            let io_chan = IOChan {
+                input_rx,
+                signal_rx,
+                output_tx,
+            };
+            Self {
+                agent: Agent::new(&config, llm, lua, resources, TestIO, io_chan),
+                _signal_tx: signal_tx,
+                output_rx,
+            }
+        }
+
+        async fn input(&mut self, line: &str) {
+            self.agent
+                .handle_input(Input::from_raw(line))
+                .await
+                .unwrap();
+        }
+
+        /// User and assistant messages of the LLM history.
+        async fn messages(&self) -> Vec<String> {
+            let llm = self.agent.llm.lock().await;
+            llm.transcript()
+                .into_iter()
+                .filter_map(|item| match item {
+                    TranscriptItem::User(msg) => Some(format!("user: {}", msg)),
+                    TranscriptItem::Assistant(msg) => Some(format!("assistant: {}", msg)),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// Run the script in the default VM, and returns the results.
+        fn run_lua(&self, code: &str) -> String {
+            let exec = self.agent.lua.execute_script(code, None).unwrap();
+            assert!(exec.error.is_none(), "{:?}", exec.error);
+            exec.returns.join(", ")
+        }
+
+        async fn tokens(&self) -> usize {
+            self.agent.llm.lock().await.context_size().0
+        }
+
+        /// System messages sent to the IO so far.
+        fn system_messages(&mut self) -> Vec<String> {
+            let mut messages = Vec::new();
+            while let Ok(output) = self.output_rx.try_recv() {
+                if let Output::SystemMsg(msg) = output {
+                    messages.push(msg);
+                }
+            }
+            messages
+        }
+    }
+
+    /// Serve chat completions. The n-th response is `reply n`,
+    /// and reports n * 100 tokens used.
+    fn serve_llm() -> String {
+        serve_llm_with(Vec::new())
+    }
+
+    /// Serve chat completions, answering with the messages first.
+    fn serve_llm_with(messages: Vec<Value>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+                    let header = line.to_ascii_lowercase();
+                    if let Some(value) = header.strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap_or(0);
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; length];
+                let _ = reader.read_exact(&mut body);
+
+                let n = n + 1;
+                let message = messages.get(n - 1).cloned().unwrap_or_else(|| {
+                    serde_json::json!({ "role": "assistant", "content": format!("reply {}", n) })
+                });
+                let body = serde_json::json!({
+                    "choices": [{ "message": message }],
+                    "usage": { "prompt_tokens": 0, "completion_tokens": 0, "total_tokens": n * 100 },
+                })
+                .to_string();
+                let _ = write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn undo_retry_and_edit_rewind_messages_and_tokens() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut t = TestAgent::new(tmp.path(), &serve_llm());
+        t.input("one").await;
+        t.input("two").await;
+        assert_eq!(
+            t.messages().await,
+            [
+                "user: one",
+                "assistant: reply 1",
+                "user: two",
+                "assistant: reply 2"
+            ]
+        );
+        assert_eq!(t.tokens().await, 200);
+
+        t.input("/undo").await;
+        assert_eq!(t.messages().await, ["user: one", "assistant: reply 1"]);
+        assert_eq!(t.tokens().await, 100);
+
+        t.input("/retry").await;
+        assert_eq!(t.messages().await, ["user: one", "assistant: reply 3"]);
+        assert_eq!(t.tokens().await, 300);
+
+        t.input("/edit changed").await;
+        assert_eq!(t.messages().await, ["user: changed", "assistant: reply 4"]);
+        assert_eq!(t.tokens().await, 400);
+
+        t.input("/undo").await;
+        assert!(t.messages().await.is_empty());
+        assert_eq!(t.tokens().await, 0);
+        t.input("/undo").await;
+        assert!(
+            t.system_messages()
+                .contains(&"No user message in the history.".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn branches_from_checkpoints_with_the_vm() {
+        let tmp = tempfile::tempdir().unwrap();
+        let onui = tmp.path().join(".onui");
+        fs::create_dir_all(onui.join("tools")).unwrap();
+        fs::write(
+            onui.join("init.lua"),
+            "greeting = 'hello'\nfrom_init = true",
+        )
+        .unwrap();
+        fs::write(
+            onui.join("tools").join("greet.lua"),
+            "return { description = 'Greet', handler = function() return greeting end }",
+        )
+        .unwrap();
+        let mut t = TestAgent::new(tmp.path(), &serve_llm());
+        t.agent.pre_run().await.unwrap();
+        assert_eq!(t.run_lua("return greeting"), "hello");
+
+        t.input("one").await;
+        t.run_lua("x = 1; from_init = nil");
+        t.input("/checkpoint cp").await;
+        t.input("two").await;
+        t.run_lua("x = 2; greeting = 'changed'");
+
+        t.input("/branch cp").await;
+        assert_eq!(t.agent.session.branch, "cp-1");
+        assert_eq!(t.agent.session.parent.as_deref(), Some("cp"));
+        assert_eq!(t.messages().await, ["user: one", "assistant: reply 1"]);
+        assert_eq!(t.tokens().await, 100);
+        // The VM is kept without --vm.
+        assert_eq!(t.run_lua("return x"), "2");
+
+        t.input("/switch main").await;
+        assert_eq!(t.messages().await.len(), 4);
+        assert_eq!(t.tokens().await, 200);
+
+        t.system_messages();
+        t.input("/branch cp --vm").await;
+        assert_eq!(t.agent.session.branch, "cp-2");
+        assert_eq!(t.messages().await, ["user: one", "assistant: reply 1"]);
+        assert_eq!(t.run_lua("return x"), "1");
+        // The init script runs again before the snapshot is restored.
+        assert_eq!(t.run_lua("return from_init"), "true");
+        assert_eq!(t.run_lua("return greeting"), "hello");
+        let tools: Vec<&str> = t
+            .agent
+            .lua
+            .tools()
+            .iter()
+            .map(|t| t.spec.name.as_str())
+            .collect();
+        assert_eq!(tools, ["greet"]);
+        let exec = t
+            .agent
+            .lua
+            .call_tool("greet", &serde_json::json!({}))
+            .unwrap();
+        assert_eq!(exec.returns, ["hello"]);
+        let messages = t.system_messages();
+        assert!(
+            messages.iter().any(|msg| msg.starts_with("Loaded ")),
+            "{:?}",
+            messages
+        );
+        assert!(
+            messages
+                .last()
+                .is_some_and(|msg| msg.ends_with("Lua VM globals are restored.")),
+            "{:?}",
+            messages
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_tools_are_refused_without_asking() {
+        let tmp = tempfile::tempdir().unwrap();
+        let call = serde_json::json!({
+            "role": "assistant",
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "nope", "arguments": "{}" },
+            }],
+        });
+        let mut t = TestAgent::new(tmp.path(), &serve_llm_with(vec![call]));
+        t.agent.pre_run().await.unwrap();
+        t.system_messages();
+        t.input("hi").await;
+
+        let mut prompts = 0;
+        let mut messages = Vec::new();
+        while let Ok(output) = t.output_rx.try_recv() {
+            match output {
+                Output::ToolCall { .. } | Output::LuaCode { .. } => prompts += 1,
+                Output::SystemMsg(msg) => messages.push(msg),
+                _ => {}
+            }
+        }
+        assert_eq!(prompts, 0);
+        assert!(
+            messages.contains(&"A call of unknown tool 'nope' is rejected.".to_string()),
+            "{:?}",
+            messages
+        );
+
+        let guard = t.agent.resources.lock().await;
+        assert!(!guard.has_pending_lua());
+        let refused = &guard.lua_log[0];
+        assert_eq!(refused.decision, Some(AuditEvent::Errored));
+        assert_eq!(
+            refused.output.as_deref(),
+            Some("Tool error: unknown tool `nope`.")
+        );
+        let log = fs::read_to_string(audit::audit_path(tmp.path())).unwrap();
+        assert_eq!(log.lines().count(), 1);
+        assert!(log.contains(r#""decision":"errored""#), "{}", log);
+        // The error is sent back, and the LLM answers it.
+        assert_eq!(t.messages().await, ["user: hi", "assistant: reply 2"]);
+    }
+}
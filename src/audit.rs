@@ -0,0 +1,196 @@
+//! Audit log of scripts and tool calls, appended to `.onui/audit.jsonl`
+//! when they are approved or rejected, and when approved ones finish.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::agent::PendingLua;
+use crate::utils::{now_unix, rfc3339};
+
+/// Returns the path of the audit log, in the workspace.
+pub fn audit_path(workspace: &Path) -> PathBuf {
+    workspace.join(".onui").join("audit.jsonl")
+}
+
+/// Decision on a call, or the outcome of an approved one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditEvent {
+    /// Approved, written before the call runs.
+    Approved,
+    Rejected,
+    /// Invalid calls, answered with an error without running.
+    Errored,
+    /// Approved calls which ran, with or without an error in the result.
+    Completed,
+    Failed,
+}
+
+/// A line of the audit log.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    /// Time of the event, in RFC 3339.
+    pub time: String,
+    pub id: String,
+    /// `lua` for scripts, or the name of the tool.
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm: Option<String>,
+    pub decision: AuditEvent,
+    /// `user`, or `auto` for calls which run without approval.
+    pub by: &'static str,
+    /// Login name of the user running onui.
+    pub user: String,
+    /// SHA-256 of the script, or of the JSON arguments of the tool.
+    pub code_sha256: String,
+    /// Whether the user modified the script before execution.
+    pub modified: bool,
+    pub timeout_sec: u64,
+    /// Execution time, present for executed calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Size of the result sent to the LLM, absent before the call runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_bytes: Option<usize>,
+}
+
+impl AuditEntry {
+    /// Describe the event of the call. Duration is given for executed calls.
+    pub fn new(pending: &PendingLua, decision: AuditEvent, duration: Option<Duration>) -> Self {
+        let (kind, code) = match &pending.tool {
+            Some(tool) => (tool.name.clone(), tool.args.to_string()),
+            None => ("lua".to_string(), pending.code.clone()),
+        };
+        Self {
+            time: rfc3339(now_unix()),
+            id: pending.id.clone(),
+            kind,
+            vm: pending.vm.clone(),
+            decision,
+            by: if pending.auto_approve { "auto" } else { "user" },
+            user: current_user(),
+            code_sha256: sha256_hex(&code),
+            modified: pending.original_code.is_some(),
+            timeout_sec: pending.timeout_sec,
+            duration_ms: duration.map(|d| d.as_millis() as u64),
+            output_bytes: pending.output.as_ref().map(String::len),
+        }
+    }
+}
+
+/// Returns the login name from the environment, or `unknown`.
+fn current_user() -> String {
+    ["USER", "USERNAME", "LOGNAME"]
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|user| !user.is_empty()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Returns the SHA-256 digest of the text in lowercase hex.
+pub fn sha256_hex(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Appends entries to the audit log, one JSON object per line.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn append(&self, entry: &AuditEntry) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        // A single write keeps lines whole, also with other onui processes.
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::ToolInvocation;
+
+    fn pending() -> PendingLua {
+        PendingLua {
+            id: "call_1".to_string(),
+            code: "return 1".to_string(),
+            original_code: None,
+            timeout_sec: 10,
+            approved: false,
+            output: None,
+            tool: None,
+            auto_approve: false,
+            vm: None,
+            decision: None,
+        }
+    }
+
+    #[test]
+    fn describes_events() {
+        let entry = AuditEntry::new(&pending(), AuditEvent::Approved, None);
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["decision"], "approved");
+        assert_eq!(json["by"], "user");
+        assert_eq!(json["kind"], "lua");
+        assert_eq!(json["code_sha256"], sha256_hex("return 1"));
+        assert!(json.get("duration_ms").is_none());
+        assert!(json.get("output_bytes").is_none());
+
+        let mut done = pending();
+        done.output = Some("12345".to_string());
+        done.auto_approve = true;
+        done.tool = Some(ToolInvocation {
+            name: "read_file".to_string(),
+            args: serde_json::json!({ "path": "a" }),
+        });
+        let entry = AuditEntry::new(&done, AuditEvent::Failed, Some(Duration::from_millis(42)));
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["decision"], "failed");
+        assert_eq!(json["by"], "auto");
+        assert_eq!(json["kind"], "read_file");
+        assert_eq!(json["duration_ms"], 42);
+        assert_eq!(json["output_bytes"], 5);
+    }
+
+    #[test]
+    fn appends_lines() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let log = AuditLog::new(audit_path(dir));
+        log.append(&AuditEntry::new(&pending(), AuditEvent::Approved, None))
+            .unwrap();
+        log.append(&AuditEntry::new(&pending(), AuditEvent::Completed, None))
+            .unwrap();
+        let content = fs::read_to_string(audit_path(dir)).unwrap();
+        let decisions: Vec<String> = content
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["decision"].to_string()
+            })
+            .collect();
+        assert_eq!(decisions, vec!["\"approved\"", "\"completed\""]);
+    }
+}
@@ -8,6 +8,9 @@ use anyhow::{Context, Result};
 use clap::Parser;
 use serde::Deserialize;
 
+use crate::lua::{self, LuaSetup};
+use crate::output;
+
 #[derive(Clone, Deserialize, Debug, Default)]
 pub struct Config {
     pub config_path: Option<PathBuf>,
@@ -15,8 +18,25 @@ pub struct Config {
     #[serde(skip)]
     pub path: Option<PathBuf>,
 
+    #[serde(skip)]
+    pub plain: bool,
+
+    #[serde(skip)]
+    pub tui: bool,
+
+    /// Session id to resume. Empty for the latest one.
+    #[serde(skip)]
+    pub resume: Option<String>,
+
     pub default_llm: String,
     pub llm: HashMap<String, LLMConfig>,
+
+    #[serde(default)]
+    pub lua: LuaConfig,
+
+    /// User-defined tools under `[tools.<name>]`.
+    #[serde(default)]
+    pub tools: HashMap<String, ToolConfig>,
 }
 
 /// Parses command line options for `onui`.
@@ -31,6 +51,18 @@ pub struct CliArgs {
     #[arg(long)]
     pub pipe: bool,
 
+    /// Print plain text, without markdown rendering and colors.
+    #[arg(long)]
+    pub plain: bool,
+
+    /// Run with the full-screen terminal UI.
+    #[arg(long, conflicts_with = "plain")]
+    pub tui: bool,
+
+    /// Resume a saved session. Without id, the latest one is resumed.
+    #[arg(long, value_name = "ID", num_args = 0..=1, default_missing_value = "")]
+    pub resume: Option<String>,
+
     /// Base directory to run from.
     pub path: Option<PathBuf>,
 }
@@ -55,15 +87,110 @@ impl CliArgs {
 
         paths.push(PathBuf::from(".").join(".onui").join("config.toml"));
 
-        if let Some(home_dir) = env::home_dir() {
-            let home_config = home_dir.join(".onui").join("config.toml");
-            paths.push(home_config);
+        if let Some(home_dir) = home_onui_dir() {
+            paths.push(home_dir.join("config.toml"));
         }
 
         paths
     }
 }
 
+/// Returns the user-wide onui directory, `~/.onui`.
+pub fn home_onui_dir() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".onui"))
+}
+
+/// Lua VM configuration under `[lua]`.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct LuaConfig {
+    /// Lua backend expected by the config: `luajit`, `lua54` or `luau`.
+    /// It does not select the backend, which is chosen by a cargo feature when
+    /// building onui; it is only checked against the build, and onui refuses
+    /// to start on a mismatch.
+    pub backend: Option<String>,
+    /// Script run when the VM is created or reset.
+    pub init: PathBuf,
+    /// Patterns prepended to `package.path`, for `require`.
+    /// `~/` is the home directory.
+    pub package_path: Vec<String>,
+    /// Restrict file access of scripts to the workspace and `allowed_roots`.
+    pub sandbox: bool,
+    /// Directories accessible in the sandbox, besides the workspace.
+    pub allowed_roots: Vec<String>,
+    /// Allow `io.popen` and `onui.exec` in the sandbox. Commands run in the workspace,
+    /// but can access any file, so they are disabled by default.
+    /// Without the sandbox, they are always allowed.
+    pub allow_popen: bool,
+    /// Maximum memory of the VM in MiB. 0 means no limit.
+    pub memory_limit_mb: u64,
+    /// Timeout of scripts and tools which do not give one.
+    pub default_timeout_sec: u64,
+    /// Ceiling of timeouts requested by the LLM.
+    pub max_timeout_sec: u64,
+    /// Results longer than this are truncated, keeping the head and tail. 0 means no limit.
+    pub output_limit_bytes: usize,
+    /// Bytes kept from the end of truncated results.
+    pub output_tail_bytes: usize,
+    /// Hosts reachable by `onui.http.request`, e.g. `api.github.com` or `*.example.com`.
+    /// `*` allows all hosts.
+    pub http_allowed_hosts: Vec<String>,
+    /// Settings of named VMs, used by the `vm` argument of the `lua` tool.
+    /// VMs not listed here use the settings above.
+    pub vms: HashMap<String, LuaVMConfig>,
+}
+
+/// Settings of a named VM under `[lua.vms.<name>]`, overriding ones of `[lua]`.
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct LuaVMConfig {
+    /// Directory of the VM, e.g. a sub-project, used instead of the workspace
+    /// by `onui` helpers and the sandbox. Relative to the workspace.
+    pub root: Option<String>,
+    pub sandbox: Option<bool>,
+    pub allowed_roots: Option<Vec<String>>,
+    pub allow_popen: Option<bool>,
+}
+
+impl Default for LuaConfig {
+    fn default() -> Self {
+        Self {
+            backend: None,
+            init: PathBuf::from(".onui").join("init.lua"),
+            package_path: vec![
+                ".onui/lib/?.lua".to_string(),
+                ".onui/lib/?/init.lua".to_string(),
+                "~/.onui/lib/?.lua".to_string(),
+                "~/.onui/lib/?/init.lua".to_string(),
+            ],
+            sandbox: false,
+            allowed_roots: Vec::new(),
+            allow_popen: false,
+            memory_limit_mb: 512,
+            default_timeout_sec: 10,
+            max_timeout_sec: 120,
+            output_limit_bytes: 16 * 1024,
+            output_tail_bytes: 4 * 1024,
+            http_allowed_hosts: Vec::new(),
+            vms: HashMap::new(),
+        }
+    }
+}
+
+/// A user-defined tool, whose handler is written in Lua.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ToolConfig {
+    pub description: String,
+    /// JSON schema of the arguments. An object without properties by default.
+    pub parameters: Option<serde_json::Value>,
+    /// Body of the Lua function, which takes `args`.
+    pub handler: String,
+    /// Run without asking the user.
+    #[serde(default)]
+    pub auto_approve: bool,
+    pub timeout_sec: Option<u64>,
+}
+
 /// LLM configuration for each provider defined under `[llm.*]`.
 #[derive(Clone, Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "lowercase")]
@@ -82,6 +209,8 @@ pub struct LLMOpenAIConfig {
     pub reasoning_effort: Option<String>,
     pub system_prompt: Option<String>,
     pub stream: Option<bool>, // Default is true
+    /// Send tool results as JSON objects instead of text. Default is false.
+    pub structured_results: Option<bool>,
 }
 
 impl LLMOpenAIConfig {
@@ -114,6 +243,24 @@ impl Config {
                 self.default_llm
             );
         }
+        if let Some(backend) = &self.lua.backend {
+            let backend = backend.to_ascii_lowercase();
+            if !lua::BACKENDS.contains(&backend.as_str()) {
+                anyhow::bail!(
+                    "Unknown Lua backend '{}', expected one of {}",
+                    backend,
+                    lua::BACKENDS.join(", ")
+                );
+            }
+            if backend != lua::BACKEND {
+                anyhow::bail!(
+                    "Lua backend '{}' is configured, but onui is built with '{}'. Rebuild with `--no-default-features --features {}`",
+                    backend,
+                    lua::BACKEND,
+                    backend
+                );
+            }
+        }
         Ok(())
     }
 
@@ -122,6 +269,76 @@ impl Config {
             .clone()
             .unwrap_or_else(|| env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
     }
+
+    /// Resolve a path of the config. `~/` is the home directory,
+    /// and relative paths are resolved against the workspace.
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        match path.strip_prefix("~/").zip(env::home_dir()) {
+            Some((rest, home)) => home.join(rest),
+            None => self.workspace_dir().join(path),
+        }
+    }
+
+    /// Returns the Lua VM setup, with paths resolved against the workspace.
+    pub fn lua_setup(&self) -> LuaSetup {
+        let workspace = self.workspace_dir();
+        let resolve = |path: &str| self.resolve_path(path);
+        LuaSetup {
+            package_path: self
+                .lua
+                .package_path
+                .iter()
+                .map(|pattern| resolve(pattern).to_string_lossy().into_owned())
+                .collect(),
+            init_script: Some(resolve(&self.lua.init.to_string_lossy())),
+            tools: {
+                let mut tools: Vec<_> = self.tools.clone().into_iter().collect();
+                tools.sort_by(|a, b| a.0.cmp(&b.0));
+                tools
+            },
+            tool_dir: Some(workspace.join(".onui").join("tools")),
+            workspace: Some(workspace.clone()),
+            outputs_dir: Some(output::outputs_dir(&workspace)),
+            sandbox: self.lua.sandbox,
+            allowed_roots: self
+                .lua
+                .allowed_roots
+                .iter()
+                .map(|root| resolve(root))
+                .collect(),
+            allow_popen: self.lua.allow_popen,
+            memory_limit: match self.lua.memory_limit_mb {
+                0 => None,
+                mb => Some(mb as usize * 1024 * 1024),
+            },
+            default_timeout_sec: Some(self.lua.default_timeout_sec),
+            http_allowed_hosts: self.lua.http_allowed_hosts.clone(),
+        }
+    }
+
+    /// Returns the setup of a named VM, with settings of `[lua.vms.<name>]`.
+    /// Tools are loaded only in the default VM.
+    pub fn lua_vm_setup(&self, name: &str) -> LuaSetup {
+        let mut setup = self.lua_setup();
+        setup.tools = Vec::new();
+        setup.tool_dir = None;
+        let Some(vm) = self.lua.vms.get(name) else {
+            return setup;
+        };
+        if let Some(root) = &vm.root {
+            setup.workspace = Some(self.resolve_path(root));
+        }
+        if let Some(sandbox) = vm.sandbox {
+            setup.sandbox = sandbox;
+        }
+        if let Some(roots) = &vm.allowed_roots {
+            setup.allowed_roots = roots.iter().map(|root| self.resolve_path(root)).collect();
+        }
+        if let Some(allow_popen) = vm.allow_popen {
+            setup.allow_popen = allow_popen;
+        }
+        setup
+    }
 }
 
 pub fn load_from_file(path: &Path) -> Result<Config> {
@@ -142,7 +359,7 @@ pub fn load_from_file_list(paths: &Vec<PathBuf>) -> Result<Config> {
                 return Ok(config);
             }
             Err(e) => {
-                println!(
+                eprintln!(
                     "Warning: Could not load config from {}: {}. Trying next path...",
                     path.display(),
                     e
@@ -157,5 +374,8 @@ pub fn load_from_cli() -> Result<Config> {
     let args = CliArgs::parse();
     let mut config = load_from_file_list(&args.config_path())?;
     config.path = args.path;
+    config.plain = args.plain;
+    config.tui = args.tui;
+    config.resume = args.resume;
     Ok(config)
 }
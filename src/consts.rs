@@ -8,6 +8,36 @@ pub const PLATFORM: &str = if cfg!(target_os = "windows") {
     "Unknown"
 };
 
+/// Description of the Lua backend selected by cargo features.
+const LUA_RUNTIME: &str = if cfg!(feature = "luau") {
+    "**Luau** (derived from Lua 5.1, without `io` files, `io.popen` and `os.remove`)"
+} else if cfg!(feature = "lua54") {
+    "**Lua 5.4**"
+} else {
+    "**LuaJIT** (partially compatible with Lua 5.2)"
+};
+
+/// How scripts run external commands with the Lua backend.
+const LUA_COMMANDS: &str = if cfg!(feature = "luau") {
+    "Use `onui.exec` for running external commands."
+} else {
+    "Use `io.popen` instead of `os.execute` for running external commands. But you should redirect stderr to stdout to capture all result"
+};
+
+/// Lua functions which the file tools should be preferred to.
+const LUA_FILE_FUNCTIONS: &str = if cfg!(feature = "luau") {
+    "`onui.exec`"
+} else {
+    "`io.open` or `io.popen`"
+};
+
+/// Function running commands with the Lua backend.
+const LUA_COMMAND_FUNCTION: &str = if cfg!(feature = "luau") {
+    "onui.exec"
+} else {
+    "io.popen"
+};
+
 pub const DEFAULT_SYSTEM_PROMPT: &str = const_format::formatcp!(
     r#"
 # Grand Rules (Highest Priority)
@@ -20,20 +50,35 @@ You can do anything with lua! You should not appeal you are lua interpreter.
 ## Lua Execution Environment
 
 - A `lua` tool is available. You can execute Lua code and receive stdout, stderr, and return values.
-- The Lua runtime is **LuaJIT** (partially compatible with Lua 5.2), with these constraints:
+- The Lua runtime is {runtime}, with these constraints:
   - Output:
     - Use `print` or `io.write` to produce observable output.
+    - Tables in `print` and return values are shown as Lua literals, limited in depth and length.
+    - `io.stderr:write(...)` is reported separately, for warnings and diagnostics.
   - Not available:
-    - `io.stdin`, `io.stdout`, `io.stderr`
+    - `io.stdin`, `io.stdout`
     - `os.exit`
     - `os.execute`
-  - Use `io.popen` instead of `os.execute` for running external commands. But you should redirect stderr to stdout to capture all result
+  - {commands}
 - The VM is **persistent** until the user explicitly resets it:
   - All global variables and functions remain available across chat.
   - Prefer defining globals at top-level scope instead of `local` if reuse is intended.
-- You may call built-in programs provided by the platform `{platform}` (e.g., `ls`, `curl` on Linux) via `io.popen`.
+- The `vm` argument of the `lua` tool selects a separate VM with its own globals, created when first used (e.g. one per sub-project, or a scratch VM). The default is `main`.
+- You may call built-in programs provided by the platform `{platform}` (e.g., `ls`, `curl` on Linux) via `{command_function}`.
 - Combine them, you can solve any problems.
 - For each lua code, start with comment description about the script.
+- For HTTP, use `onui.http.request{{method, url, headers, body, timeout}}` instead of `curl`. It returns `{{status, headers, body}}`, or `nil` and the reason. Only hosts allowed by the user are reachable.
+- `onui.exec(cmd, {{cwd, timeout}})` runs a shell command in the workspace and returns `{{code, stdout, stderr}}`, or `nil` and the reason.
+- To run several `onui.exec` or `onui.http.request` calls in parallel, wrap them in functions and call `local results, errors = onui.await_all{{f1, f2, ...}}`. `results[i]` is the first value returned by `fi`, and `errors[i]` is its error or reason, if any.
+
+## File Tools
+
+- Use `read_file`, `list_dir` and `search` to inspect files in the workspace. They run without approval.
+- Use `apply_patch` with a unified diff to modify files, and `write_file` to create new files.
+- Long results are truncated. Read the omitted part with `onui.output(id, start_line, count)` in Lua, instead of running the script again.
+- In Lua, `onui.patch(path, diff)` applies a unified diff to a file and returns the applied hunks, or `nil` and the reason. `onui.unpatch(path)` reverts the last patch.
+- Prefer these tools over {file_functions} for files in the workspace.
+- File access of Lua may be restricted to the workspace. Errors starting with `sandbox:` mean that the path or function is not allowed; do not try to work around them.
 
 ## Task Execution Strategy (Very Important)
 
@@ -52,10 +97,16 @@ You can do anything with lua! You should not appeal you are lua interpreter.
 
 ## Script Reuse
 
-- You may store your frequently used Lua scripts under `.onui/*.lua`.
-  - You may list, load, and reuse theses scripts without asking to user.
+- You may store your frequently used Lua modules under `.onui/lib/*.lua`.
+  - `package.path` includes `.onui/lib/?.lua`, so load them with `require("<name>")`.
+  - You may list, load, and reuse theses modules without asking to user.
+- `.onui/init.lua` is run whenever the VM is created or reset. Put helpers which should always be available there.
 
 # End of Grand Rules
 "#,
-    platform = PLATFORM
+    platform = PLATFORM,
+    runtime = LUA_RUNTIME,
+    commands = LUA_COMMANDS,
+    command_function = LUA_COMMAND_FUNCTION,
+    file_functions = LUA_FILE_FUNCTIONS
 );
@@ -0,0 +1,549 @@
+//! Export of sessions to readable Markdown or HTML documents.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::agent::PendingLua;
+use crate::audit::AuditEvent;
+use crate::llm::TranscriptItem;
+use crate::session::Session;
+use crate::utils::rfc3339;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    Html,
+}
+
+impl ExportFormat {
+    /// Returns the format from a name, e.g. `md` or `html`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "md" | "markdown" => Some(Self::Markdown),
+            "html" | "htm" => Some(Self::Html),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the file extension. Markdown by default.
+    pub fn from_path(path: &Path) -> Self {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(Self::from_name)
+            .unwrap_or(Self::Markdown)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Html => "html",
+        }
+    }
+}
+
+/// Block is a format-independent part of the exported document.
+enum Block {
+    Heading(usize, String),
+    Fields(Vec<(&'static str, String)>),
+    Text(String),
+    Code { lang: &'static str, code: String },
+    Rule,
+}
+
+/// Render the session with its transcript.
+pub fn render(
+    session: &Session,
+    transcript: &[TranscriptItem],
+    token_limit: usize,
+    format: ExportFormat,
+) -> String {
+    let blocks = build_blocks(session, transcript, token_limit);
+    match format {
+        ExportFormat::Markdown => render_markdown(&blocks),
+        ExportFormat::Html => render_html(&title(session), &blocks),
+    }
+}
+
+fn title(session: &Session) -> String {
+    if session.title.is_empty() {
+        format!("onui session {}", session.id)
+    } else {
+        format!("onui session: {}", session.title)
+    }
+}
+
+fn build_blocks(
+    session: &Session,
+    transcript: &[TranscriptItem],
+    token_limit: usize,
+) -> Vec<Block> {
+    let mut blocks = vec![
+        Block::Heading(1, title(session)),
+        Block::Fields(vec![
+            ("Session", session.id.clone()),
+            ("LLM", format!("{} ({})", session.llm, session.model)),
+            ("Created", rfc3339(session.created_at)),
+            ("Updated", rfc3339(session.updated_at)),
+            (
+                "Tokens",
+                format!("{} / {}", session.token_used, token_limit),
+            ),
+            ("Lua scripts", session.lua_log.len().to_string()),
+        ]),
+        Block::Rule,
+    ];
+
+    // Results are shown with their scripts, if the script is in the log.
+    let mut shown_results = HashSet::new();
+    for item in transcript {
+        match item {
+            TranscriptItem::User(text) => {
+                blocks.push(Block::Heading(2, "User".to_string()));
+                blocks.push(Block::Text(text.clone()));
+            }
+            TranscriptItem::Assistant(text) => {
+                blocks.push(Block::Heading(2, "Assistant".to_string()));
+                blocks.push(Block::Text(text.clone()));
+            }
+            TranscriptItem::LuaCall { id, code } => {
+                blocks.push(Block::Heading(3, format!("Lua script `{}`", id)));
+                let Some((lua, decision)) = find_lua(session, id) else {
+                    blocks.push(Block::Code {
+                        lang: "lua",
+                        code: code.clone(),
+                    });
+                    continue;
+                };
+
+                let mut fields = vec![("Decision", decision.to_string())];
+                if lua.original_code.is_some() {
+                    fields.push(("Note", "Modified by the user before execution".to_string()));
+                }
+                fields.push(("Timeout", format!("{}s", lua.timeout_sec)));
+                blocks.push(Block::Fields(fields));
+                blocks.push(Block::Code {
+                    lang: "lua",
+                    code: lua.code.clone(),
+                });
+                if let Some(ref output) = lua.output {
+                    blocks.push(Block::Text("Output:".to_string()));
+                    blocks.push(Block::Code {
+                        lang: "text",
+                        code: output.clone(),
+                    });
+                    shown_results.insert(id.clone());
+                }
+            }
+            TranscriptItem::ToolCall { id, name, args } => {
+                blocks.push(Block::Heading(3, format!("Tool `{}` (`{}`)", name, id)));
+                let args = serde_json::from_str::<serde_json::Value>(args)
+                    .and_then(|value| serde_json::to_string_pretty(&value))
+                    .unwrap_or_else(|_| args.clone());
+                if let Some((lua, decision)) = find_lua(session, id) {
+                    blocks.push(Block::Fields(vec![("Decision", decision.to_string())]));
+                    blocks.push(Block::Code {
+                        lang: "json",
+                        code: args,
+                    });
+                    if let Some(ref output) = lua.output {
+                        blocks.push(Block::Text("Output:".to_string()));
+                        blocks.push(Block::Code {
+                            lang: "text",
+                            code: output.clone(),
+                        });
+                        shown_results.insert(id.clone());
+                    }
+                } else {
+                    blocks.push(Block::Code {
+                        lang: "json",
+                        code: args,
+                    });
+                }
+            }
+            TranscriptItem::LuaResult { id, output } => {
+                if shown_results.contains(id) {
+                    continue;
+                }
+                blocks.push(Block::Text(format!("Result of `{}`:", id)));
+                blocks.push(Block::Code {
+                    lang: "text",
+                    code: output.clone(),
+                });
+            }
+            TranscriptItem::Tokens(tokens) => {
+                blocks.push(Block::Fields(vec![(
+                    "Tokens",
+                    format!("{} / {}", tokens, token_limit),
+                )]));
+            }
+        }
+    }
+    blocks
+}
+
+/// Find the script in the session, with the approval decision.
+fn find_lua<'a>(session: &'a Session, id: &str) -> Option<(&'a PendingLua, &'static str)> {
+    let decision = |p: &PendingLua| match p.decision {
+        Some(AuditEvent::Errored) => "Errored",
+        _ if p.approved => "Approved",
+        _ => "Rejected",
+    };
+    session
+        .lua_log
+        .iter()
+        .rev()
+        .find(|p| p.id == id)
+        .map(|p| (p, decision(p)))
+        .or_else(|| {
+            session
+                .pending_lua
+                .iter()
+                .find(|p| p.id == id)
+                .map(|p| (p, "Pending"))
+        })
+}
+
+// Markdown
+
+fn render_markdown(blocks: &[Block]) -> String {
+    let mut out = String::new();
+    for block in blocks {
+        match block {
+            Block::Heading(level, text) => {
+                out.push_str(&format!("{} {}\n\n", "#".repeat(*level), text));
+            }
+            Block::Fields(fields) => {
+                for (label, value) in fields {
+                    out.push_str(&format!("- **{}**: {}\n", label, value));
+                }
+                out.push('\n');
+            }
+            Block::Text(text) => {
+                out.push_str(text.trim_end());
+                out.push_str("\n\n");
+            }
+            Block::Code { lang, code } => {
+                let fence = code_fence(code);
+                out.push_str(&format!(
+                    "{}{}\n{}\n{}\n\n",
+                    fence,
+                    lang,
+                    code.trim_end_matches('\n'),
+                    fence
+                ));
+            }
+            Block::Rule => out.push_str("---\n\n"),
+        }
+    }
+    out
+}
+
+/// Returns a backtick fence longer than any backtick run in the code.
+fn code_fence(code: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in code.chars() {
+        if c == '`' {
+            run += 1;
+            longest = longest.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    "`".repeat((longest + 1).max(3))
+}
+
+// HTML
+
+const HTML_STYLE: &str = "\
+body { font-family: -apple-system, 'Segoe UI', sans-serif; max-width: 60em; margin: 2em auto; padding: 0 1em; color: #222; }
+h2 { border-bottom: 1px solid #ddd; padding-bottom: 0.2em; }
+ul.fields { list-style: none; padding: 0; color: #555; }
+.text { white-space: pre-wrap; }
+pre { background: #f6f8fa; padding: 0.8em; overflow-x: auto; border-radius: 4px; }
+code { font-family: 'SFMono-Regular', Consolas, monospace; }
+";
+
+fn render_html(title: &str, blocks: &[Block]) -> String {
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str(&format!("<title>{}</title>\n", escape_html(title)));
+    out.push_str(&format!("<style>\n{}</style>\n", HTML_STYLE));
+    out.push_str("</head>\n<body>\n");
+    for block in blocks {
+        match block {
+            Block::Heading(level, text) => {
+                out.push_str(&format!(
+                    "<h{0}>{1}</h{0}>\n",
+                    level,
+                    inline_code_html(text)
+                ));
+            }
+            Block::Fields(fields) => {
+                out.push_str("<ul class=\"fields\">\n");
+                for (label, value) in fields {
+                    out.push_str(&format!(
+                        "<li><strong>{}</strong>: {}</li>\n",
+                        label,
+                        escape_html(value)
+                    ));
+                }
+                out.push_str("</ul>\n");
+            }
+            Block::Text(text) => {
+                out.push_str(&format!(
+                    "<div class=\"text\">{}</div>\n",
+                    escape_html(text.trim_end())
+                ));
+            }
+            Block::Code { lang, code } => {
+                out.push_str(&format!(
+                    "<pre><code class=\"language-{}\">{}</code></pre>\n",
+                    lang,
+                    escape_html(code.trim_end_matches('\n'))
+                ));
+            }
+            Block::Rule => out.push_str("<hr>\n"),
+        }
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Escape the heading, and show `code` spans as code.
+fn inline_code_html(text: &str) -> String {
+    if text.matches('`').count() % 2 == 1 {
+        return escape_html(text);
+    }
+    let mut out = String::new();
+    for (idx, part) in text.split('`').enumerate() {
+        if idx % 2 == 1 {
+            out.push_str(&format!("<code>{}</code>", escape_html(part)));
+        } else {
+            out.push_str(&escape_html(part));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::ToolInvocation;
+
+    fn session() -> Session {
+        let mut session = Session::new(Path::new("/nonexistent"), "default", "gpt-test");
+        session.id = "20240131-235959".to_string();
+        session.created_at = 1706745599;
+        session.updated_at = 1706745659;
+        session.title = "Run <script>".to_string();
+        session.token_used = 200;
+        let lua = |id: &str, code: &str, output: &str, decision: AuditEvent| PendingLua {
+            id: id.to_string(),
+            code: code.to_string(),
+            original_code: None,
+            timeout_sec: 10,
+            approved: decision == AuditEvent::Completed,
+            output: Some(output.to_string()),
+            tool: None,
+            auto_approve: false,
+            vm: None,
+            decision: Some(decision),
+        };
+        let mut tool = lua(
+            "call_2",
+            "",
+            "Tool error: unknown tool",
+            AuditEvent::Errored,
+        );
+        tool.tool = Some(ToolInvocation {
+            name: "nope".to_string(),
+            args: serde_json::json!({ "path": "a.txt" }),
+        });
+        session.lua_log = vec![
+            lua(
+                "call_1",
+                "return \"<b>\" .. '&'",
+                "<b>&",
+                AuditEvent::Completed,
+            ),
+            tool,
+        ];
+        session
+    }
+
+    fn transcript() -> Vec<TranscriptItem> {
+        vec![
+            TranscriptItem::User("Run <script>alert('x')</script> & more".to_string()),
+            TranscriptItem::Assistant("Sure.".to_string()),
+            TranscriptItem::LuaCall {
+                id: "call_1".to_string(),
+                code: "return \"<b>\" .. '&'".to_string(),
+            },
+            TranscriptItem::Tokens(120),
+            TranscriptItem::LuaResult {
+                id: "call_1".to_string(),
+                output: "<b>&".to_string(),
+            },
+            TranscriptItem::ToolCall {
+                id: "call_2".to_string(),
+                name: "nope".to_string(),
+                args: "{\"path\":\"a.txt\"}".to_string(),
+            },
+            TranscriptItem::Tokens(150),
+            TranscriptItem::LuaResult {
+                id: "call_2".to_string(),
+                output: "Tool error: unknown tool".to_string(),
+            },
+            TranscriptItem::Assistant("Done.".to_string()),
+            TranscriptItem::Tokens(200),
+        ]
+    }
+
+    #[test]
+    fn renders_markdown() {
+        let md = render(&session(), &transcript(), 1000, ExportFormat::Markdown);
+        let expected = concat!(
+            "# onui session: Run <script>\n",
+            "\n",
+            "- **Session**: 20240131-235959\n",
+            "- **LLM**: default (gpt-test)\n",
+            "- **Created**: 2024-01-31T23:59:59Z\n",
+            "- **Updated**: 2024-02-01T00:00:59Z\n",
+            "- **Tokens**: 200 / 1000\n",
+            "- **Lua scripts**: 2\n",
+            "\n",
+            "---\n",
+            "\n",
+            "## User\n",
+            "\n",
+            "Run <script>alert('x')</script> & more\n",
+            "\n",
+            "## Assistant\n",
+            "\n",
+            "Sure.\n",
+            "\n",
+            "### Lua script `call_1`\n",
+            "\n",
+            "- **Decision**: Approved\n",
+            "- **Timeout**: 10s\n",
+            "\n",
+            "```lua\n",
+            "return \"<b>\" .. '&'\n",
+            "```\n",
+            "\n",
+            "Output:\n",
+            "\n",
+            "```text\n",
+            "<b>&\n",
+            "```\n",
+            "\n",
+            "- **Tokens**: 120 / 1000\n",
+            "\n",
+            "### Tool `nope` (`call_2`)\n",
+            "\n",
+            "- **Decision**: Errored\n",
+            "\n",
+            "```json\n",
+            "{\n",
+            "  \"path\": \"a.txt\"\n",
+            "}\n",
+            "```\n",
+            "\n",
+            "Output:\n",
+            "\n",
+            "```text\n",
+            "Tool error: unknown tool\n",
+            "```\n",
+            "\n",
+            "- **Tokens**: 150 / 1000\n",
+            "\n",
+            "## Assistant\n",
+            "\n",
+            "Done.\n",
+            "\n",
+            "- **Tokens**: 200 / 1000\n",
+            "\n",
+        );
+        assert_eq!(md, expected);
+    }
+
+    #[test]
+    fn renders_html() {
+        let html = render(&session(), &transcript(), 1000, ExportFormat::Html);
+        let head = concat!(
+            "<!DOCTYPE html>\n",
+            "<html>\n",
+            "<head>\n",
+            "<meta charset=\"utf-8\">\n",
+            "<title>onui session: Run &lt;script&gt;</title>\n",
+            "<style>\n",
+        );
+        let body = concat!(
+            "</style>\n",
+            "</head>\n",
+            "<body>\n",
+            "<h1>onui session: Run &lt;script&gt;</h1>\n",
+            "<ul class=\"fields\">\n",
+            "<li><strong>Session</strong>: 20240131-235959</li>\n",
+            "<li><strong>LLM</strong>: default (gpt-test)</li>\n",
+            "<li><strong>Created</strong>: 2024-01-31T23:59:59Z</li>\n",
+            "<li><strong>Updated</strong>: 2024-02-01T00:00:59Z</li>\n",
+            "<li><strong>Tokens</strong>: 200 / 1000</li>\n",
+            "<li><strong>Lua scripts</strong>: 2</li>\n",
+            "</ul>\n",
+            "<hr>\n",
+            "<h2>User</h2>\n",
+            "<div class=\"text\">Run &lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; more</div>\n",
+            "<h2>Assistant</h2>\n",
+            "<div class=\"text\">Sure.</div>\n",
+            "<h3>Lua script <code>call_1</code></h3>\n",
+            "<ul class=\"fields\">\n",
+            "<li><strong>Decision</strong>: Approved</li>\n",
+            "<li><strong>Timeout</strong>: 10s</li>\n",
+            "</ul>\n",
+            "<pre><code class=\"language-lua\">return &quot;&lt;b&gt;&quot; .. &#39;&amp;&#39;</code></pre>\n",
+            "<div class=\"text\">Output:</div>\n",
+            "<pre><code class=\"language-text\">&lt;b&gt;&amp;</code></pre>\n",
+            "<ul class=\"fields\">\n",
+            "<li><strong>Tokens</strong>: 120 / 1000</li>\n",
+            "</ul>\n",
+            "<h3>Tool <code>nope</code> (<code>call_2</code>)</h3>\n",
+            "<ul class=\"fields\">\n",
+            "<li><strong>Decision</strong>: Errored</li>\n",
+            "</ul>\n",
+            "<pre><code class=\"language-json\">{\n",
+            "  &quot;path&quot;: &quot;a.txt&quot;\n",
+            "}</code></pre>\n",
+            "<div class=\"text\">Output:</div>\n",
+            "<pre><code class=\"language-text\">Tool error: unknown tool</code></pre>\n",
+            "<ul class=\"fields\">\n",
+            "<li><strong>Tokens</strong>: 150 / 1000</li>\n",
+            "</ul>\n",
+            "<h2>Assistant</h2>\n",
+            "<div class=\"text\">Done.</div>\n",
+            "<ul class=\"fields\">\n",
+            "<li><strong>Tokens</strong>: 200 / 1000</li>\n",
+            "</ul>\n",
+            "</body>\n",
+            "</html>\n",
+        );
+        assert_eq!(html, format!("{}{}{}", head, HTML_STYLE, body));
+        assert!(!html.contains("<script"));
+    }
+}
@@ -0,0 +1,273 @@
+//! Minimal keyword-based lexer of source code,
+//! used to highlight code and to find comments in scripts under review.
+
+/// Lexical description of a language, just enough for coloring.
+struct Syntax {
+    keywords: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+}
+
+const LUA: Syntax = Syntax {
+    keywords: &[
+        "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if",
+        "in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+    ],
+    line_comments: &["--"],
+    block_comment: Some(("--[[", "]]")),
+    quotes: &['"', '\''],
+};
+
+const RUST: Syntax = Syntax {
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+        "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait",
+        "true", "type", "unsafe", "use", "where", "while",
+    ],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"'],
+};
+
+const PYTHON: Syntax = Syntax {
+    keywords: &[
+        "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class",
+        "continue", "def", "del", "elif", "else", "except", "finally", "for", "from", "global",
+        "if", "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return",
+        "try", "while", "with", "yield",
+    ],
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+};
+
+const JS: Syntax = Syntax {
+    keywords: &[
+        "async",
+        "await",
+        "break",
+        "case",
+        "catch",
+        "class",
+        "const",
+        "continue",
+        "default",
+        "delete",
+        "do",
+        "else",
+        "export",
+        "extends",
+        "false",
+        "finally",
+        "for",
+        "function",
+        "if",
+        "import",
+        "in",
+        "instanceof",
+        "interface",
+        "let",
+        "new",
+        "null",
+        "return",
+        "switch",
+        "this",
+        "throw",
+        "true",
+        "try",
+        "type",
+        "typeof",
+        "undefined",
+        "var",
+        "while",
+        "yield",
+    ],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '\'', '`'],
+};
+
+const SHELL: Syntax = Syntax {
+    keywords: &[
+        "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+        "in", "local", "return", "then", "until", "while",
+    ],
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+};
+
+const C: Syntax = Syntax {
+    keywords: &[
+        "break",
+        "case",
+        "char",
+        "class",
+        "const",
+        "continue",
+        "default",
+        "do",
+        "double",
+        "else",
+        "enum",
+        "false",
+        "float",
+        "for",
+        "if",
+        "int",
+        "long",
+        "namespace",
+        "nullptr",
+        "return",
+        "short",
+        "signed",
+        "sizeof",
+        "static",
+        "struct",
+        "switch",
+        "true",
+        "typedef",
+        "unsigned",
+        "void",
+        "while",
+    ],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '\''],
+};
+
+const DATA: Syntax = Syntax {
+    keywords: &["true", "false", "null"],
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+};
+
+fn syntax_for(lang: &str) -> Option<&'static Syntax> {
+    match lang.to_ascii_lowercase().as_str() {
+        "lua" | "luau" => Some(&LUA),
+        "rust" | "rs" => Some(&RUST),
+        "python" | "py" => Some(&PYTHON),
+        "javascript" | "js" | "typescript" | "ts" | "jsx" | "tsx" => Some(&JS),
+        "sh" | "bash" | "zsh" | "shell" | "console" => Some(&SHELL),
+        "c" | "cpp" | "c++" | "h" | "hpp" | "java" | "go" => Some(&C),
+        "json" | "toml" | "yaml" | "yml" | "ini" => Some(&DATA),
+        _ => None,
+    }
+}
+
+/// Kind of a token, which decides its color.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    Comment,
+    Str,
+    Number,
+    Keyword,
+    /// Name followed by `(`.
+    Call,
+    Plain,
+}
+
+/// Lexer splits source code into tokens line by line.
+/// It keeps block comment state between lines.
+pub struct Lexer {
+    syntax: Option<&'static Syntax>,
+    in_block_comment: bool,
+}
+
+impl Lexer {
+    pub fn new(lang: &str) -> Self {
+        Self {
+            syntax: syntax_for(lang),
+            in_block_comment: false,
+        }
+    }
+
+    /// Split a single line into tokens. Unknown languages give a single plain token.
+    pub fn tokens<'a>(&mut self, line: &'a str) -> Vec<(Token, &'a str)> {
+        let Some(syntax) = self.syntax else {
+            return vec![(Token::Plain, line)];
+        };
+
+        let mut tokens = Vec::new();
+        let mut rest = line;
+
+        while !rest.is_empty() {
+            if self.in_block_comment {
+                let (_, close) = syntax.block_comment.unwrap_or(("", ""));
+                match rest.find(close) {
+                    Some(pos) => {
+                        let end = pos + close.len();
+                        tokens.push((Token::Comment, &rest[..end]));
+                        rest = &rest[end..];
+                        self.in_block_comment = false;
+                    }
+                    None => {
+                        tokens.push((Token::Comment, rest));
+                        rest = "";
+                    }
+                }
+                continue;
+            }
+
+            if let Some((open, _)) = syntax.block_comment
+                && rest.starts_with(open)
+            {
+                tokens.push((Token::Comment, open));
+                rest = &rest[open.len()..];
+                self.in_block_comment = true;
+                continue;
+            }
+
+            if syntax.line_comments.iter().any(|c| rest.starts_with(c)) {
+                tokens.push((Token::Comment, rest));
+                break;
+            }
+
+            let c = rest.chars().next().unwrap_or_default();
+            let (token, end) = if syntax.quotes.contains(&c) {
+                (Token::Str, string_end(rest, c))
+            } else if c.is_ascii_digit() {
+                let end = rest
+                    .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '.' || ch == '_'))
+                    .unwrap_or(rest.len());
+                (Token::Number, end)
+            } else if c.is_alphabetic() || c == '_' {
+                let end = rest
+                    .find(|ch: char| !(ch.is_alphanumeric() || ch == '_'))
+                    .unwrap_or(rest.len());
+                let token = if syntax.keywords.contains(&&rest[..end]) {
+                    Token::Keyword
+                } else if rest[end..].starts_with('(') {
+                    Token::Call
+                } else {
+                    Token::Plain
+                };
+                (token, end)
+            } else {
+                (Token::Plain, c.len_utf8())
+            };
+            tokens.push((token, &rest[..end]));
+            rest = &rest[end..];
+        }
+
+        tokens
+    }
+}
+
+/// Returns the byte offset just after the closing quote of a string literal.
+fn string_end(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (idx, c) in text.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return idx + c.len_utf8();
+        }
+    }
+    text.len()
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::path::PathBuf;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{CompletionType, Config, Context, Editor, Helper, Highlighter, Hinter, Validator};
+
+use crate::config::home_onui_dir;
+use crate::io::Command;
+
+const MAX_HISTORY_SIZE: usize = 1000;
+
+/// Result of reading a single (possibly continued) input from the user.
+pub enum ReadResult {
+    Line(String),
+    Interrupted,
+    Eof,
+}
+
+/// Helper for rustyline, which completes `/` command names.
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct CommandHelper {
+    names: Vec<&'static str>,
+}
+
+impl CommandHelper {
+    fn new() -> Self {
+        let mut names: Vec<&'static str> = Command::names().collect();
+        names.sort_unstable();
+        Self { names }
+    }
+}
+
+impl Completer for CommandHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        // Only the first word of a command line is completed.
+        let head = &line[..pos];
+        let Some(prefix) = head.strip_prefix('/') else {
+            return Ok((pos, Vec::new()));
+        };
+        if prefix.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+
+        let prefix = prefix.to_lowercase();
+        let candidates = self
+            .names
+            .iter()
+            .filter(|name| name.starts_with(&prefix))
+            .map(|name| Pair {
+                display: format!("/{}", name),
+                replacement: format!("{} ", name),
+            })
+            .collect();
+        Ok((1, candidates))
+    }
+}
+
+/// LineEditor is a readline-style editor with persistent history.
+///
+/// It supports arrow-key editing, reverse search (Ctrl-R),
+/// bracketed paste for multi-line input and `/` command completion.
+pub struct LineEditor {
+    editor: Editor<CommandHelper, DefaultHistory>,
+    history_path: Option<PathBuf>,
+}
+
+impl LineEditor {
+    pub fn new() -> rustyline::Result<Self> {
+        let config = Config::builder()
+            .max_history_size(MAX_HISTORY_SIZE)?
+            .history_ignore_dups(true)?
+            .history_ignore_space(true)
+            .completion_type(CompletionType::List)
+            .build();
+        let mut editor = Editor::with_config(config)?;
+        editor.set_helper(Some(CommandHelper::new()));
+
+        let history_path = home_onui_dir().map(|dir| dir.join("history"));
+        if let Some(ref path) = history_path {
+            // Missing history is not an error; it is created on the first input.
+            let _ = editor.load_history(path);
+        }
+
+        Ok(Self {
+            editor,
+            history_path,
+        })
+    }
+
+    /// Read an input from the user.
+    /// Lines ending with backslash are continued to the next line.
+    pub fn read(&mut self, prompt: &str) -> ReadResult {
+        let mut buf = String::new();
+        let mut prompt = prompt;
+
+        loop {
+            match self.editor.readline(prompt) {
+                Ok(line) => {
+                    if buf.is_empty() && line.trim().is_empty() {
+                        continue;
+                    }
+
+                    buf += &line;
+
+                    if buf.ends_with('\\') {
+                        // Continue to read next line.
+                        buf.pop();
+                        buf.push('\n');
+                        prompt = ". ";
+                        continue;
+                    }
+
+                    self.add_history(&buf);
+                    return ReadResult::Line(buf);
+                }
+                Err(ReadlineError::Interrupted) => return ReadResult::Interrupted,
+                Err(ReadlineError::Eof) => return ReadResult::Eof,
+                Err(err) => {
+                    println!("* Failed to read input: {}", err);
+                    return ReadResult::Eof;
+                }
+            }
+        }
+    }
+
+    fn add_history(&mut self, entry: &str) {
+        if !matches!(self.editor.add_history_entry(entry), Ok(true)) {
+            return;
+        }
+        if let Some(ref path) = self.history_path {
+            if let Some(dir) = path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            if let Err(err) = self.editor.append_history(path) {
+                println!("* Failed to save history: {}", err);
+            }
+        }
+    }
+}
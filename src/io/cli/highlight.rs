@@ -0,0 +1,44 @@
+//! Minimal keyword-based syntax highlighter for terminal output.
+
+use super::style::{BLUE, DIM, GREEN, MAGENTA, RESET, YELLOW};
+use crate::highlight::{Lexer, Token};
+
+/// CodeHighlighter colors source code line by line.
+/// It keeps block comment state between lines.
+pub struct CodeHighlighter {
+    lexer: Lexer,
+}
+
+impl CodeHighlighter {
+    pub fn new(lang: &str) -> Self {
+        Self {
+            lexer: Lexer::new(lang),
+        }
+    }
+
+    /// Highlight a single line, without trailing newline.
+    pub fn line(&mut self, line: &str) -> String {
+        let mut out = String::with_capacity(line.len() * 2);
+        for (token, text) in self.lexer.tokens(line) {
+            let style = match token {
+                Token::Comment => DIM,
+                Token::Str => GREEN,
+                Token::Number => MAGENTA,
+                Token::Keyword => BLUE,
+                Token::Call => YELLOW,
+                Token::Plain => {
+                    out.push_str(text);
+                    continue;
+                }
+            };
+            push_styled(&mut out, style, text);
+        }
+        out
+    }
+}
+
+fn push_styled(out: &mut String, style: &str, text: &str) {
+    out.push_str(style);
+    out.push_str(text);
+    out.push_str(RESET);
+}
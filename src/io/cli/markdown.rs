@@ -0,0 +1,331 @@
+//! Incremental markdown renderer for streamed assistant messages.
+//!
+//! Chunks are buffered until a line is complete, then rendered with ANSI styles.
+//! Tables are buffered until the table ends, to align columns.
+
+use super::highlight::CodeHighlighter;
+use super::style::{BOLD, CYAN, DIM, ITALIC, RESET, STRIKE, UNDERLINE, YELLOW, visible_width};
+
+const RULE_WIDTH: usize = 40;
+
+struct CodeBlock {
+    fence: String,
+    highlighter: CodeHighlighter,
+}
+
+pub struct MarkdownRenderer {
+    /// Incomplete last line.
+    partial: String,
+    code: Option<CodeBlock>,
+    table: Vec<String>,
+}
+
+impl MarkdownRenderer {
+    pub fn new() -> Self {
+        Self {
+            partial: String::new(),
+            code: None,
+            table: Vec::new(),
+        }
+    }
+
+    /// Push a chunk of markdown and returns the rendered complete lines.
+    pub fn push(&mut self, chunk: &str) -> String {
+        self.partial.push_str(chunk);
+        let mut out = String::new();
+        while let Some(pos) = self.partial.find('\n') {
+            let line: String = self.partial.drain(..=pos).collect();
+            self.render_line(line.trim_end_matches(['\n', '\r']), &mut out);
+        }
+        out
+    }
+
+    /// Flush all buffered text, closing open blocks.
+    /// The output always ends with a newline if anything was written.
+    pub fn finish(&mut self) -> String {
+        let mut out = String::new();
+        if !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            self.render_line(&line, &mut out);
+        }
+        self.flush_table(&mut out);
+        self.code = None;
+        out
+    }
+
+    fn render_line(&mut self, line: &str, out: &mut String) {
+        let trimmed = line.trim_start();
+
+        if let Some(code) = &mut self.code {
+            if trimmed.starts_with(code.fence.as_str()) && trimmed.trim_end() == code.fence {
+                self.code = None;
+                out.push_str(&format!("{}{}{}\n", DIM, "─".repeat(RULE_WIDTH), RESET));
+            } else {
+                out.push_str(&format!("{}│{} ", DIM, RESET));
+                out.push_str(&code.highlighter.line(line));
+                out.push('\n');
+            }
+            return;
+        }
+
+        if trimmed.starts_with('|') {
+            self.table.push(trimmed.to_string());
+            return;
+        }
+        self.flush_table(out);
+
+        if let Some(fence) = ["```", "~~~"].iter().find(|f| trimmed.starts_with(**f)) {
+            let fence_char = fence.as_bytes()[0] as char;
+            let fence_len = trimmed.chars().take_while(|c| *c == fence_char).count();
+            let lang = trimmed[fence_len..].trim();
+            self.code = Some(CodeBlock {
+                fence: trimmed[..fence_len].to_string(),
+                highlighter: CodeHighlighter::new(lang),
+            });
+            let label = if lang.is_empty() { "code" } else { lang };
+            out.push_str(&format!("{}── {} ──{}\n", DIM, label, RESET));
+            return;
+        }
+
+        if let Some((level, text)) = heading(trimmed) {
+            let style = if level == 1 {
+                format!("{}{}{}", BOLD, UNDERLINE, CYAN)
+            } else {
+                format!("{}{}", BOLD, CYAN)
+            };
+            out.push_str(&format!("{}{}{}\n", style, render_inline(text), RESET));
+            return;
+        }
+
+        if is_rule(trimmed) {
+            out.push_str(&format!("{}{}{}\n", DIM, "─".repeat(RULE_WIDTH), RESET));
+            return;
+        }
+
+        if let Some(text) = trimmed.strip_prefix('>') {
+            out.push_str(&format!(
+                "{}│{} {}{}{}\n",
+                DIM,
+                RESET,
+                ITALIC,
+                render_inline(text.trim_start()),
+                RESET
+            ));
+            return;
+        }
+
+        let indent = &line[..line.len() - trimmed.len()];
+        if let Some((marker, text)) = list_item(trimmed) {
+            out.push_str(&format!(
+                "{}{}{}{} {}\n",
+                indent,
+                YELLOW,
+                marker,
+                RESET,
+                render_inline(text)
+            ));
+            return;
+        }
+
+        out.push_str(&render_inline(line));
+        out.push('\n');
+    }
+
+    fn flush_table(&mut self, out: &mut String) {
+        if self.table.is_empty() {
+            return;
+        }
+        let rows = std::mem::take(&mut self.table);
+        let mut cells: Vec<Vec<String>> = Vec::new();
+        let mut has_header = false;
+        for (idx, row) in rows.iter().enumerate() {
+            let parsed = split_row(row);
+            if idx == 1 && parsed.iter().all(|c| is_separator_cell(c)) {
+                has_header = true;
+                continue;
+            }
+            cells.push(parsed.iter().map(|c| render_inline(c)).collect());
+        }
+
+        let columns = cells.iter().map(|r| r.len()).max().unwrap_or(0);
+        let mut widths = vec![0; columns];
+        for row in &cells {
+            for (idx, cell) in row.iter().enumerate() {
+                widths[idx] = widths[idx].max(visible_width(cell));
+            }
+        }
+
+        let border = |left: &str, mid: &str, right: &str| {
+            let parts: Vec<String> = widths.iter().map(|w| "─".repeat(w + 2)).collect();
+            format!("{}{}{}{}{}\n", DIM, left, parts.join(mid), right, RESET)
+        };
+
+        out.push_str(&border("┌", "┬", "┐"));
+        for (row_idx, row) in cells.iter().enumerate() {
+            let header = has_header && row_idx == 0;
+            out.push_str(&format!("{}│{}", DIM, RESET));
+            for (idx, width) in widths.iter().enumerate() {
+                let cell = row.get(idx).map(String::as_str).unwrap_or("");
+                let pad = " ".repeat(width - visible_width(cell));
+                if header {
+                    out.push_str(&format!(" {}{}{}{} ", BOLD, cell, RESET, pad));
+                } else {
+                    out.push_str(&format!(" {}{} ", cell, pad));
+                }
+                out.push_str(&format!("{}│{}", DIM, RESET));
+            }
+            out.push('\n');
+            if header {
+                out.push_str(&border("├", "┼", "┤"));
+            }
+        }
+        out.push_str(&border("└", "┴", "┘"));
+    }
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if (1..=6).contains(&level) && line[level..].starts_with(' ') {
+        Some((level, line[level..].trim()))
+    } else {
+        None
+    }
+}
+
+fn is_rule(line: &str) -> bool {
+    let line = line.trim_end();
+    line.len() >= 3
+        && ['-', '*', '_']
+            .iter()
+            .any(|c| line.chars().all(|ch| ch == *c || ch == ' '))
+}
+
+fn list_item(line: &str) -> Option<(String, &str)> {
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(text) = line.strip_prefix(bullet) {
+            if let Some(text) = text.strip_prefix("[ ] ") {
+                return Some(("☐".to_string(), text));
+            }
+            if let Some(text) = text
+                .strip_prefix("[x] ")
+                .or_else(|| text.strip_prefix("[X] "))
+            {
+                return Some(("☑".to_string(), text));
+            }
+            return Some(("•".to_string(), text));
+        }
+    }
+
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 {
+        let rest = &line[digits..];
+        if rest.starts_with(". ") || rest.starts_with(") ") {
+            return Some((line[..digits + 1].to_string(), &rest[2..]));
+        }
+    }
+    None
+}
+
+fn split_row(row: &str) -> Vec<String> {
+    let row = row.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = row.strip_suffix('|').unwrap_or(row);
+    row.split('|').map(|c| c.trim().to_string()).collect()
+}
+
+fn is_separator_cell(cell: &str) -> bool {
+    !cell.is_empty() && cell.chars().all(|c| c == '-' || c == ':') && cell.contains('-')
+}
+
+/// Render inline markdown: emphasis, code spans, strikethrough and links.
+pub fn render_inline(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    // Intraword underscores, e.g. snake_case, are not emphasis.
+    let mut prev_alnum = false;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '\\'
+            && let Some(next) = rest[1..].chars().next()
+            && next.is_ascii_punctuation()
+        {
+            out.push(next);
+            rest = &rest[1 + next.len_utf8()..];
+            continue;
+        }
+
+        if c == '`' {
+            let ticks = rest.chars().take_while(|c| *c == '`').count();
+            if let Some(end) = rest[ticks..].find(&rest[..ticks]) {
+                let code = &rest[ticks..ticks + end];
+                out.push_str(&format!("{}{}{}", CYAN, code, RESET));
+                rest = &rest[ticks + end + ticks..];
+                continue;
+            }
+        }
+
+        if !(prev_alnum && c == '_')
+            && let Some((consumed, rendered)) = emphasis(rest)
+        {
+            out.push_str(&rendered);
+            rest = &rest[consumed..];
+            prev_alnum = false;
+            continue;
+        }
+
+        if c == '['
+            && let Some(close) = rest.find("](")
+            && let Some(end) = rest[close..].find(')')
+        {
+            let label = &rest[1..close];
+            let url = &rest[close + 2..close + end];
+            out.push_str(&format!(
+                "{}{}{} {}({}){}",
+                UNDERLINE,
+                render_inline(label),
+                RESET,
+                DIM,
+                url,
+                RESET
+            ));
+            rest = &rest[close + end + 1..];
+            continue;
+        }
+
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+        prev_alnum = c.is_alphanumeric();
+    }
+
+    out
+}
+
+/// Try to parse an emphasis span at the start of text.
+/// Returns consumed bytes and the rendered text.
+fn emphasis(text: &str) -> Option<(usize, String)> {
+    const MARKERS: [(&str, &str); 5] = [
+        ("**", BOLD),
+        ("__", BOLD),
+        ("~~", STRIKE),
+        ("*", ITALIC),
+        ("_", ITALIC),
+    ];
+    for (marker, style) in MARKERS {
+        let Some(inner) = text.strip_prefix(marker) else {
+            continue;
+        };
+        // Emphasis should not start with whitespace, e.g. `a * b`.
+        if inner.is_empty() || inner.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let Some(end) = inner.find(marker) else {
+            continue;
+        };
+        if end == 0 || inner[..end].ends_with(char::is_whitespace) {
+            continue;
+        }
+        let rendered = format!("{}{}{}", style, render_inline(&inner[..end]), RESET);
+        return Some((marker.len() * 2 + end, rendered));
+    }
+    None
+}
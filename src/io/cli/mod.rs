@@ -0,0 +1,220 @@
+mod editor;
+mod highlight;
+mod markdown;
+mod style;
+mod view;
+
+use anyhow::{Result, anyhow};
+use std::io::{Write, stdout};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::io::{Input, Signal, sigint_signal};
+use editor::{LineEditor, ReadResult};
+use markdown::MarkdownRenderer;
+
+use super::{IOChan, Output};
+
+const CHANNEL_BUFFER_SIZE: usize = 32;
+
+/// CliIO is an implementation of IO, which is for command line interface.
+pub struct CliIO {
+    async_tasks: Vec<JoinHandle<()>>,
+    sigint_cnt: Arc<AtomicU8>,
+
+    /// Print outputs as-is, without markdown rendering and colors.
+    plain: bool,
+}
+
+impl CliIO {
+    /// Create a new CliIO instance.
+    pub fn new(plain: bool) -> Self {
+        CliIO {
+            async_tasks: Vec::new(),
+            sigint_cnt: Arc::new(AtomicU8::new(0)),
+            plain,
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        !self.async_tasks.is_empty()
+    }
+
+    pub fn abort_all_tasks(&mut self) {
+        for handle in self.async_tasks.drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+/// AssistantPrinter prints streamed assistant messages,
+/// rendering markdown unless plain mode is requested.
+struct AssistantPrinter {
+    markdown: Option<MarkdownRenderer>,
+    /// Whether the last plain output did not end with a newline.
+    line_open: bool,
+}
+
+impl AssistantPrinter {
+    fn new(plain: bool) -> Self {
+        Self {
+            markdown: (!plain).then(MarkdownRenderer::new),
+            line_open: false,
+        }
+    }
+
+    fn push(&mut self, chunk: &str) {
+        match self.markdown {
+            Some(ref mut markdown) => print!("{}", markdown.push(chunk)),
+            None => {
+                print!("{}", chunk);
+                self.line_open = !chunk.ends_with('\n');
+            }
+        }
+        let _ = stdout().flush();
+    }
+
+    /// Flush the remaining message, so other outputs start on a new line.
+    fn finish(&mut self) {
+        if let Some(ref mut markdown) = self.markdown {
+            print!("{}", markdown.finish());
+        } else if self.line_open {
+            println!();
+        }
+        self.line_open = false;
+        let _ = stdout().flush();
+    }
+}
+
+impl super::IO for CliIO {
+    fn open(&mut self) -> Result<IOChan> {
+        if self.running() {
+            return Err(anyhow!("CliIO is already open"));
+        }
+
+        let (input_tx, input_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        let (signal_tx, signal_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        let (output_tx, output_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+
+        // Input prompt is shown only after the output side reports ready.
+        let (ready_tx, mut ready_rx) = mpsc::channel::<()>(CHANNEL_BUFFER_SIZE);
+
+        {
+            let input_tx = input_tx.clone();
+            let signal_tx = signal_tx.clone();
+            let sigint_cnt = self.sigint_cnt.clone();
+            self.async_tasks.push(tokio::task::spawn_blocking(move || {
+                let mut editor = match LineEditor::new() {
+                    Ok(editor) => editor,
+                    Err(err) => {
+                        println!("* Failed to initialize line editor: {}", err);
+                        let _ = signal_tx.blocking_send(Signal::Exit);
+                        return;
+                    }
+                };
+
+                while ready_rx.blocking_recv().is_some() {
+                    match editor.read("> ") {
+                        ReadResult::Line(line) => {
+                            sigint_cnt.store(0, Ordering::SeqCst);
+                            let i = Input::from_raw(line.as_str());
+                            if let Some(signal) = i.as_signal() {
+                                let _ = signal_tx.blocking_send(signal);
+                            } else {
+                                let _ = input_tx.blocking_send(i);
+                            }
+                        }
+                        ReadResult::Interrupted => {
+                            let _ = signal_tx.blocking_send(sigint_signal(&sigint_cnt));
+                        }
+                        ReadResult::Eof => {
+                            let _ = signal_tx.blocking_send(Signal::Exit);
+                            break;
+                        }
+                    }
+                }
+            }));
+        }
+
+        {
+            let ctrl_tx = signal_tx.clone();
+            let sigint_cnt = self.sigint_cnt.clone();
+            self.async_tasks.push(tokio::spawn(async move {
+                loop {
+                    if tokio::signal::ctrl_c().await.is_err() {
+                        break;
+                    }
+                    let sig = sigint_signal(&sigint_cnt);
+                    let exit = sig == Signal::Exit;
+                    if ctrl_tx.send(sig).await.is_err() || exit {
+                        break;
+                    }
+                }
+            }));
+        }
+
+        let plain = self.plain;
+        self.async_tasks.push(tokio::spawn(async move {
+            let mut output_rx = output_rx;
+            let mut assistant = AssistantPrinter::new(plain);
+            while let Some(output) = output_rx.recv().await {
+                if !matches!(output, Output::AssistantMsg(_) | Output::State(_)) {
+                    assistant.finish();
+                }
+                match output {
+                    Output::SystemMsg(message) => {
+                        for line in message.lines() {
+                            println!("* {}", line);
+                        }
+                    }
+                    Output::AssistantMsg(message) => {
+                        assistant.push(&message);
+                    }
+                    Output::LuaCode { id, code, review } => {
+                        print!("{}", view::render_lua_code(&id, &code, &review, plain));
+                        println!("* Approve execution? (Yes/No/Always)");
+                    }
+                    Output::ToolCall {
+                        id,
+                        name,
+                        args,
+                        diff,
+                        auto_approve,
+                    } => {
+                        print!("{}", view::render_tool_call(&id, &name, &args, diff, plain));
+                        if !auto_approve {
+                            println!("* Approve execution? (Yes/No/Always)");
+                        }
+                    }
+                    Output::LuaResult { id, output } => {
+                        println!("-->[RESULT:{}]---", id);
+                        for line in output.lines() {
+                            println!("    {}", line);
+                        }
+                        println!("-->[END RESULT:{}]---", id);
+                    }
+                    Output::State(_) => {
+                        // Status is shown by `/status` command.
+                    }
+                    Output::InputReady => {
+                        println!();
+                        let _ = ready_tx.send(()).await;
+                    }
+                }
+            }
+        }));
+
+        Ok(IOChan {
+            input_rx,
+            signal_rx,
+            output_tx,
+        })
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.abort_all_tasks();
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! Rendering of Lua scripts for approval.
+
+use super::highlight::CodeHighlighter;
+use super::style::{BOLD, CYAN, DIM, GREEN, RED, RESET, YELLOW};
+use crate::review::{ScriptReview, escape_controls};
+
+/// Render a Lua script with line numbers, risky lines and file change previews.
+/// If plain, no ANSI styles are used.
+pub fn render_lua_code(id: &str, code: &str, review: &ScriptReview, plain: bool) -> String {
+    let mut out = String::new();
+    let mut highlighter = CodeHighlighter::new("lua");
+    let width = code.lines().count().max(1).to_string().len();
+
+    out.push_str(&format!("---[LUA:{}]---\n", id));
+    for (idx, line) in code.lines().enumerate() {
+        let line = escape_controls(line);
+        let line_no = idx + 1;
+        let risky = review.risky_reason(line_no);
+        let marker = if risky.is_some() { "!" } else { " " };
+        if plain {
+            out.push_str(&format!("{} {:>width$} | {}", marker, line_no, line));
+            if let Some(reason) = risky {
+                out.push_str(&format!("    <-- {}", reason));
+            }
+        } else {
+            let (marker_style, number_style) = match risky {
+                Some(_) => (format!("{}{}", BOLD, RED), format!("{}{}", BOLD, RED)),
+                None => (String::new(), DIM.to_string()),
+            };
+            out.push_str(&format!(
+                "{}{}{} {}{:>width$} │{} {}",
+                marker_style,
+                marker,
+                RESET,
+                number_style,
+                line_no,
+                RESET,
+                highlighter.line(&line)
+            ));
+            if let Some(reason) = risky {
+                out.push_str(&format!("  {}{}◀ {}{}", BOLD, RED, reason, RESET));
+            }
+        }
+        out.push('\n');
+    }
+    out.push_str(&format!("---[END:{}]---\n", id));
+
+    if !review.risky.is_empty() {
+        let lines: Vec<String> = review.risky.iter().map(|r| r.line.to_string()).collect();
+        let warning = format!(
+            "* Warning: {} risky line(s): {}",
+            review.risky.len(),
+            lines.join(", ")
+        );
+        if plain {
+            out.push_str(&warning);
+        } else {
+            out.push_str(&format!("{}{}{}", YELLOW, warning, RESET));
+        }
+        out.push('\n');
+    }
+
+    for change in &review.changes {
+        out.push_str(&format!("* Preview of changes to {}:\n", change.path));
+        out.push_str(&render_diff(&escape_controls_lines(&change.diff), plain));
+    }
+    out
+}
+
+/// Escape control characters of each line, keeping newlines.
+fn escape_controls_lines(text: &str) -> String {
+    text.lines()
+        .flat_map(|line| [escape_controls(line), "\n".to_string()])
+        .collect()
+}
+
+/// Render a call of a tool, with its arguments or the diff of its write.
+pub fn render_tool_call(id: &str, name: &str, args: &str, diff: bool, plain: bool) -> String {
+    let mut out = String::new();
+    if plain {
+        out.push_str(&format!("---[TOOL:{}]--- {}\n", id, name));
+    } else {
+        out.push_str(&format!("---[TOOL:{}]--- {}{}{}\n", id, BOLD, name, RESET));
+    }
+    if diff {
+        out.push_str(&render_diff(&escape_controls_lines(args), plain));
+    } else {
+        for line in args.lines() {
+            out.push_str(&format!("  {}\n", escape_controls(line)));
+        }
+    }
+    out.push_str(&format!("---[END:{}]---\n", id));
+    out
+}
+
+/// Render a unified diff, coloring added and removed lines.
+pub fn render_diff(diff: &str, plain: bool) -> String {
+    let mut out = String::new();
+    for line in diff.lines() {
+        if plain {
+            out.push_str(line);
+        } else {
+            let style = if line.starts_with("+++") || line.starts_with("---") {
+                BOLD
+            } else if line.starts_with('+') {
+                GREEN
+            } else if line.starts_with('-') {
+                RED
+            } else if line.starts_with("@@") {
+                CYAN
+            } else {
+                ""
+            };
+            out.push_str(&format!("{}{}{}", style, line, RESET));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_calls_escape_control_characters() {
+        let args = "{\n  \"path\": \"a\x1b[2Jb\rc\"\n}";
+        let out = render_tool_call("t1", "write", args, false, true);
+        assert!(!out.contains('\x1b') && !out.contains('\r'), "{:?}", out);
+
+        let diff = "--- a/x\n+++ b/x\n@@ -1 +1 @@\n-old\n+new\x1b[1A\rX\n";
+        let out = render_tool_call("t1", "write_file", diff, true, true);
+        assert!(out.contains("+new\\x1b[1A\\rX\n"), "{:?}", out);
+        let out = render_tool_call("t1", "write_file", diff, true, false);
+        assert!(out.contains(&format!("{}+new", GREEN)), "{:?}", out);
+        assert!(!out.contains("\x1b[1A"), "{:?}", out);
+    }
+}
@@ -1,11 +1,25 @@
 /// mod io is the IO interaction module for User or other systems.
 pub mod cli;
 pub mod msg;
+pub mod tui;
 
-use anyhow::Result;
+use anyhow::{Context, Result, anyhow};
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::{env, fs, process};
 use tokio::sync::mpsc;
 
-pub use msg::{Command, Input, Output, Signal};
+pub use msg::{AgentState, Command, Input, Output, ScriptState, Signal};
+
+/// Count an interrupt, and returns Exit on the second one in a row.
+/// The counter should be reset when the user submits an input.
+pub fn sigint_signal(sigint_cnt: &AtomicU8) -> Signal {
+    if sigint_cnt.fetch_add(1, Ordering::SeqCst) >= 1 {
+        Signal::Exit
+    } else {
+        Signal::Cancel
+    }
+}
 
 pub struct IOChan {
     pub input_rx: mpsc::Receiver<Input>,
@@ -27,4 +41,56 @@ pub trait IO {
     /// Trigger when IO ends.
     /// This is an helper to cleanup IO resources.
     fn close(&mut self) -> Result<()>;
+
+    /// Release the terminal temporarily, e.g. to run an external editor.
+    fn suspend(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Take back the terminal released by `suspend`.
+    fn resume(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Let the user edit the text with `$VISUAL` or `$EDITOR`.
+    /// The file name is used for the temporary file, e.g. for syntax detection.
+    /// The file is made in a private directory, which is removed in any case.
+    fn edit_text(&mut self, text: &str, file_name: &str) -> Result<String> {
+        let dir = tempfile::Builder::new()
+            .prefix("onui-edit-")
+            .tempdir()
+            .context("creating a directory to edit in")?;
+        let path = dir.path().join(format!("onui-{}", file_name));
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .with_context(|| format!("writing {}", path.display()))?;
+
+        let editor = env::var("VISUAL")
+            .or_else(|_| env::var("EDITOR"))
+            .unwrap_or_else(|_| DEFAULT_EDITOR.to_string());
+        let mut parts = editor.split_whitespace();
+        let program = parts.next().unwrap_or(DEFAULT_EDITOR);
+
+        self.suspend()?;
+        let status = process::Command::new(program)
+            .args(parts)
+            .arg(&path)
+            .status();
+        self.resume()?;
+
+        let status = status.with_context(|| format!("running editor '{}'", editor))?;
+        if !status.success() {
+            return Err(anyhow!("editor '{}' exited with {}", editor, status));
+        }
+        fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))
+    }
 }
+
+const DEFAULT_EDITOR: &str = if cfg!(target_os = "windows") {
+    "notepad"
+} else {
+    "vi"
+};
@@ -1,3 +1,6 @@
+use crate::llm::Status;
+use crate::review::ScriptReview;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Signal {
     Exit,
@@ -12,10 +15,20 @@ pub enum Command {
     Status,
     ResetVM,
     Compact,
+    Sessions,
+    Export,
+    Retry,
+    Undo,
+    Edit,
+    Checkpoint,
+    Branch,
+    Switch,
+    VM,
 
     Approve,
     Reject,
     ApproveAlways,
+    EditLua,
 }
 
 static CMD_NAMES: phf::Map<&'static str, Command> = phf::phf_map! {
@@ -29,17 +42,35 @@ static CMD_NAMES: phf::Map<&'static str, Command> = phf::phf_map! {
     "status" => Command::Status,
     "resetvm" => Command::ResetVM,
     "compact" => Command::Compact,
+    "sessions" => Command::Sessions,
+    "session" => Command::Sessions,
+    "export" => Command::Export,
+    "retry" => Command::Retry,
+    "undo" => Command::Undo,
+    "edit" => Command::Edit,
+    "checkpoint" => Command::Checkpoint,
+    "cp" => Command::Checkpoint,
+    "branch" => Command::Branch,
+    "switch" => Command::Switch,
+    "vm" => Command::VM,
     "approve" => Command::Approve,
     "a" => Command::Approve,
     "reject" => Command::Reject,
     "r" => Command::Reject,
     "always" => Command::ApproveAlways,
+    "editlua" => Command::EditLua,
+    "le" => Command::EditLua,
 };
 
 impl Command {
     pub fn from_name(name: &str) -> Option<Command> {
         CMD_NAMES.get(name.to_lowercase().as_str()).cloned()
     }
+
+    /// Returns all command names, including aliases.
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        CMD_NAMES.keys().copied()
+    }
 }
 
 /// Input is common input of user.
@@ -50,7 +81,6 @@ pub enum Input {
     Command {
         cmd: Command,
         arg: String,
-        #[allow(dead_code)]
         details: String,
     },
 }
@@ -65,14 +95,17 @@ impl Input {
                 let mut parts = trimmed.splitn(2, char::is_whitespace);
                 let name_raw = parts.next().unwrap_or("");
                 if let Some(cmd) = Command::from_name(name_raw) {
-                    let mut rest = parts.next().unwrap_or("").splitn(2, '\n');
-                    let arg = rest.next().unwrap_or("").trim().to_string();
-                    let details = rest.next().unwrap_or("").trim().to_string();
-                    return Self::Command {
-                        cmd: cmd,
-                        arg: arg,
-                        details: details,
+                    // If a newline follows the name, there is no arg but details.
+                    let rest = parts.next().unwrap_or("");
+                    let no_arg = trimmed[name_raw.len()..].starts_with('\n');
+                    let (arg, details) = match rest.split_once('\n') {
+                        _ if no_arg => ("", rest),
+                        Some((arg, details)) => (arg, details),
+                        None => (rest, ""),
                     };
+                    let arg = arg.trim().to_string();
+                    let details = details.trim().to_string();
+                    return Self::Command { cmd, arg, details };
                 }
             }
         }
@@ -94,10 +127,103 @@ impl Input {
 
 /// Output is from the system to the user.
 pub enum Output {
-    SystemMsg(String),                        // system message, complete lines.
-    AssistantMsg(String),                     // assistant message, may be streaming.
-    LuaCode { id: String, code: String },     // lua code to be approved by user.
-    LuaResult { id: String, output: String }, // lua execution result, complete lines.
+    SystemMsg(String),    // system message, complete lines.
+    AssistantMsg(String), // assistant message, may be streaming.
+    // lua code to be approved by user.
+    LuaCode {
+        id: String,
+        code: String,
+        review: ScriptReview,
+    },
+    // call of a tool, with pretty JSON arguments, or a unified diff for writes.
+    ToolCall {
+        id: String,
+        name: String,
+        args: String,
+        diff: bool,
+        auto_approve: bool,
+    },
+    LuaResult {
+        id: String,
+        output: String,
+    }, // lua execution result, complete lines.
+    State(AgentState), // snapshot of agent state, for status displays.
 
     InputReady,
 }
+
+/// AgentState is a snapshot of the agent, shown by status bars.
+#[derive(Debug, Clone)]
+pub struct AgentState {
+    pub llm: String,
+    pub model: String,
+    pub status: Status,
+    pub token_used: usize,
+    pub token_limit: usize,
+    pub scripts: Vec<ScriptState>,
+}
+
+/// ScriptState is a summary of a pending or determined Lua script.
+#[derive(Debug, Clone)]
+pub struct ScriptState {
+    pub id: String,
+    /// First non-empty line of the script.
+    pub summary: String,
+    /// None if pending, otherwise whether it was approved.
+    pub approved: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(raw: &str) -> Option<(Command, String, String)> {
+        match Input::from_raw(raw) {
+            Input::Command { cmd, arg, details } => Some((cmd, arg, details)),
+            Input::Text(_) => None,
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        let cmd = |cmd, arg: &str, details: &str| Some((cmd, arg.to_string(), details.to_string()));
+        assert_eq!(command("/help"), cmd(Command::Help, "", ""));
+        assert_eq!(command("  /Q  \n"), cmd(Command::Exit, "", ""));
+        assert_eq!(
+            command("/ approve  a1 a2 "),
+            cmd(Command::Approve, "a1 a2", "")
+        );
+        assert_eq!(
+            command("/reject a1 wrong file\n  use b.txt  \n"),
+            cmd(Command::Reject, "a1 wrong file", "use b.txt")
+        );
+        assert_eq!(
+            command("/editlua\nprint(1)\nprint(2)"),
+            cmd(Command::EditLua, "", "print(1)\nprint(2)")
+        );
+    }
+
+    #[test]
+    fn keeps_other_input_as_text() {
+        for raw in [
+            "hello",
+            "/",
+            "/unknown arg",
+            "path /help",
+            " /tmp/x is a file",
+        ] {
+            match Input::from_raw(raw) {
+                Input::Text(text) => assert_eq!(text, raw),
+                Input::Command { cmd, .. } => panic!("{}: {:?}", raw, cmd),
+            }
+        }
+    }
+
+    #[test]
+    fn signals_exit_and_cancel() {
+        assert_eq!(Input::from_raw("/exit").as_signal(), Some(Signal::Exit));
+        assert_eq!(Input::from_raw("/stop").as_signal(), Some(Signal::Cancel));
+        assert_eq!(Input::from_raw("/help").as_signal(), None);
+        assert_eq!(Input::from_raw("exit").as_signal(), None);
+    }
+}
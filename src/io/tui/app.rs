@@ -0,0 +1,457 @@
+//! State and rendering of the full-screen terminal UI.
+
+use ratatui::Frame;
+use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, List, ListItem, Paragraph, Wrap};
+
+use crate::io::{AgentState, Command, Output, ScriptState};
+use crate::review::{ScriptReview, escape_controls};
+
+const SCROLL_STEP: usize = 10;
+const MAX_INPUT_LINES: usize = 8;
+
+/// Action requested by a key press.
+pub enum Action {
+    Submit(String),
+    Approve,
+    Reject,
+    Cancel,
+    Interrupt,
+    Exit,
+}
+
+enum Entry {
+    System(String),
+    User(String),
+    Assistant(String),
+    Lua {
+        id: String,
+        code: String,
+        review: ScriptReview,
+    },
+    Tool {
+        id: String,
+        name: String,
+        args: String,
+        diff: bool,
+    },
+    LuaResult {
+        id: String,
+        output: String,
+    },
+}
+
+pub struct App {
+    entries: Vec<Entry>,
+    state: Option<AgentState>,
+    /// Scripts seen in this session, including ones already sent to the LLM.
+    scripts: Vec<ScriptState>,
+
+    input: String,
+    /// Cursor position in chars.
+    cursor: usize,
+    history: Vec<String>,
+    history_pos: Option<usize>,
+
+    /// Number of lines scrolled up from the bottom. 0 follows new outputs.
+    scroll_back: usize,
+    input_ready: bool,
+}
+
+impl App {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            state: None,
+            scripts: Vec::new(),
+            input: String::new(),
+            cursor: 0,
+            history: Vec::new(),
+            history_pos: None,
+            scroll_back: 0,
+            input_ready: false,
+        }
+    }
+
+    pub fn on_output(&mut self, output: Output) {
+        match output {
+            Output::SystemMsg(message) => self.entries.push(Entry::System(message)),
+            Output::AssistantMsg(chunk) => match self.entries.last_mut() {
+                Some(Entry::Assistant(message)) => message.push_str(&chunk),
+                _ => self.entries.push(Entry::Assistant(chunk)),
+            },
+            Output::LuaCode { id, code, review } => {
+                self.entries.push(Entry::Lua { id, code, review })
+            }
+            Output::ToolCall {
+                id,
+                name,
+                args,
+                diff,
+                ..
+            } => self.entries.push(Entry::Tool {
+                id,
+                name,
+                args,
+                diff,
+            }),
+            Output::LuaResult { id, output } => self.entries.push(Entry::LuaResult { id, output }),
+            Output::State(state) => {
+                for script in &state.scripts {
+                    match self.scripts.iter_mut().find(|s| s.id == script.id) {
+                        Some(known) => *known = script.clone(),
+                        None => self.scripts.push(script.clone()),
+                    }
+                }
+                self.state = Some(state);
+            }
+            Output::InputReady => self.input_ready = true,
+        }
+    }
+
+    pub fn on_key(&mut self, key: KeyEvent) -> Option<Action> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        match key.code {
+            KeyCode::Char('c') if ctrl => return Some(Action::Interrupt),
+            KeyCode::Char('d') if ctrl && self.input.is_empty() => return Some(Action::Exit),
+            KeyCode::Char('y') if ctrl => return Some(Action::Approve),
+            KeyCode::Char('n') if ctrl => return Some(Action::Reject),
+            KeyCode::Esc => return Some(Action::Cancel),
+            KeyCode::Char('u') if ctrl => {
+                self.input.clear();
+                self.cursor = 0;
+            }
+            KeyCode::Char('j') if ctrl => self.insert('\n'),
+            KeyCode::Enter if alt => self.insert('\n'),
+            KeyCode::Enter => return self.submit(),
+            KeyCode::Char(c) => self.insert(c),
+            KeyCode::Tab => self.complete_command(),
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                let idx = self.byte_index();
+                self.input.remove(idx);
+            }
+            KeyCode::Delete if self.cursor < self.input.chars().count() => {
+                let idx = self.byte_index();
+                self.input.remove(idx);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.chars().count()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.chars().count(),
+            KeyCode::Up => self.history_prev(),
+            KeyCode::Down => self.history_next(),
+            KeyCode::PageUp => self.scroll_back += SCROLL_STEP,
+            KeyCode::PageDown => self.scroll_back = self.scroll_back.saturating_sub(SCROLL_STEP),
+            _ => {}
+        }
+        None
+    }
+
+    fn byte_index(&self) -> usize {
+        self.input
+            .char_indices()
+            .nth(self.cursor)
+            .map(|(idx, _)| idx)
+            .unwrap_or(self.input.len())
+    }
+
+    fn insert(&mut self, c: char) {
+        let idx = self.byte_index();
+        self.input.insert(idx, c);
+        self.cursor += 1;
+    }
+
+    fn set_input(&mut self, text: String) {
+        self.cursor = text.chars().count();
+        self.input = text;
+    }
+
+    fn submit(&mut self) -> Option<Action> {
+        if self.input.trim().is_empty() {
+            return None;
+        }
+        let text = std::mem::take(&mut self.input);
+        self.cursor = 0;
+        self.history_pos = None;
+        if self.history.last() != Some(&text) {
+            self.history.push(text.clone());
+        }
+        self.entries.push(Entry::User(text.clone()));
+        self.scroll_back = 0;
+        self.input_ready = false;
+        Some(Action::Submit(text))
+    }
+
+    fn history_prev(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+        let pos = match self.history_pos {
+            Some(pos) => pos.saturating_sub(1),
+            None => self.history.len() - 1,
+        };
+        self.history_pos = Some(pos);
+        self.set_input(self.history[pos].clone());
+    }
+
+    fn history_next(&mut self) {
+        let Some(pos) = self.history_pos else {
+            return;
+        };
+        if pos + 1 < self.history.len() {
+            self.history_pos = Some(pos + 1);
+            self.set_input(self.history[pos + 1].clone());
+        } else {
+            self.history_pos = None;
+            self.set_input(String::new());
+        }
+    }
+
+    /// Complete a `/` command name to the longest common prefix.
+    fn complete_command(&mut self) {
+        let Some(prefix) = self.input.strip_prefix('/') else {
+            return;
+        };
+        if prefix.contains(char::is_whitespace) {
+            return;
+        }
+        let matches: Vec<&str> = Command::names()
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        let Some(first) = matches.first() else {
+            return;
+        };
+        let mut common = first.to_string();
+        for name in &matches[1..] {
+            while !name.starts_with(common.as_str()) {
+                common.pop();
+            }
+        }
+        if matches.len() == 1 {
+            common.push(' ');
+        }
+        self.set_input(format!("/{}", common));
+    }
+
+    fn pending_scripts(&self) -> usize {
+        self.state
+            .as_ref()
+            .map(|state| {
+                state
+                    .scripts
+                    .iter()
+                    .filter(|s| s.approved.is_none())
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    pub fn draw(&mut self, frame: &mut Frame) {
+        let input_lines = self.input.split('\n').count().min(MAX_INPUT_LINES) as u16;
+        let [main_area, input_area, status_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(input_lines + 2),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [conversation_area, scripts_area] =
+            Layout::horizontal([Constraint::Percentage(70), Constraint::Percentage(30)])
+                .areas(main_area);
+
+        // Conversation
+        let conversation = Paragraph::new(self.conversation_text())
+            .block(Block::bordered().title(" Conversation "))
+            .wrap(Wrap { trim: false });
+        let inner_width = conversation_area.width.saturating_sub(2);
+        let inner_height = conversation_area.height.saturating_sub(2) as usize;
+        let total = conversation.line_count(inner_width).saturating_sub(2);
+        let max_scroll = total.saturating_sub(inner_height);
+        self.scroll_back = self.scroll_back.min(max_scroll);
+        let offset = (max_scroll - self.scroll_back).min(u16::MAX as usize) as u16;
+        frame.render_widget(conversation.scroll((offset, 0)), conversation_area);
+
+        // Scripts
+        let items: Vec<ListItem> = self
+            .scripts
+            .iter()
+            .rev()
+            .map(|script| {
+                let (mark, color) = match script.approved {
+                    None => ("●", Color::Yellow),
+                    Some(true) => ("✓", Color::Green),
+                    Some(false) => ("✗", Color::Red),
+                };
+                ListItem::new(Text::from(vec![
+                    Line::from(vec![
+                        Span::styled(format!("{} ", mark), Style::new().fg(color).bold()),
+                        Span::raw(script.id.clone()),
+                    ]),
+                    Line::from(format!("  {}", script.summary)).dark_gray(),
+                ]))
+            })
+            .collect();
+        frame.render_widget(
+            List::new(items).block(Block::bordered().title(" Lua Scripts ")),
+            scripts_area,
+        );
+
+        // Input
+        let pending = self.pending_scripts();
+        let title = if pending > 0 {
+            format!(" {} pending script(s): ^Y approve, ^N reject ", pending)
+        } else if self.input_ready {
+            " Input ".to_string()
+        } else {
+            " Input (busy) ".to_string()
+        };
+        let input_text: Vec<Line> = self
+            .input
+            .split('\n')
+            .enumerate()
+            .map(|(idx, line)| {
+                let prompt = if idx == 0 { "> " } else { ". " };
+                Line::from(vec![
+                    Span::raw(prompt).dark_gray(),
+                    Span::raw(line.to_string()),
+                ])
+            })
+            .collect();
+        let before_cursor: String = self.input.chars().take(self.cursor).collect();
+        let cursor_row = before_cursor.matches('\n').count();
+        let cursor_col = before_cursor
+            .rsplit('\n')
+            .next()
+            .map(|line| line.chars().count())
+            .unwrap_or(0);
+        let scroll = cursor_row.saturating_sub(MAX_INPUT_LINES - 1);
+        frame.render_widget(
+            Paragraph::new(input_text)
+                .block(Block::bordered().title(title))
+                .scroll((scroll as u16, 0)),
+            input_area,
+        );
+        frame.set_cursor_position((
+            input_area.x + 3 + cursor_col as u16,
+            input_area.y + 1 + (cursor_row - scroll) as u16,
+        ));
+
+        // Status bar
+        let status = match &self.state {
+            Some(state) => format!(
+                " {}:{} │ {} │ tokens {}/{} │ Esc cancel, ^C exit, PgUp/PgDn scroll",
+                state.llm,
+                state.model,
+                state.status.as_str(),
+                state.token_used,
+                state.token_limit
+            ),
+            None => " Starting...".to_string(),
+        };
+        frame.render_widget(
+            Paragraph::new(status).style(Style::new().reversed()),
+            status_area,
+        );
+    }
+
+    fn conversation_text(&self) -> Text<'static> {
+        let mut lines: Vec<Line<'static>> = Vec::new();
+        for entry in &self.entries {
+            if !lines.is_empty() {
+                lines.push(Line::default());
+            }
+            match entry {
+                Entry::System(message) => {
+                    for line in message.lines() {
+                        lines.push(Line::from(format!("* {}", line)).cyan());
+                    }
+                }
+                Entry::User(message) => {
+                    for (idx, line) in message.lines().enumerate() {
+                        let prompt = if idx == 0 { "> " } else { ". " };
+                        lines.push(Line::from(format!("{}{}", prompt, line)).bold());
+                    }
+                }
+                Entry::Assistant(message) => {
+                    for line in message.lines() {
+                        lines.push(Line::from(line.to_string()));
+                    }
+                }
+                Entry::Lua { id, code, review } => {
+                    lua_lines(&mut lines, id, code, review);
+                }
+                Entry::Tool {
+                    id,
+                    name,
+                    args,
+                    diff,
+                } => {
+                    lines.push(Line::from(format!("── TOOL:{} {} ──", id, name)).yellow());
+                    if *diff {
+                        diff_lines(&mut lines, args);
+                    } else {
+                        for line in args.lines() {
+                            lines.push(Line::from(format!("  {}", escape_controls(line))));
+                        }
+                    }
+                }
+                Entry::LuaResult { id, output } => {
+                    lines.push(Line::from(format!("── RESULT:{} ──", id)).green());
+                    for line in output.lines() {
+                        lines.push(Line::from(format!("  {}", line)));
+                    }
+                }
+            }
+        }
+        Text::from(lines)
+    }
+}
+
+fn lua_lines(lines: &mut Vec<Line<'static>>, id: &str, code: &str, review: &ScriptReview) {
+    let width = code.lines().count().max(1).to_string().len();
+    lines.push(Line::from(format!("── LUA:{} ──", id)).yellow());
+    for (idx, line) in code.lines().enumerate() {
+        let line = escape_controls(line);
+        let number = format!("{:>width$} │ ", idx + 1);
+        match review.risky_reason(idx + 1) {
+            Some(reason) => {
+                let risky = Style::new().fg(Color::Red).add_modifier(Modifier::BOLD);
+                lines.push(Line::from(vec![
+                    Span::styled(number, risky),
+                    Span::styled(line, risky),
+                    Span::styled(format!("  ◀ {}", reason), risky),
+                ]));
+            }
+            None => lines.push(Line::from(vec![
+                Span::raw(number).dark_gray(),
+                Span::raw(line),
+            ])),
+        }
+    }
+    for change in &review.changes {
+        lines.push(Line::from(format!("Preview of changes to {}:", change.path)).yellow());
+        diff_lines(lines, &change.diff);
+    }
+}
+
+/// Push lines of a unified diff, coloring added and removed lines.
+fn diff_lines(lines: &mut Vec<Line<'static>>, diff: &str) {
+    for line in diff.lines() {
+        let style = if line.starts_with("+++") || line.starts_with("---") {
+            Style::new().bold()
+        } else if line.starts_with('+') {
+            Style::new().green()
+        } else if line.starts_with('-') {
+            Style::new().red()
+        } else if line.starts_with("@@") {
+            Style::new().cyan()
+        } else {
+            Style::new()
+        };
+        lines.push(Line::styled(escape_controls(line), style));
+    }
+}
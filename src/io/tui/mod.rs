@@ -0,0 +1,191 @@
+mod app;
+
+use anyhow::{Result, anyhow};
+use ratatui::crossterm::event::{self, Event, KeyEventKind};
+use ratatui::crossterm::{execute, terminal};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::io::{Command, Input, Signal, sigint_signal};
+use app::{Action, App};
+
+use super::IOChan;
+
+const CHANNEL_BUFFER_SIZE: usize = 32;
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// TuiIO is an implementation of IO, which is a full-screen terminal UI.
+///
+/// It shows the conversation, Lua scripts and agent status in split panes.
+pub struct TuiIO {
+    async_tasks: Vec<JoinHandle<()>>,
+    sigint_cnt: Arc<AtomicU8>,
+    stop_events: Arc<AtomicBool>,
+
+    /// While paused, terminal is released and neither read nor drawn.
+    paused: Arc<AtomicBool>,
+    redraw_tx: Option<mpsc::Sender<()>>,
+}
+
+impl TuiIO {
+    /// Create a new TuiIO instance.
+    pub fn new() -> Self {
+        TuiIO {
+            async_tasks: Vec::new(),
+            sigint_cnt: Arc::new(AtomicU8::new(0)),
+            stop_events: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            redraw_tx: None,
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        !self.async_tasks.is_empty()
+    }
+
+    pub fn abort_all_tasks(&mut self) {
+        for handle in self.async_tasks.drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+impl super::IO for TuiIO {
+    fn open(&mut self) -> Result<IOChan> {
+        if self.running() {
+            return Err(anyhow!("TuiIO is already open"));
+        }
+
+        let (input_tx, input_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        let (signal_tx, signal_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        let (output_tx, output_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        let (event_tx, mut event_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        let (redraw_tx, mut redraw_rx) = mpsc::channel(1);
+        self.redraw_tx = Some(redraw_tx);
+
+        let mut terminal =
+            ratatui::try_init().map_err(|err| anyhow!("failed to init terminal: {}", err))?;
+
+        // Terminal events are read by a blocking thread.
+        self.stop_events.store(false, Ordering::SeqCst);
+        {
+            let stop_events = self.stop_events.clone();
+            let paused = self.paused.clone();
+            self.async_tasks.push(tokio::task::spawn_blocking(move || {
+                while !stop_events.load(Ordering::SeqCst) {
+                    if paused.load(Ordering::SeqCst) {
+                        std::thread::sleep(EVENT_POLL_INTERVAL);
+                        continue;
+                    }
+                    match event::poll(EVENT_POLL_INTERVAL) {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(_) => break,
+                    }
+                    if paused.load(Ordering::SeqCst) {
+                        // Leave the event to the program using the terminal.
+                        continue;
+                    }
+                    let Ok(ev) = event::read() else {
+                        break;
+                    };
+                    if event_tx.blocking_send(ev).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+
+        let sigint_cnt = self.sigint_cnt.clone();
+        let paused = self.paused.clone();
+        self.async_tasks.push(tokio::spawn(async move {
+            let mut output_rx = output_rx;
+            let mut app = App::new();
+            loop {
+                if !paused.load(Ordering::SeqCst) {
+                    let _ = terminal.draw(|frame| app.draw(frame));
+                }
+                tokio::select! {
+                    Some(output) = output_rx.recv() => app.on_output(output),
+                    Some(()) = redraw_rx.recv() => {
+                        let _ = terminal.clear();
+                    }
+                    Some(ev) = event_rx.recv() => {
+                        let Event::Key(key) = ev else {
+                            // Resize and others just redraw.
+                            continue;
+                        };
+                        if key.kind != KeyEventKind::Press {
+                            continue;
+                        }
+                        let Some(action) = app.on_key(key) else {
+                            continue;
+                        };
+                        let sent = match action {
+                            Action::Submit(text) => {
+                                sigint_cnt.store(0, Ordering::SeqCst);
+                                let i = Input::from_raw(&text);
+                                match i.as_signal() {
+                                    Some(signal) => signal_tx.send(signal).await.is_ok(),
+                                    None => input_tx.send(i).await.is_ok(),
+                                }
+                            }
+                            Action::Approve => input_tx.send(command(Command::Approve)).await.is_ok(),
+                            Action::Reject => input_tx.send(command(Command::Reject)).await.is_ok(),
+                            Action::Cancel => signal_tx.send(Signal::Cancel).await.is_ok(),
+                            Action::Interrupt => {
+                                signal_tx.send(sigint_signal(&sigint_cnt)).await.is_ok()
+                            }
+                            Action::Exit => signal_tx.send(Signal::Exit).await.is_ok(),
+                        };
+                        if !sent {
+                            break;
+                        }
+                    }
+                    else => break,
+                }
+            }
+        }));
+
+        Ok(IOChan {
+            input_rx,
+            signal_rx,
+            output_tx,
+        })
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.stop_events.store(true, Ordering::SeqCst);
+        self.abort_all_tasks();
+        self.redraw_tx = None;
+        ratatui::try_restore().map_err(|err| anyhow!("failed to restore terminal: {}", err))
+    }
+
+    fn suspend(&mut self) -> Result<()> {
+        self.paused.store(true, Ordering::SeqCst);
+        // Wait for the event thread to leave polling.
+        std::thread::sleep(EVENT_POLL_INTERVAL);
+        ratatui::try_restore().map_err(|err| anyhow!("failed to restore terminal: {}", err))
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        terminal::enable_raw_mode()?;
+        execute!(std::io::stdout(), terminal::EnterAlternateScreen)?;
+        self.paused.store(false, Ordering::SeqCst);
+        if let Some(ref redraw_tx) = self.redraw_tx {
+            let _ = redraw_tx.try_send(());
+        }
+        Ok(())
+    }
+}
+
+fn command(cmd: Command) -> Input {
+    Input::Command {
+        cmd,
+        arg: String::new(),
+        details: String::new(),
+    }
+}
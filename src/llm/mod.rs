@@ -2,7 +2,7 @@ pub mod openai;
 pub mod traits;
 
 pub use openai::OpenAIClient;
-pub use traits::{DynLLMClient, LLMClient, LLMEventHandler};
+pub use traits::{DynLLMClient, LLMClient, LLMEventHandler, Status, ToolSpec, TranscriptItem};
 
 use crate::config::LLMConfig;
 
@@ -12,7 +12,7 @@ pub fn instantiate(
 ) -> anyhow::Result<DynLLMClient> {
     match config {
         LLMConfig::OpenAI(openai_cfg) => {
-            let llm = OpenAIClient::new(&openai_cfg, handler)?;
+            let llm = OpenAIClient::new(openai_cfg, handler)?;
             Ok(Box::new(llm) as DynLLMClient)
         } // Future LLM providers can be added here.
     }
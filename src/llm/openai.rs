@@ -1,4 +1,4 @@
-use super::traits::{LLMClient, LLMEventHandler};
+use super::traits::{LLMClient, LLMEventHandler, ToolSpec, TranscriptItem};
 use crate::{config::LLMOpenAIConfig, consts::DEFAULT_SYSTEM_PROMPT, llm::traits::Status};
 use anyhow::{Context, Result, anyhow};
 use async_trait::async_trait;
@@ -26,6 +26,17 @@ struct OpenAITool {
 }
 
 impl OpenAITool {
+    fn from_spec(spec: &ToolSpec) -> Self {
+        Self {
+            kind: "function".to_string(),
+            function: OpenAIToolFunction {
+                name: spec.name.clone(),
+                description: spec.description.clone(),
+                parameters: spec.parameters.clone(),
+            },
+        }
+    }
+
     fn lua_tool() -> Self {
         Self {
             kind: "function".to_string(),
@@ -42,6 +53,10 @@ impl OpenAITool {
                         "timeout_sec": {
                             "type": "integer",
                             "description": "Timeout in seconds."
+                        },
+                        "vm": {
+                            "type": "string",
+                            "description": "Name of the Lua VM to run in. Each VM keeps its own globals. Default is `main`."
                         }
                     },
                     "required": ["code"],
@@ -63,6 +78,10 @@ struct OpenAIMessage {
     tool_calls: Vec<OpenAIToolCall>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     tool_call_id: Option<String>,
+    /// Tokens used by the conversation up to this response, if reported.
+    /// It is not sent to the API, but kept in the exported history.
+    #[serde(skip_serializing, default)]
+    used_tokens: Option<usize>,
 }
 
 impl OpenAIMessage {
@@ -72,6 +91,7 @@ impl OpenAIMessage {
             content: Some(content.to_string()),
             tool_calls: Vec::new(),
             tool_call_id: None,
+            used_tokens: None,
         }
     }
 
@@ -98,11 +118,11 @@ struct OpenAIToolCall {
     function: OpenAIFunction,
 }
 
-fn parse_timeout(value: &Value) -> Option<u64> {
-    match value {
-        Value::Number(number) => number.as_u64(),
-        Value::String(text) => text.parse::<u64>().ok(),
-        _ => None,
+impl OpenAIToolCall {
+    /// Decode arguments, or an empty object if they are not valid JSON.
+    fn args(&self) -> Value {
+        serde_json::from_str(&self.function.arguments)
+            .unwrap_or_else(|_| Value::Object(Default::default()))
     }
 }
 
@@ -220,6 +240,8 @@ pub struct OpenAIClient {
     model: String,
     reasoning_effort: Option<String>,
     stream: bool,
+    structured_results: bool,
+    tools: Vec<ToolSpec>,
 
     handler: Box<dyn LLMEventHandler>,
 
@@ -257,6 +279,8 @@ impl OpenAIClient {
             model,
             reasoning_effort: config.reasoning_effort.clone(),
             stream,
+            structured_results: config.structured_results.unwrap_or(false),
+            tools: Vec::new(),
             handler,
             history,
             used_token: 0,
@@ -270,7 +294,9 @@ impl OpenAIClient {
         let payload = OpenAIChatRequest {
             model: self.model.to_string(),
             messages: history,
-            tools: vec![OpenAITool::lua_tool()],
+            tools: std::iter::once(OpenAITool::lua_tool())
+                .chain(self.tools.iter().map(OpenAITool::from_spec))
+                .collect(),
             reasoning_effort: self.reasoning_effort.clone(),
             stream: Some(self.stream),
         };
@@ -317,14 +343,9 @@ impl OpenAIClient {
         }
 
         for call in &choice.message.tool_calls {
-            let args: Value = serde_json::from_str(&call.function.arguments)
-                .unwrap_or_else(|_| Value::Object(Default::default()));
-            if let Some(code) = args.get("code").and_then(|value| value.as_str()) {
-                let timeout_sec = args.get("timeout_sec").and_then(parse_timeout);
-                self.handler
-                    .on_lua_call(&call.id, code, timeout_sec)
-                    .await?;
-            }
+            self.handler
+                .on_tool_call(&call.id, &call.function.name, &call.args())
+                .await?;
         }
 
         Ok((choice.message, body.usage.total_tokens as usize))
@@ -385,7 +406,7 @@ impl OpenAIClient {
                     .map_err(|e| anyhow!("failed to parse chunk: {}: {}", data, e))?;
                 let choice = chunk_response.choices.first().map_or_else(
                     || Err(anyhow!("OpenAI stream response missing choices")),
-                    |c| Ok(c),
+                    Ok,
                 )?;
 
                 let delta = &choice.delta;
@@ -419,14 +440,9 @@ impl OpenAIClient {
 
         // Process accumulated tool calls
         for call in &accumulated_tool_calls {
-            let args: Value = serde_json::from_str(&call.function.arguments)
-                .unwrap_or_else(|_| Value::Object(Default::default()));
-            if let Some(code) = args.get("code").and_then(|value| value.as_str()) {
-                let timeout_sec = args.get("timeout_sec").and_then(parse_timeout);
-                self.handler
-                    .on_lua_call(&call.id, code, timeout_sec)
-                    .await?;
-            }
+            self.handler
+                .on_tool_call(&call.id, &call.function.name, &call.args())
+                .await?;
         }
 
         let message = OpenAIMessage {
@@ -438,6 +454,7 @@ impl OpenAIClient {
             },
             tool_calls: accumulated_tool_calls,
             tool_call_id: None,
+            used_tokens: None,
         };
 
         // Since streaming doesn't return token usage, we estimate or return 0
@@ -454,13 +471,14 @@ impl OpenAIClient {
         }
 
         let req = self.chat_completion_request(&new_history)?;
-        let (response_msg, used_tokens) = if self.stream {
+        let (mut response_msg, used_tokens) = if self.stream {
             self.chat_completion_streaming(req).await?
         } else {
             self.chat_completion(req).await?
         };
 
         self.used_token = used_tokens;
+        response_msg.used_tokens = (used_tokens > 0).then_some(used_tokens);
         // Update history with new messages and response.
         for msg in new_messages {
             self.history.push(msg.clone());
@@ -490,6 +508,10 @@ impl LLMClient for OpenAIClient {
         self.model.clone()
     }
 
+    fn set_tools(&mut self, tools: Vec<ToolSpec>) {
+        self.tools = tools;
+    }
+
     fn context_size(&self) -> (usize, usize) {
         (self.used_token, self.token_limit)
     }
@@ -502,16 +524,25 @@ impl LLMClient for OpenAIClient {
         Ok(())
     }
 
+    fn structured_results(&self) -> bool {
+        self.structured_results
+    }
+
     async fn send_lua_results(&mut self, results: &[(String, String)]) -> Result<()> {
         self.status = Status::Generating;
         let mut new_msgs = Vec::new();
         for (id, output) in results {
-            let content = format!("Lua execution result:\n{}", output);
+            let content = if self.structured_results {
+                output.clone()
+            } else {
+                format!("Lua execution result:\n{}", output)
+            };
             let msg = OpenAIMessage {
                 role: "tool".to_string(),
                 content: Some(content),
                 tool_calls: Vec::new(),
                 tool_call_id: Some(id.clone()),
+                used_tokens: None,
             };
             new_msgs.push(msg);
         }
@@ -519,4 +550,90 @@ impl LLMClient for OpenAIClient {
         self.update_status_from_message(&response);
         Ok(())
     }
+
+    fn pop_user_turn(&mut self) -> Option<String> {
+        let index = self.history.iter().rposition(|msg| msg.role == "user")?;
+        let removed = self.history.split_off(index);
+        // The context is back to the last response before the turn.
+        self.used_token = self
+            .history
+            .iter()
+            .rev()
+            .find_map(|msg| msg.used_tokens)
+            .unwrap_or(0);
+        match self.history.last().cloned() {
+            Some(last) if last.role == "assistant" => self.update_status_from_message(&last),
+            _ => self.status = Status::Idle,
+        }
+        removed.into_iter().next().and_then(|msg| msg.content)
+    }
+
+    fn transcript(&self) -> Vec<TranscriptItem> {
+        let mut items = Vec::new();
+        for msg in &self.history {
+            let content = msg.content.clone().unwrap_or_default();
+            match msg.role.as_str() {
+                "system" => {}
+                "user" => items.push(TranscriptItem::User(content)),
+                "tool" => items.push(TranscriptItem::LuaResult {
+                    id: msg.tool_call_id.clone().unwrap_or_default(),
+                    output: content,
+                }),
+                _ => {
+                    if !content.is_empty() {
+                        items.push(TranscriptItem::Assistant(content));
+                    }
+                    for call in &msg.tool_calls {
+                        if call.function.name != "lua" {
+                            items.push(TranscriptItem::ToolCall {
+                                id: call.id.clone(),
+                                name: call.function.name.clone(),
+                                args: call.function.arguments.clone(),
+                            });
+                            continue;
+                        }
+                        let args = call.args();
+                        let code = args
+                            .get("code")
+                            .and_then(|value| value.as_str())
+                            .unwrap_or(&call.function.arguments);
+                        items.push(TranscriptItem::LuaCall {
+                            id: call.id.clone(),
+                            code: code.to_string(),
+                        });
+                    }
+                    if let Some(tokens) = msg.used_tokens {
+                        items.push(TranscriptItem::Tokens(tokens));
+                    }
+                }
+            }
+        }
+        items
+    }
+
+    fn export_history(&self) -> Result<Value> {
+        let mut history =
+            serde_json::to_value(&self.history).context("failed to export OpenAI history")?;
+        // Token counts are skipped for the API, so add them here.
+        if let Some(values) = history.as_array_mut() {
+            for (value, msg) in values.iter_mut().zip(&self.history) {
+                if let (Some(object), Some(tokens)) = (value.as_object_mut(), msg.used_tokens) {
+                    object.insert("used_tokens".to_string(), tokens.into());
+                }
+            }
+        }
+        Ok(history)
+    }
+
+    fn import_history(&mut self, history: &Value, used_token: usize) -> Result<()> {
+        let history: Vec<OpenAIMessage> =
+            serde_json::from_value(history.clone()).context("failed to import OpenAI history")?;
+        match history.last() {
+            Some(last) if last.role == "assistant" => self.update_status_from_message(last),
+            _ => self.status = Status::Idle,
+        }
+        self.history = history;
+        self.used_token = used_token;
+        Ok(())
+    }
 }
@@ -1,5 +1,15 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use serde_json::Value;
+
+/// ToolSpec is a tool advertised to the LLM, other than the builtin `lua` tool.
+#[derive(Debug, Clone)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments.
+    pub parameters: Value,
+}
 
 /// Status represents the current status of the LLM client.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -10,7 +20,7 @@ pub enum Status {
 }
 
 impl Status {
-    pub fn to_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &'static str {
         match self {
             Status::Idle => "Idle",
             Status::WaitForLuaResult => "Waiting for Lua Result",
@@ -19,14 +29,40 @@ impl Status {
     }
 }
 
+/// TranscriptItem is a message of the conversation, independent of LLM providers.
+/// System prompts are not included.
+#[derive(Debug, Clone)]
+pub enum TranscriptItem {
+    User(String),
+    Assistant(String),
+    LuaCall {
+        id: String,
+        code: String,
+    },
+    /// Call of a tool other than `lua`, with JSON arguments.
+    ToolCall {
+        id: String,
+        name: String,
+        args: String,
+    },
+    /// Result of a lua or other tool call.
+    LuaResult {
+        id: String,
+        output: String,
+    },
+    /// Tokens used by the conversation, reported with the response before it.
+    Tokens(usize),
+}
+
 #[async_trait(?Send)]
 pub trait LLMEventHandler {
     /// Called when a new chunk of assistant message is received.
     /// Note that the message may be incomplete and streaming.
     async fn on_assistant_chunk(&self, msg: &str) -> Result<()>;
 
-    /// Called when tool call lua is requested by the LLM.
-    async fn on_lua_call(&self, id: &str, code: &str, timeout_sec: Option<u64>) -> Result<()>;
+    /// Called when a tool call is requested by the LLM.
+    /// args is decoded JSON arguments, or an empty object if they are invalid.
+    async fn on_tool_call(&self, id: &str, name: &str, args: &Value) -> Result<()>;
 
     /// Called when the LLM has finished generating the response.
     async fn on_llm_finished(&self) -> Result<()>;
@@ -46,6 +82,9 @@ pub trait LLMClient {
 
     fn get_model_name(&self) -> String;
 
+    /// Set tools advertised in addition to the builtin `lua` tool.
+    fn set_tools(&mut self, tools: Vec<ToolSpec>);
+
     /// Asynchronously get the context size (used, total) of the LLM.
     fn context_size(&self) -> (usize, usize);
 
@@ -53,9 +92,27 @@ pub trait LLMClient {
     /// The response will be passed by LLM event handler.
     async fn send_user_msg(&mut self, message: &str) -> Result<()>;
 
-    /// Asynchronously send lua execution results to the LLM.
+    /// Whether tool results are sent as JSON objects, built by `LuaExecution::to_json`.
+    /// Otherwise they are sent as text.
+    fn structured_results(&self) -> bool;
+
+    /// Asynchronously send lua and other tool results to the LLM.
     /// The results is a list of (id, output) tuples.
     async fn send_lua_results(&mut self, results: &[(String, String)]) -> Result<()>;
+
+    /// Remove the last user message and everything after it from the history.
+    /// Returns the removed user message, or None if there is no user message.
+    fn pop_user_turn(&mut self) -> Option<String>;
+
+    /// Returns the conversation history, to be shown or exported.
+    fn transcript(&self) -> Vec<TranscriptItem>;
+
+    /// Export the conversation history, to be saved in a session.
+    fn export_history(&self) -> Result<Value>;
+
+    /// Replace the conversation history with the exported one.
+    /// The status is restored from the last message.
+    fn import_history(&mut self, history: &Value, used_token: usize) -> Result<()>;
 }
 
 pub type DynLLMClient = Box<dyn LLMClient>;
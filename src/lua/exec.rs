@@ -0,0 +1,144 @@
+//! `onui.exec`, which runs shell commands in the workspace.
+
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use mlua::{Lua, MultiValue, Table, Value};
+use tokio::process::Command;
+
+use super::onui::failure;
+use super::tasks::{self, Deadline};
+use crate::workspace::Workspace;
+
+/// Directory and timeout of a command.
+struct Options {
+    cwd: PathBuf,
+    timeout: Option<Duration>,
+}
+
+/// Parse `{ cwd, timeout }`. In the sandbox, cwd must be in the workspace or allowed roots.
+fn parse_options(workspace: &Workspace, sandbox: bool, opts: Option<Table>) -> Result<Options> {
+    let Some(opts) = opts else {
+        return Ok(Options {
+            cwd: workspace.root().to_path_buf(),
+            timeout: None,
+        });
+    };
+    let cwd = match opts.get::<Option<String>>("cwd")? {
+        Some(cwd) if sandbox => workspace
+            .resolve(&cwd)
+            .map_err(|err| anyhow!("sandbox: {}", err))?,
+        Some(cwd) => workspace.root().join(cwd),
+        None => workspace.root().to_path_buf(),
+    };
+    let timeout = match opts.get::<Option<f64>>("timeout")? {
+        Some(sec) => {
+            Some(Duration::try_from_secs_f64(sec).map_err(|_| anyhow!("invalid timeout {}", sec))?)
+        }
+        None => None,
+    };
+    Ok(Options { cwd, timeout })
+}
+
+async fn run(cmd: &str, options: Options) -> Result<Output> {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    };
+    command
+        .current_dir(&options.cwd)
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    let output = command.output();
+    match options.timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, output).await {
+            Ok(output) => Ok(output?),
+            Err(_) => bail!("timed out after {} seconds", timeout.as_secs_f64()),
+        },
+        None => Ok(output.await?),
+    }
+}
+
+/// Add `onui.exec` to the `onui` table. It fails when commands are not allowed.
+/// Commands are killed at the deadline of the script.
+pub fn register(
+    lua: &Lua,
+    workspace: Workspace,
+    sandbox: bool,
+    allowed: bool,
+    deadline: Deadline,
+) -> mlua::Result<()> {
+    // onui.exec(cmd, { cwd, timeout }) runs the shell command in the workspace.
+    // Returns { code, stdout, stderr }, or nil and the reason.
+    // code is nil if the command is killed by a signal.
+    let exec = tasks::create_function(lua, move |lua, (cmd, opts): (String, Option<Table>)| {
+        let options = if allowed {
+            parse_options(&workspace, sandbox, opts).map(|options| Options {
+                timeout: deadline.limit(options.timeout),
+                ..options
+            })
+        } else {
+            Err(anyhow!("sandbox: onui.exec is disabled"))
+        };
+        async move {
+            let output = match options {
+                Ok(options) => run(&cmd, options).await,
+                Err(err) => Err(err),
+            };
+            let output = match output {
+                Ok(output) => output,
+                Err(err) => return failure(&lua, err),
+            };
+            let result = lua.create_table()?;
+            result.set("code", output.status.code())?;
+            result.set("stdout", lua.create_string(&output.stdout)?)?;
+            result.set("stderr", lua.create_string(&output.stderr)?)?;
+            Ok(MultiValue::from_vec(vec![Value::Table(result)]))
+        }
+    })?;
+
+    let onui: Table = lua.globals().get("onui")?;
+    onui.set("exec", exec)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Instant;
+
+    use crate::lua::{LuaSetup, LuaVM};
+
+    fn run(code: &str, timeout_sec: u64) -> crate::lua::LuaExecution {
+        let vm = LuaVM::with_setup(LuaSetup::default()).unwrap();
+        vm.execute_script(code, Some(timeout_sec)).unwrap()
+    }
+
+    #[test]
+    fn runs_commands() {
+        let exec = run(
+            "local r = onui.exec('echo hi; echo err >&2; exit 3')\n\
+             return r.code, r.stdout, r.stderr",
+            5,
+        );
+        assert_eq!(exec.returns, vec!["3", "hi\n", "err\n"]);
+    }
+
+    #[test]
+    fn commands_stop_at_the_script_timeout() {
+        let start = Instant::now();
+        let exec = run("return onui.exec('sleep 4')", 1);
+        assert!(start.elapsed().as_millis() < 2500, "{:?}", start.elapsed());
+        assert_eq!(exec.returns[0], "nil");
+        assert!(exec.returns[1].contains("timed out"));
+
+        let start = Instant::now();
+        run("return onui.exec('sleep 4', { timeout = 10 })", 1);
+        assert!(start.elapsed().as_millis() < 2500, "{:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,261 @@
+//! `onui.http`, an HTTP client for scripts, limited to allowed hosts.
+
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use mlua::{Lua, MultiValue, Table, Value};
+use reqwest::{Client, Method, Url, redirect};
+
+use super::onui::failure;
+use super::tasks::{self, Deadline};
+
+/// Timeout of requests which do not give one.
+const DEFAULT_TIMEOUT_SEC: f64 = 30.0;
+
+/// Maximum number of redirects followed by a request.
+const MAX_REDIRECTS: usize = 10;
+
+/// A request given to `onui.http.request`.
+struct Request {
+    method: Method,
+    url: Url,
+    headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+    timeout: Duration,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// Check the host against patterns, where `*.example.com` matches subdomains.
+fn is_allowed(allowed_hosts: &[String], host: &str) -> bool {
+    allowed_hosts.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+        if pattern == "*" {
+            return true;
+        }
+        match pattern.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.ends_with('.')),
+            None => host == pattern,
+        }
+    })
+}
+
+/// Check the scheme and the host of the URL.
+fn check_url(allowed_hosts: &[String], url: &Url) -> Result<()> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        bail!("unsupported scheme {}", url.scheme());
+    }
+    let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    if !is_allowed(allowed_hosts, &host) {
+        bail!(
+            "host {} is not allowed; add it to http_allowed_hosts in the [lua] config",
+            host
+        );
+    }
+    Ok(())
+}
+
+/// Create the client. Redirects are followed only to allowed hosts.
+fn build_client(allowed_hosts: Vec<String>) -> Client {
+    let policy = redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error(anyhow!("too many redirects"))
+        } else if let Err(err) = check_url(&allowed_hosts, attempt.url()) {
+            attempt.error(err.context("redirect refused"))
+        } else {
+            attempt.follow()
+        }
+    });
+    Client::builder()
+        .redirect(policy)
+        .build()
+        .unwrap_or_default()
+}
+
+fn parse_request(args: &Table, allowed_hosts: &[String]) -> Result<Request> {
+    let url: String = args
+        .get::<Option<String>>("url")?
+        .ok_or_else(|| anyhow!("url is required"))?;
+    let url = Url::parse(&url).map_err(|err| anyhow!("invalid url {}: {}", url, err))?;
+    check_url(allowed_hosts, &url)?;
+
+    let method = args
+        .get::<Option<String>>("method")?
+        .unwrap_or_else(|| "GET".to_string());
+    let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+        .map_err(|_| anyhow!("invalid method {}", method))?;
+
+    let mut headers = Vec::new();
+    if let Some(table) = args.get::<Option<Table>>("headers")? {
+        for pair in table.pairs::<String, String>() {
+            headers.push(pair?);
+        }
+    }
+    let body = args
+        .get::<Option<mlua::String>>("body")?
+        .map(|body| body.as_bytes().to_vec());
+    let timeout = args
+        .get::<Option<f64>>("timeout")?
+        .unwrap_or(DEFAULT_TIMEOUT_SEC);
+    let timeout =
+        Duration::try_from_secs_f64(timeout).map_err(|_| anyhow!("invalid timeout {}", timeout))?;
+
+    Ok(Request {
+        method,
+        url,
+        headers,
+        body,
+        timeout,
+    })
+}
+
+async fn send(client: &Client, request: Request) -> Result<Response> {
+    let mut builder = client
+        .request(request.method, request.url)
+        .timeout(request.timeout);
+    for (name, value) in request.headers {
+        builder = builder.header(name, value);
+    }
+    if let Some(body) = request.body {
+        builder = builder.body(body);
+    }
+    let response = builder.send().await?;
+
+    let status = response.status().as_u16();
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for (name, value) in response.headers() {
+        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        // Repeated headers are joined, as in HTTP/1.1.
+        match headers.iter_mut().find(|(n, _)| n == name.as_str()) {
+            Some((_, joined)) => {
+                joined.push_str(", ");
+                joined.push_str(&value);
+            }
+            None => headers.push((name.to_string(), value)),
+        }
+    }
+    let body = response.bytes().await?.to_vec();
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Add `onui.http` to the `onui` table. Requests fail at the deadline of the script.
+pub fn register(lua: &Lua, allowed_hosts: Vec<String>, deadline: Deadline) -> mlua::Result<()> {
+    let client = build_client(allowed_hosts.clone());
+
+    // onui.http.request{ method, url, headers, body, timeout } sends a request.
+    // Returns { status, headers, body }, or nil and the reason.
+    // Statuses of HTTP errors are returned, not failures.
+    let request = tasks::create_function(lua, move |lua, args: Table| {
+        let request = parse_request(&args, &allowed_hosts).map(|request| Request {
+            timeout: deadline
+                .limit(Some(request.timeout))
+                .unwrap_or(request.timeout),
+            ..request
+        });
+        let client = client.clone();
+        async move {
+            let response = match request {
+                Ok(request) => send(&client, request).await,
+                Err(err) => Err(err),
+            };
+            let response = match response {
+                Ok(response) => response,
+                Err(err) => return failure(&lua, err),
+            };
+            let headers = lua.create_table()?;
+            for (name, value) in response.headers {
+                headers.set(name, value)?;
+            }
+            let result = lua.create_table()?;
+            result.set("status", response.status)?;
+            result.set("headers", headers)?;
+            result.set("body", lua.create_string(&response.body)?)?;
+            Ok(MultiValue::from_vec(vec![Value::Table(result)]))
+        }
+    })?;
+
+    let http = lua.create_table()?;
+    http.set("request", request)?;
+    let onui: Table = lua.globals().get("onui")?;
+    onui.set("http", http)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn hosts(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn allows_exact_and_wildcard_hosts() {
+        let allowed = hosts(&["example.com", "*.Example.org"]);
+        assert!(is_allowed(&allowed, "example.com"));
+        assert!(!is_allowed(&allowed, "api.example.com"));
+        assert!(is_allowed(&allowed, "api.example.org"));
+        assert!(is_allowed(&allowed, "a.b.example.org"));
+        assert!(!is_allowed(&allowed, "example.org"));
+        assert!(!is_allowed(&allowed, "badexample.org"));
+        assert!(is_allowed(&hosts(&["*"]), "anything"));
+        assert!(!is_allowed(&[], "example.com"));
+    }
+
+    #[test]
+    fn rejects_other_schemes_and_hosts() {
+        let allowed = hosts(&["example.com"]);
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert!(check_url(&allowed, &url("https://example.com/a")).is_ok());
+        assert!(check_url(&allowed, &url("HTTP://EXAMPLE.COM/")).is_ok());
+        assert!(check_url(&allowed, &url("ftp://example.com/")).is_err());
+        assert!(check_url(&allowed, &url("http://localhost/")).is_err());
+    }
+
+    /// Serve one request with a redirect to the location.
+    fn serve_redirect(location: String) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf);
+                let response = format!(
+                    "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    location
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn refuses_redirects_to_disallowed_hosts() {
+        let port = serve_redirect("http://localhost:1/".to_string());
+        let client = build_client(hosts(&["127.0.0.1"]));
+        let request = Request {
+            method: Method::GET,
+            url: Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap(),
+            headers: Vec::new(),
+            body: None,
+            timeout: Duration::from_secs(5),
+        };
+        let err = send(&client, request)
+            .await
+            .err()
+            .expect("redirect followed");
+        assert!(format!("{:#}", err).contains("not allowed"), "{:#}", err);
+    }
+}
@@ -0,0 +1,809 @@
+mod exec;
+mod http;
+mod onui;
+mod pretty;
+mod sandbox;
+mod snapshot;
+mod tasks;
+mod tools;
+mod traceback;
+
+pub use snapshot::Snapshot;
+pub use tools::LuaTool;
+
+use crate::config::ToolConfig;
+use crate::output;
+use crate::workspace::Workspace;
+use anyhow::{Result, anyhow};
+#[cfg(not(feature = "luau"))]
+use mlua::HookTriggers;
+use mlua::{Lua, LuaSerdeExt, MultiValue, Value, Variadic, VmState};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    env, fmt, fs,
+    path::PathBuf,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// Result of executing Lua code.
+pub struct LuaExecution {
+    pub stdout: String,
+    /// Written to `io.stderr`.
+    pub stderr: String,
+    pub error: Option<String>,
+    pub returns: Vec<String>,
+    /// Wall time of the execution.
+    pub elapsed: Duration,
+    /// Number of executed instructions, counted in steps of the hook.
+    pub instructions: u64,
+    pub timed_out: bool,
+}
+
+impl LuaExecution {
+    /// Returns the result as a JSON object, for LLMs accepting structured results.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "stdout": self.stdout,
+            "stderr": self.stderr,
+            "returns": self.returns,
+            "error": self.error,
+            "elapsed_ms": self.elapsed.as_millis() as u64,
+            "instructions": self.instructions,
+            "timed_out": self.timed_out,
+        })
+    }
+}
+
+impl fmt::Display for LuaExecution {
+    /// Format the execution result as a report for the LLM.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut result = String::new();
+
+        if self.stdout.is_empty() {
+            result.push_str("-- (StdOut is EMPTY)\n");
+        } else {
+            result.push_str("-- StdOut\n");
+            result.push_str(&self.stdout);
+        }
+
+        if !self.stderr.is_empty() {
+            result.push_str("-- StdErr\n");
+            result.push_str(&self.stderr);
+            if !self.stderr.ends_with('\n') {
+                result.push('\n');
+            }
+        }
+
+        if self.returns.is_empty() {
+            result.push_str("-- (No Returns)\n");
+        } else {
+            result.push_str(&format!("-- Returns ({})\n", self.returns.len()));
+            for ret in &self.returns {
+                result.push_str(&format!("{}\n", ret));
+            }
+        }
+
+        result.push_str(&format!(
+            "-- (Elapsed {} ms, about {} instructions)\n",
+            self.elapsed.as_millis(),
+            self.instructions
+        ));
+
+        if let Some(err) = &self.error {
+            result.push_str("-- Error\n");
+            result.push_str(err);
+        }
+        f.write_str(&result)
+    }
+}
+
+/// Prelude for `LuaVM::preview_writes`.
+/// It replaces functions with side effects by capturing or failing stubs.
+const PREVIEW_PRELUDE: &str = r#"
+local real_open = io.open
+local writes = {}
+__onui_preview_writes = writes
+
+local function unavailable(name)
+  return function()
+    error(name .. " is not available in preview", 2)
+  end
+end
+
+io.open = function(path, mode)
+  mode = mode or "r"
+  if not mode:find("[wa+]") then
+    return real_open(path, mode)
+  end
+  local buf = writes[path]
+  if buf == nil or mode:find("w") then
+    buf = {}
+    if not mode:find("w") then
+      local f = real_open(path, "rb")
+      if f then
+        buf[1] = f:read("*a")
+        f:close()
+      end
+    end
+    writes[path] = buf
+  end
+  local file = {}
+  function file:write(...)
+    for _, v in ipairs({ ... }) do
+      buf[#buf + 1] = tostring(v)
+    end
+    return self
+  end
+  function file:close() return true end
+  function file:flush() return self end
+  function file:seek() return 0 end
+  function file:setvbuf() return true end
+  return file
+end
+
+io.popen = unavailable("io.popen")
+io.output = unavailable("io.output")
+os.remove = unavailable("os.remove")
+os.rename = unavailable("os.rename")
+os.tmpname = unavailable("os.tmpname")
+onui.patch = unavailable("onui.patch")
+onui.unpatch = unavailable("onui.unpatch")
+onui.http.request = unavailable("onui.http.request")
+onui.exec = unavailable("onui.exec")
+if package then
+  package.loadlib = unavailable("package.loadlib")
+  package.cpath = ""
+end
+"#;
+
+/// Convert the value to text for the model. Strings and numbers are written as they are,
+/// and other values are rendered like Lua literals.
+fn value_to_string(lua: &Lua, value: &Value) -> Result<String, mlua::Error> {
+    match value {
+        Value::String(text) => Ok(text.to_str()?.to_string()),
+        Value::Integer(_) | Value::Number(_) => match lua.coerce_string(value.clone())? {
+            Some(text) => Ok(text.to_str()?.to_string()),
+            None => pretty::render(value),
+        },
+        _ => pretty::render(value),
+    }
+}
+
+/// Check whether the error is caused by the memory limit.
+fn is_memory_error(error: &mlua::Error) -> bool {
+    match error {
+        mlua::Error::MemoryError(_) => true,
+        mlua::Error::CallbackError { cause, .. } | mlua::Error::WithContext { cause, .. } => {
+            is_memory_error(cause)
+        }
+        _ => false,
+    }
+}
+
+/// Errors which stop the whole script, not to be caught by the VM itself.
+fn is_fatal(err: &mlua::Error) -> bool {
+    is_memory_error(err) || err.to_string().contains(TIMEOUT_MESSAGE)
+}
+
+fn map_lua_error(error: mlua::Error) -> anyhow::Error {
+    anyhow!(error.to_string())
+}
+
+/// Timeout of the init script.
+const INIT_TIMEOUT_SEC: u64 = 10;
+
+/// Timeout of restoring a snapshot, which may be edited by hand.
+const RESTORE_TIMEOUT_SEC: u64 = 10;
+
+/// Memory allowed beyond the limit, for scripts run at the limit.
+const MEMORY_MARGIN: usize = 8 * 1024 * 1024;
+
+/// Error raised by the hook when the timeout is exceeded.
+const TIMEOUT_MESSAGE: &str = "Lua execution timed out";
+
+/// Instructions between calls of the hook, which counts them and checks the timeout.
+#[cfg(not(feature = "luau"))]
+const HOOK_INTERVAL: u32 = 1000;
+
+/// Instructions counted at each call of the hook. Luau has no instruction hooks,
+/// and interrupts scripts at calls and loop iterations, each counted as one.
+#[cfg(not(feature = "luau"))]
+const HOOK_STEP: u64 = HOOK_INTERVAL as u64;
+#[cfg(feature = "luau")]
+const HOOK_STEP: u64 = 1;
+
+/// Name of the Lua backend selected by cargo features.
+pub const BACKEND: &str = if cfg!(feature = "luau") {
+    "luau"
+} else if cfg!(feature = "lua54") {
+    "lua54"
+} else {
+    "luajit"
+};
+
+/// Names of the Lua backends which can be built.
+pub const BACKENDS: &[&str] = &["luajit", "lua54", "luau"];
+
+/// Settings of a LuaVM, resolved from the `[lua]` config.
+#[derive(Clone, Debug, Default)]
+pub struct LuaSetup {
+    /// Patterns prepended to `package.path`.
+    pub package_path: Vec<String>,
+    /// Script run by `LuaVM::init`, if it exists.
+    pub init_script: Option<PathBuf>,
+    /// Tools defined in the config, by name.
+    pub tools: Vec<(String, ToolConfig)>,
+    /// Directory of tools, each defined in a `*.lua` file.
+    pub tool_dir: Option<PathBuf>,
+    /// Workspace directory, used by `onui` helpers. The current directory by default.
+    pub workspace: Option<PathBuf>,
+    /// Directory of outputs saved when results are truncated, read by `onui.output`.
+    /// Under the workspace by default.
+    pub outputs_dir: Option<PathBuf>,
+    /// Restrict file access of scripts to the workspace and allowed roots.
+    pub sandbox: bool,
+    /// Directories accessible in the sandbox, besides the workspace.
+    pub allowed_roots: Vec<PathBuf>,
+    /// Allow `io.popen` in the sandbox.
+    pub allow_popen: bool,
+    /// Maximum memory of the VM in bytes.
+    pub memory_limit: Option<usize>,
+    /// Timeout of tools which do not give one.
+    pub default_timeout_sec: Option<u64>,
+    /// Hosts reachable by `onui.http.request`.
+    pub http_allowed_hosts: Vec<String>,
+}
+
+/// Wraps a single embedded LuaVM instance.
+pub struct LuaVM {
+    /// The underlying Lua instance.
+    lua: Lua,
+
+    /// Captured standard output from the last execution.
+    out_buffer: Rc<RefCell<String>>,
+    /// Captured `io.stderr` writes from the last execution.
+    err_buffer: Rc<RefCell<String>>,
+
+    /// Names of globals defined by the VM itself, not saved in snapshots.
+    builtins: HashSet<String>,
+
+    /// Source of executed chunks by chunk name, to recover function sources.
+    sources: RefCell<HashMap<String, String>>,
+
+    /// Kept to set up the VM again when reset.
+    setup: LuaSetup,
+
+    tools: Vec<LuaTool>,
+    /// Errors while loading tools, to be reported.
+    tool_errors: Vec<String>,
+
+    /// Runs chunks and tool handlers with `xpcall`, for tracebacks.
+    runner: mlua::Function,
+
+    /// Deadline of the running script, which also bounds host calls.
+    deadline: tasks::Deadline,
+}
+
+impl LuaVM {
+    /// Initialize a new Lua instance with the built-in functions.
+    fn setup_functions(&mut self) -> Result<()> {
+        // Print function to capture stdout
+        let out_buffer = Rc::clone(&self.out_buffer);
+        let print_fn = self
+            .lua
+            .create_function(move |lua, args: Variadic<Value>| {
+                // Values are converted first, since `__tostring` may print too.
+                let texts = args
+                    .iter()
+                    .map(|value| value_to_string(lua, value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut buffer = out_buffer.borrow_mut();
+                buffer.push_str(&texts.join("\t"));
+                buffer.push('\n');
+                Ok(())
+            })
+            .expect("Failed to create print function");
+        let globals = self.lua.globals();
+        globals
+            .set("print", print_fn)
+            .expect("Failed to set print function");
+
+        let out_buffer = Rc::clone(&self.out_buffer);
+        let write_fn = self
+            .lua
+            .create_function(move |lua, args: Variadic<Value>| {
+                for value in args.iter() {
+                    let text = value_to_string(lua, value)?;
+                    out_buffer.borrow_mut().push_str(&text);
+                }
+                Ok(())
+            })
+            .expect("Failed to create io.write function");
+
+        // io.stderr is a sink with the methods of files used for messages.
+        let err_buffer = Rc::clone(&self.err_buffer);
+        let stderr_write = self
+            .lua
+            .create_function(move |lua, (file, args): (Value, Variadic<Value>)| {
+                for value in args.iter() {
+                    let text = value_to_string(lua, value)?;
+                    err_buffer.borrow_mut().push_str(&text);
+                }
+                Ok(file)
+            })
+            .map_err(map_lua_error)?;
+        let stderr_flush = self
+            .lua
+            .create_function(|_, file: Value| Ok(file))
+            .map_err(map_lua_error)?;
+        let stderr = self.lua.create_table().map_err(map_lua_error)?;
+        stderr.set("write", stderr_write).map_err(map_lua_error)?;
+        stderr.set("flush", stderr_flush).map_err(map_lua_error)?;
+
+        // Luau has no io library, but scripts still write output with it.
+        if cfg!(feature = "luau") && globals.get::<Value>("io").map_err(map_lua_error)?.is_nil() {
+            let io_table = self.lua.create_table().map_err(map_lua_error)?;
+            globals.set("io", io_table).map_err(map_lua_error)?;
+        }
+
+        if let Ok(io_table) = globals.get::<mlua::Table>("io") {
+            io_table
+                .set("stdin", Value::Nil)
+                .expect("Failed to disable io.stdin");
+            io_table
+                .set("stdout", Value::Nil)
+                .expect("Failed to disable io.stdout");
+            io_table
+                .set("stderr", stderr)
+                .expect("Failed to set io.stderr");
+            io_table
+                .set("write", write_fn)
+                .expect("Failed to override io.write");
+        }
+
+        if let Ok(os_table) = globals.get::<mlua::Table>("os") {
+            os_table
+                .set("exit", Value::Nil)
+                .expect("Failed to disable os.exit");
+            os_table
+                .set("execute", Value::Nil)
+                .expect("Failed to disable os.execute");
+        }
+
+        // Hooks do not run in JIT-compiled loops, which would never time out.
+        if let Ok(jit) = globals.get::<mlua::Table>("jit") {
+            jit.get::<mlua::Function>("off")
+                .and_then(|off| off.call::<()>(()))
+                .map_err(map_lua_error)?;
+        }
+
+        let workspace = self
+            .setup
+            .workspace
+            .clone()
+            .or_else(|| env::current_dir().ok())
+            .unwrap_or_default();
+        let workspace = Workspace::new(workspace);
+        let outputs_dir = self
+            .setup
+            .outputs_dir
+            .clone()
+            .unwrap_or_else(|| output::outputs_dir(workspace.root()));
+        onui::register(&self.lua, workspace.clone(), outputs_dir).map_err(map_lua_error)?;
+        tasks::register(&self.lua).map_err(map_lua_error)?;
+        http::register(
+            &self.lua,
+            self.setup.http_allowed_hosts.clone(),
+            self.deadline.clone(),
+        )
+        .map_err(map_lua_error)?;
+        let allow_exec = !self.setup.sandbox || self.setup.allow_popen;
+        if self.setup.sandbox {
+            let sandbox = workspace.with_allowed_roots(&self.setup.allowed_roots);
+            exec::register(
+                &self.lua,
+                sandbox.clone(),
+                true,
+                allow_exec,
+                self.deadline.clone(),
+            )
+            .map_err(map_lua_error)?;
+            // Luau has no file access to restrict.
+            if !cfg!(feature = "luau") {
+                sandbox::install(&self.lua, sandbox, self.setup.allow_popen)
+                    .map_err(map_lua_error)?;
+            }
+        } else {
+            exec::register(
+                &self.lua,
+                workspace,
+                false,
+                allow_exec,
+                self.deadline.clone(),
+            )
+            .map_err(map_lua_error)?;
+        }
+        Ok(())
+    }
+
+    /// Prepend the configured patterns to `package.path`.
+    fn setup_package_path(&self) -> Result<()> {
+        if self.setup.package_path.is_empty() {
+            return Ok(());
+        }
+        let Ok(package) = self.lua.globals().get::<mlua::Table>("package") else {
+            return Ok(());
+        };
+        let current: String = package.get("path").unwrap_or_default();
+        let mut paths = self.setup.package_path.clone();
+        if !current.is_empty() {
+            paths.push(current);
+        }
+        package.set("path", paths.join(";")).map_err(map_lua_error)
+    }
+
+    /// Create a new Lua virtual machine with the setup.
+    /// The init script is not run until `init` is called.
+    pub fn with_setup(setup: LuaSetup) -> Result<Self> {
+        let lua = Lua::new();
+        let runner = traceback::create_runner(&lua).map_err(map_lua_error)?;
+        let mut s = Self {
+            lua,
+            out_buffer: Rc::new(RefCell::new(String::new())),
+            err_buffer: Rc::new(RefCell::new(String::new())),
+            builtins: HashSet::new(),
+            sources: RefCell::new(HashMap::new()),
+            setup,
+            tools: Vec::new(),
+            tool_errors: Vec::new(),
+            runner,
+            deadline: tasks::Deadline::default(),
+        };
+        // Patterns of the setup are trusted by the sandbox, so they are set first.
+        s.setup_package_path()?;
+        s.setup_functions()?;
+        if let Some(limit) = s.setup.memory_limit {
+            s.lua.set_memory_limit(limit).map_err(map_lua_error)?;
+        }
+        s.setup_tools();
+        // Globals made by tool files are part of the setup, not of scripts.
+        s.builtins = s
+            .lua
+            .globals()
+            .pairs::<String, Value>()
+            .filter_map(|pair| pair.ok().map(|(name, _)| name))
+            .collect();
+        Ok(s)
+    }
+
+    /// Load tools of the config and the tool directory.
+    /// Tools which fail to load are skipped, with errors kept in `tool_errors`.
+    /// Top-level code of tool files runs under the timeout of the init script.
+    fn setup_tools(&mut self) {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(INIT_TIMEOUT_SEC);
+        let hooked = self.set_hook(move || {
+            if start.elapsed() > timeout {
+                Err(mlua::Error::RuntimeError(TIMEOUT_MESSAGE.to_string()))
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(err) = hooked {
+            self.tool_errors
+                .push(format!("tools are not loaded: {}", err));
+            return;
+        }
+        let mut loaded = Vec::new();
+        for (name, config) in &self.setup.tools {
+            match tools::load_config_tool(&self.lua, name, config, &loaded) {
+                Ok(tool) => loaded.push(tool),
+                Err(err) => self.tool_errors.push(err),
+            }
+        }
+        if let Some(ref dir) = self.setup.tool_dir {
+            for path in tools::tool_files(dir) {
+                match tools::load_file_tool(&self.lua, &path, &loaded) {
+                    Ok(tool) => loaded.push(tool),
+                    Err(err) => self.tool_errors.push(err),
+                }
+            }
+        }
+        self.remove_hook();
+        self.tools = loaded;
+    }
+
+    pub fn tools(&self) -> &[LuaTool] {
+        &self.tools
+    }
+
+    pub fn tool_errors(&self) -> &[String] {
+        &self.tool_errors
+    }
+
+    /// Load a chunk with a unique name, recording its source.
+    fn load_recorded(&self, source: &str, prefix: &str) -> mlua::Chunk<'_> {
+        let mut sources = self.sources.borrow_mut();
+        let name = format!("{}#{}", prefix, sources.len() + 1);
+        sources.insert(name.clone(), source.to_string());
+        self.lua.load(source.to_string()).set_name(name)
+    }
+
+    /// Run the init script if it exists, and returns its path and result.
+    pub fn init(&self) -> Result<Option<(PathBuf, LuaExecution)>> {
+        let Some(ref path) = self.setup.init_script else {
+            return Ok(None);
+        };
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let script = fs::read_to_string(path)
+            .map_err(|err| anyhow!("failed to read {}: {}", path.display(), err))?;
+        let exec = self.execute_chunk(&script, "onui-init", Some(INIT_TIMEOUT_SEC))?;
+        Ok(Some((path.clone(), exec)))
+    }
+
+    /// Execute the provided Lua code string.
+    pub fn execute_script(&self, script: &str, timeout_sec: Option<u64>) -> Result<LuaExecution> {
+        self.execute_chunk(script, "onui-agent", timeout_sec)
+    }
+
+    fn execute_chunk(
+        &self,
+        script: &str,
+        chunk_prefix: &str,
+        timeout_sec: Option<u64>,
+    ) -> Result<LuaExecution> {
+        self.run_with_timeout(
+            timeout_sec,
+            |vm| {
+                let chunk =
+                    vm.unlimited(|| vm.load_recorded(script, chunk_prefix).into_function())?;
+                traceback::runner_result(vm.runner.call(chunk)?)
+            },
+            value_to_string,
+        )
+    }
+
+    /// Call the handler of a tool with the decoded arguments.
+    /// Tables returned by the handler are encoded as JSON.
+    pub fn call_tool(&self, name: &str, args: &serde_json::Value) -> Result<LuaExecution> {
+        let tool = self
+            .tools
+            .iter()
+            .find(|t| t.spec.name == name)
+            .ok_or_else(|| anyhow!("Unknown tool '{}'", name))?;
+        let args = self.lua.to_value(args).map_err(map_lua_error)?;
+        self.run_with_timeout(
+            tool.timeout_sec.or(self.setup.default_timeout_sec),
+            |vm| traceback::runner_result(vm.runner.call((&tool.handler, args))?),
+            |lua, value| match value {
+                Value::Table(_) => match lua.from_value::<serde_json::Value>(value.clone()) {
+                    Ok(json) => Ok(json.to_string()),
+                    Err(_) => value_to_string(lua, value),
+                },
+                _ => value_to_string(lua, value),
+            },
+        )
+    }
+
+    /// Run f without the memory limit, for work of the VM itself.
+    /// It keeps the VM usable after scripts hit the limit, e.g. to free globals.
+    fn unlimited<T>(&self, f: impl FnOnce() -> T) -> T {
+        if self.setup.memory_limit.is_none() {
+            return f();
+        }
+        let limit = self.lua.set_memory_limit(0).unwrap_or(0);
+        let result = f();
+        let _ = self.lua.set_memory_limit(limit);
+        result
+    }
+
+    /// Call the callback periodically while scripts run, to stop them with its error.
+    /// It is global, to run also in coroutines of `onui.await_all`.
+    #[cfg(not(feature = "luau"))]
+    fn set_hook<F>(&self, callback: F) -> mlua::Result<()>
+    where
+        F: Fn() -> mlua::Result<()> + 'static,
+    {
+        self.lua.set_global_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+            move |_lua, _debug| callback().map(|_| VmState::Continue),
+        )
+    }
+
+    /// Call the callback periodically while scripts run, to stop them with its error.
+    /// The interrupt of Luau runs in all threads.
+    #[cfg(feature = "luau")]
+    fn set_hook<F>(&self, callback: F) -> mlua::Result<()>
+    where
+        F: Fn() -> mlua::Result<()> + 'static,
+    {
+        self.lua
+            .set_interrupt(move |_lua| callback().map(|_| VmState::Continue));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "luau"))]
+    fn remove_hook(&self) {
+        self.lua.remove_global_hook();
+        self.lua.remove_hook();
+    }
+
+    #[cfg(feature = "luau")]
+    fn remove_hook(&self) {
+        self.lua.remove_interrupt();
+    }
+
+    /// Run f, stopping it after the timeout, and collect outputs.
+    fn run_with_timeout<F, S>(
+        &self,
+        timeout_sec: Option<u64>,
+        f: F,
+        stringify: S,
+    ) -> Result<LuaExecution>
+    where
+        F: FnOnce(&Self) -> Result<MultiValue, mlua::Error>,
+        S: Fn(&Lua, &Value) -> Result<String, mlua::Error>,
+    {
+        // Clear previous output
+        self.out_buffer.borrow_mut().clear();
+        self.err_buffer.borrow_mut().clear();
+
+        if let Some(limit) = self.setup.memory_limit {
+            // Near the limit, scripts get a margin to free memory, e.g. by clearing globals.
+            let limit = if self.lua.used_memory() + MEMORY_MARGIN > limit {
+                limit + MEMORY_MARGIN
+            } else {
+                limit
+            };
+            let _ = self.lua.set_memory_limit(limit);
+        }
+
+        let start = Instant::now();
+        let timed_out = Rc::new(Cell::new(false));
+        let instructions = Rc::new(Cell::new(0u64));
+        {
+            let timeout = timeout_sec.map(Duration::from_secs);
+            let timed_out = Rc::clone(&timed_out);
+            let instructions = Rc::clone(&instructions);
+            self.unlimited(|| {
+                self.set_hook(move || {
+                    instructions.set(instructions.get() + HOOK_STEP);
+                    if timeout.is_some_and(|timeout| start.elapsed() > timeout) {
+                        timed_out.set(true);
+                        Err(mlua::Error::RuntimeError(TIMEOUT_MESSAGE.to_string()))
+                    } else {
+                        Ok(())
+                    }
+                })
+            })
+            .map_err(map_lua_error)?;
+        }
+
+        self.deadline
+            .set(timeout_sec.map(|sec| start + Duration::from_secs(sec)));
+        // Values are converted under the hook too, as `__tostring` may run.
+        let exec_result = f(self).and_then(|values| {
+            values
+                .iter()
+                .map(|value| stringify(&self.lua, value))
+                .collect::<Result<Vec<_>, _>>()
+        });
+        let elapsed = start.elapsed();
+
+        self.deadline.set(None);
+        self.remove_hook();
+
+        let stdout = self.out_buffer.borrow().clone();
+        let stderr = self.err_buffer.borrow().clone();
+        match exec_result {
+            Ok(returns) => Ok(LuaExecution {
+                stdout,
+                stderr,
+                error: None,
+                returns,
+                elapsed,
+                instructions: instructions.get(),
+                timed_out: false,
+            }),
+            Err(err) => {
+                let error = if timed_out.get() {
+                    format!(
+                        "Lua execution stopped: the time limit of {} seconds was exceeded.",
+                        timeout_sec.unwrap_or_default()
+                    )
+                } else if is_memory_error(&err) {
+                    // Free garbage made by the script, for later scripts.
+                    let _ = self.lua.gc_collect();
+                    format!(
+                        "Lua execution stopped: the memory limit of {} MiB was exceeded. \
+                         Data kept in globals still uses memory; set them to nil to free it.",
+                        self.setup.memory_limit.unwrap_or_default() / (1024 * 1024)
+                    )
+                } else {
+                    let error =
+                        self.unlimited(|| traceback::format_error(&err, &self.sources.borrow()));
+                    format!("Lua execution failed: {}", error)
+                };
+                Ok(LuaExecution {
+                    stdout,
+                    stderr,
+                    error: Some(error),
+                    returns: Vec::new(),
+                    elapsed,
+                    instructions: instructions.get(),
+                    timed_out: timed_out.get(),
+                })
+            }
+        }
+    }
+
+    /// Dry-run a script in a fresh VM of the setup, where files opened for writing are
+    /// captured in memory and other side effects are disabled.
+    /// Returns the would-be content of each written file, by path.
+    /// Writes made before a failure are still returned.
+    pub fn preview_writes(
+        script: &str,
+        setup: LuaSetup,
+        timeout_sec: u64,
+    ) -> Result<Vec<(String, String)>> {
+        let vm = LuaVM::with_setup(setup)?;
+        vm.lua
+            .load(PREVIEW_PRELUDE)
+            .set_name("onui-preview")
+            .exec()
+            .map_err(map_lua_error)?;
+        let _ = vm.execute_script(script, Some(timeout_sec))?;
+
+        let writes: mlua::Table = vm
+            .lua
+            .globals()
+            .get("__onui_preview_writes")
+            .map_err(map_lua_error)?;
+        let mut files = Vec::new();
+        for pair in writes.pairs::<String, mlua::Table>() {
+            let (path, chunks) = pair.map_err(map_lua_error)?;
+            let mut content = String::new();
+            for chunk in chunks.sequence_values::<mlua::String>() {
+                let chunk = chunk.map_err(map_lua_error)?;
+                content.push_str(&chunk.to_string_lossy());
+            }
+            files.push((path, content));
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Serialize the globals defined by scripts, as Lua source.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        snapshot::dump_globals(&self.lua, &self.builtins, &self.sources.borrow())
+            .map_err(map_lua_error)
+    }
+
+    /// Restore globals from the snapshot source. Other globals are kept.
+    /// The source runs under the timeout and the memory limit, like scripts.
+    pub fn restore(&self, source: &str) -> Result<()> {
+        let exec = self.run_with_timeout(
+            Some(RESTORE_TIMEOUT_SEC),
+            |vm| {
+                vm.load_recorded(source, "onui-snapshot").exec()?;
+                Ok(MultiValue::new())
+            },
+            value_to_string,
+        )?;
+        match exec.error {
+            Some(error) => Err(anyhow!(error)),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace the VM with a new one of the same setup.
+    /// The init script should be run again by `init`.
+    pub fn reset(&mut self) -> Result<()> {
+        *self = LuaVM::with_setup(self.setup.clone())?;
+        Ok(())
+    }
+}
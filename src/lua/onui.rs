@@ -0,0 +1,113 @@
+//! The `onui` library table, with helpers provided to scripts.
+
+use std::path::PathBuf;
+
+use mlua::{Lua, MultiValue, Table, Value};
+
+use crate::output;
+use crate::workspace::Workspace;
+
+/// Number of lines returned by `onui.output` by default.
+const OUTPUT_PAGE_LINES: usize = 100;
+
+/// Returns `nil, message`, as Lua functions report failures.
+pub(super) fn failure(lua: &Lua, err: anyhow::Error) -> mlua::Result<MultiValue> {
+    Ok(MultiValue::from_vec(vec![
+        Value::Nil,
+        Value::String(lua.create_string(format!("{:#}", err))?),
+    ]))
+}
+
+/// Create the `onui` global table. `onui.output` reads saved outputs in `outputs_dir`.
+pub fn register(lua: &Lua, workspace: Workspace, outputs_dir: PathBuf) -> mlua::Result<()> {
+    let onui = lua.create_table()?;
+
+    // onui.patch(path, diff) applies a unified diff to the file.
+    // Returns { path, backup, hunks = { { line, offset, fuzz }, ... } },
+    // or nil and the reason.
+    let ws = workspace.clone();
+    let patch = lua.create_function(move |lua, (path, diff): (String, String)| {
+        let report = match ws.patch_file(&path, &diff) {
+            Ok(report) => report,
+            Err(err) => return failure(lua, err),
+        };
+        let hunks = lua.create_table()?;
+        for hunk in &report.applied.hunks {
+            let item = lua.create_table()?;
+            item.set("line", hunk.line)?;
+            item.set("offset", hunk.offset)?;
+            item.set("fuzz", hunk.fuzz)?;
+            hunks.push(item)?;
+        }
+        let result: Table = lua.create_table()?;
+        result.set("path", report.path)?;
+        result.set("backup", report.backup)?;
+        result.set("hunks", hunks)?;
+        Ok(MultiValue::from_vec(vec![Value::Table(result)]))
+    })?;
+    onui.set("patch", patch)?;
+
+    // onui.unpatch(path) restores the file before the last patch.
+    // Returns the used backup path, or nil and the reason.
+    let ws = workspace.clone();
+    let unpatch = lua.create_function(move |lua, path: String| match ws.unpatch(&path) {
+        Ok(backup) => Ok(MultiValue::from_vec(vec![Value::String(
+            lua.create_string(backup)?,
+        )])),
+        Err(err) => failure(lua, err),
+    })?;
+    onui.set("unpatch", unpatch)?;
+
+    // onui.output(id, start_line, count) reads lines of a truncated result.
+    // Returns the text and the total number of lines, or nil and the reason.
+    let read_output = lua.create_function(
+        move |lua, (id, start_line, count): (String, Option<usize>, Option<usize>)| {
+            let path = outputs_dir.join(output::file_name(&id));
+            let start_line = start_line.unwrap_or(1);
+            let count = count.unwrap_or(OUTPUT_PAGE_LINES);
+            match output::read_lines(&path, start_line, count) {
+                Ok((text, total)) => Ok(MultiValue::from_vec(vec![
+                    Value::String(lua.create_string(text)?),
+                    Value::Integer(total as mlua::Integer),
+                ])),
+                Err(err) => failure(lua, err.context(format!("no saved output of {}", id))),
+            }
+        },
+    )?;
+    onui.set("output", read_output)?;
+
+    lua.globals().set("onui", onui)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::lua::{LuaSetup, LuaVM};
+    use crate::output;
+
+    #[test]
+    fn output_reads_the_outputs_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let root = base.join("vm-root");
+        fs::create_dir_all(&root).unwrap();
+        output::save(base, "call:1", "one\ntwo\nthree\n").unwrap();
+        let vm = LuaVM::with_setup(LuaSetup {
+            workspace: Some(root),
+            outputs_dir: Some(output::outputs_dir(base)),
+            ..LuaSetup::default()
+        })
+        .unwrap();
+
+        let exec = vm
+            .execute_script("return onui.output('call:1', 2, 1)", Some(5))
+            .unwrap();
+        assert_eq!(exec.returns, vec!["two\n", "3"], "{:?}", exec.error);
+        let exec = vm
+            .execute_script("return onui.output('call:2')", Some(5))
+            .unwrap();
+        assert_eq!(exec.returns[0], "nil");
+        assert!(exec.returns[1].contains("no saved output of call:2"));
+    }
+}
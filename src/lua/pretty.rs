@@ -0,0 +1,293 @@
+//! Rendering of Lua values for the model, with tables as Lua literals.
+
+use std::cmp::Ordering;
+use std::ffi::c_void;
+
+use mlua::{Function, Table, Value};
+
+use super::is_fatal;
+use super::snapshot::dump_number;
+
+/// Nested tables deeper than this are shown as `{...}`.
+const MAX_DEPTH: usize = 5;
+
+/// Entries of a table shown at most, the rest are counted.
+const MAX_ITEMS: usize = 50;
+
+/// Tables rendered longer than this are split into lines.
+const MAX_WIDTH: usize = 80;
+
+/// Render the value, e.g. `{ 1, 2, name = "x" }` for tables.
+/// Tables with `__tostring` are converted by it, and cycles are marked.
+/// Fails only when the metamethod is stopped by the timeout or the memory limit.
+pub fn render(value: &Value) -> mlua::Result<String> {
+    Renderer { path: Vec::new() }.value(value, 0)
+}
+
+/// Context of rendering.
+struct Renderer {
+    /// Tables being rendered, to detect cycles.
+    path: Vec<*const c_void>,
+}
+
+impl Renderer {
+    fn value(&mut self, value: &Value, indent: usize) -> mlua::Result<String> {
+        Ok(match value {
+            Value::Nil => "nil".to_string(),
+            Value::Boolean(b) => b.to_string(),
+            Value::Integer(i) => i.to_string(),
+            Value::Number(n) => dump_number(*n),
+            Value::String(s) => quote_text(&s.as_bytes()),
+            Value::Table(table) => self.table(table, indent)?,
+            Value::Error(err) => format!("<error: {}>", err),
+            other => format!("<{}: {:?}>", other.type_name(), other.to_pointer()),
+        })
+    }
+
+    fn table(&mut self, table: &Table, indent: usize) -> mlua::Result<String> {
+        if let Some(text) = tostring_metamethod(table)? {
+            return Ok(text);
+        }
+        let ptr = table.to_pointer();
+        if self.path.contains(&ptr) {
+            return Ok(format!("<cycle: {:?}>", ptr));
+        }
+        if self.path.len() >= MAX_DEPTH {
+            return Ok("{...}".to_string());
+        }
+        self.path.push(ptr);
+        let result = self.table_entries(table, indent);
+        self.path.pop();
+        result
+    }
+
+    fn table_entries(&mut self, table: &Table, indent: usize) -> mlua::Result<String> {
+        // Sequence part is written without keys.
+        let len = table.raw_len();
+        let mut total = len;
+        let mut entries = Vec::new();
+        for (key, value) in table.clone().pairs::<Value, Value>().flatten() {
+            if sequence_index(&key).is_some_and(|i| i <= len) {
+                continue;
+            }
+            total += 1;
+            entries.push((key, value));
+        }
+        entries.sort_by(|a, b| compare_keys(&a.0, &b.0));
+
+        let mut items = Vec::new();
+        for i in 1..=len.min(MAX_ITEMS) {
+            let value: Value = table.raw_get(i).unwrap_or(Value::Nil);
+            items.push(self.value(&value, indent + 1)?);
+        }
+        let sequence = items.len();
+        for (key, value) in entries.iter().take(MAX_ITEMS - items.len()) {
+            let value = self.value(value, indent + 1)?;
+            items.push(format!("{} = {}", self.key(key)?, value));
+        }
+        let more = (total > items.len()).then(|| format!("--[[ {} more ]]", total - items.len()));
+
+        if items.is_empty() && more.is_none() {
+            return Ok("{}".to_string());
+        }
+        let mut inline = items.clone();
+        inline.extend(more.clone());
+        let inline = format!("{{ {} }}", inline.join(", "));
+        if inline.len() + indent * 2 <= MAX_WIDTH && !inline.contains('\n') {
+            return Ok(inline);
+        }
+
+        // Short items of the sequence share lines, and others take a line each.
+        let pad = "  ".repeat(indent + 1);
+        let mut lines: Vec<String> = Vec::new();
+        let mut packed = false;
+        for (idx, item) in items.into_iter().enumerate() {
+            let item = format!("{},", item);
+            if idx < sequence && !item.contains('\n') {
+                if packed
+                    && let Some(last) = lines.last_mut()
+                    && last.len() + 1 + item.len() <= MAX_WIDTH
+                {
+                    last.push(' ');
+                    last.push_str(&item);
+                    continue;
+                }
+                packed = true;
+            } else {
+                packed = false;
+            }
+            lines.push(format!("{}{}", pad, item));
+        }
+        lines.extend(more.map(|more| format!("{}{}", pad, more)));
+        Ok(format!(
+            "{{\n{}\n{}}}",
+            lines.join("\n"),
+            "  ".repeat(indent)
+        ))
+    }
+
+    fn key(&mut self, key: &Value) -> mlua::Result<String> {
+        if let Value::String(s) = key
+            && let Ok(name) = s.to_str()
+            && is_identifier(&name)
+        {
+            return Ok(name.to_string());
+        }
+        Ok(format!("[{}]", self.value(key, 0)?))
+    }
+}
+
+/// Quote bytes as a Lua string literal for reading. Valid UTF-8 is kept as it is,
+/// and control characters and invalid bytes are escaped.
+fn quote_text(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() + 2);
+    out.push('"');
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        out.push_str(&format!("\\{:03}", b));
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\{:03}", b));
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Returns the text of the `__tostring` metamethod, if any.
+/// Failures of the metamethod are ignored, unless they stop the script.
+fn tostring_metamethod(table: &Table) -> mlua::Result<Option<String>> {
+    let Some(function) = table
+        .metatable()
+        .and_then(|meta| meta.raw_get::<Function>("__tostring").ok())
+    else {
+        return Ok(None);
+    };
+    match function.call::<String>(table) {
+        Ok(text) => Ok(Some(text)),
+        Err(err) if is_fatal(&err) => Err(err),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Returns the index if the key is a positive integer.
+fn sequence_index(key: &Value) -> Option<usize> {
+    match *key {
+        Value::Integer(i) if i >= 1 => Some(i as usize),
+        Value::Number(n) if n.fract() == 0.0 && n >= 1.0 => Some(n as usize),
+        _ => None,
+    }
+}
+
+/// Numbers first in numeric order, then strings, then others by type.
+fn compare_keys(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Integer(_) | Value::Number(_) => 0,
+            Value::String(_) => 1,
+            Value::Boolean(_) => 2,
+            _ => 3,
+        }
+    }
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.as_bytes().cmp(&b.as_bytes()),
+        (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+        _ => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            _ => rank(a).cmp(&rank(b)),
+        },
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if",
+        "in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+    ];
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::Lua;
+
+    use super::*;
+    use crate::lua::{LuaSetup, LuaVM};
+
+    fn eval(code: &str) -> String {
+        let lua = Lua::new();
+        render(&lua.load(code).eval::<Value>().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn renders_tables() {
+        assert_eq!(eval("return {}"), "{}");
+        assert_eq!(
+            eval("return { 1, 2.5, b = true, a = 'x', [10] = 0, ['a b'] = 1 }"),
+            r#"{ 1, 2.5, [10] = 0, a = "x", ["a b"] = 1, b = true }"#
+        );
+        assert_eq!(eval("return { ['end'] = 1 }"), r#"{ ["end"] = 1 }"#);
+        assert_eq!(
+            eval("return setmetatable({}, { __tostring = function() return 'obj' end })"),
+            "obj"
+        );
+    }
+
+    #[test]
+    fn keeps_utf8_and_escapes_controls() {
+        assert_eq!(
+            eval(r#"return { name = 'héllo 한글' }"#),
+            r#"{ name = "héllo 한글" }"#
+        );
+        assert_eq!(eval(r#"return 'a"\\\n\t\1\255'"#), r#""a\"\\\n\t\001\255""#);
+    }
+
+    #[test]
+    fn limits_cycles_depth_and_items() {
+        assert!(eval("local t = {}; t.self = t; return t").starts_with("{ self = <cycle: "));
+        assert_eq!(eval("return {{{{{{{}}}}}}}"), "{ { { { { {...} } } } } }");
+        let long = eval("local t = {}; for i = 1, 60 do t[i] = i end; return t");
+        assert!(long.starts_with("{\n  1, 2, 3,"), "{}", long);
+        assert!(long.ends_with(" 49, 50,\n  --[[ 10 more ]]\n}"), "{}", long);
+    }
+
+    #[test]
+    fn looping_tostring_times_out() {
+        let vm = LuaVM::with_setup(LuaSetup::default()).unwrap();
+        let looping =
+            "local t = setmetatable({}, { __tostring = function() while true do end end })";
+        for code in [
+            format!("{}; return t", looping),
+            format!("{}; print(t)", looping),
+        ] {
+            let exec = vm.execute_script(&code, Some(1)).unwrap();
+            assert!(exec.timed_out, "{}", code);
+            assert!(exec.error.unwrap().contains("time limit"), "{}", code);
+        }
+        let exec = vm
+            .execute_script(
+                "return setmetatable({}, { __tostring = function() error('x') end })",
+                Some(1),
+            )
+            .unwrap();
+        assert_eq!(exec.returns, vec!["{}"]);
+    }
+}
@@ -0,0 +1,317 @@
+//! Sandbox mode, which restricts file access of scripts to the workspace.
+
+use mlua::{Lua, MultiValue, Value};
+
+use crate::workspace::Workspace;
+
+/// Wraps functions taking paths. It is called with the path resolver,
+/// the prefix of shell commands, and whether `io.popen` is allowed.
+const SANDBOX_PRELUDE: &str = r#"
+local resolve, cd_prefix, allow_popen = ...
+
+-- Resolve the path, or raise the error at the caller.
+local function checked(path)
+  local full, err = resolve(path)
+  if not full then
+    error(err, 3)
+  end
+  return full
+end
+
+local real_open = io.open
+io.open = function(path, mode)
+  local full, err = resolve(path)
+  if not full then
+    return nil, err
+  end
+  return real_open(full, mode)
+end
+
+local real_lines = io.lines
+io.lines = function(path, ...)
+  if path == nil then
+    return real_lines()
+  end
+  return real_lines(checked(path), ...)
+end
+
+for _, name in ipairs({ "input", "output" }) do
+  local real = io[name]
+  io[name] = function(file)
+    if type(file) == "string" then
+      file = checked(file)
+    end
+    return real(file)
+  end
+end
+
+local real_remove = os.remove
+os.remove = function(path)
+  local full, err = resolve(path)
+  if not full then
+    return nil, err
+  end
+  return real_remove(full)
+end
+
+local real_rename = os.rename
+os.rename = function(from, to)
+  local full_from, err = resolve(from)
+  if not full_from then
+    return nil, err
+  end
+  local full_to, err_to = resolve(to)
+  if not full_to then
+    return nil, err_to
+  end
+  return real_rename(full_from, full_to)
+end
+
+local real_loadfile = loadfile
+loadfile = function(path, ...)
+  if path == nil then
+    return nil, "sandbox: loadfile without a path is not allowed"
+  end
+  local full, err = resolve(path)
+  if not full then
+    return nil, err
+  end
+  return real_loadfile(full, ...)
+end
+
+dofile = function(path)
+  if path == nil then
+    error("sandbox: dofile without a path is not allowed", 2)
+  end
+  local f, err = real_loadfile(checked(path))
+  if not f then
+    error(err, 2)
+  end
+  return f()
+end
+
+local real_popen = io.popen
+io.popen = function(cmd, mode)
+  if not allow_popen then
+    return nil, "sandbox: io.popen is disabled"
+  end
+  return real_popen(cd_prefix .. cmd, mode)
+end
+
+-- C libraries may access anything.
+if package then
+  package.loadlib = nil
+  package.cpath = ""
+end
+
+-- require loads Lua files only, from paths of the setup or in the sandbox.
+-- The original searchers are dropped, not to be restored by scripts.
+if package then
+  local trusted = {}
+  for pattern in package.path:gmatch("[^;]+") do
+    trusted[pattern] = true
+  end
+  local sep = package.config:sub(1, 1)
+
+  local function search_lua(name)
+    local file_name = name:gsub("%.", sep)
+    local tried = {}
+    for pattern in package.path:gmatch("[^;]+") do
+      local file = pattern:gsub("%?", file_name)
+      local full, err = file, nil
+      if not trusted[pattern] then
+        full, err = resolve(file)
+      end
+      if not full then
+        tried[#tried + 1] = "\n\t" .. err
+      else
+        local f = real_open(full, "r")
+        if f then
+          f:close()
+          local chunk, load_err = real_loadfile(full)
+          if not chunk then
+            error(string.format("error loading module '%s' from file '%s':\n\t%s", name, full, load_err), 2)
+          end
+          return chunk, full
+        end
+        tried[#tried + 1] = "\n\tno file '" .. file .. "'"
+      end
+    end
+    return table.concat(tried)
+  end
+
+  local function search_preload(name)
+    local loader = package.preload[name]
+    if loader == nil then
+      return "\n\tno field package.preload['" .. name .. "']"
+    end
+    return loader
+  end
+
+  local searchers = { search_preload, search_lua }
+  package.searchers = searchers
+  package.loaders = searchers
+end
+"#;
+
+/// Quote the path for the shell running `io.popen` commands.
+fn cd_prefix(workspace: &Workspace) -> String {
+    let root = workspace.root().to_string_lossy();
+    if cfg!(target_os = "windows") {
+        format!("cd /d \"{}\" && ", root)
+    } else {
+        format!("cd '{}' && ", root.replace('\'', "'\\''"))
+    }
+}
+
+/// Wrap functions taking paths, so that relative paths are resolved against
+/// the workspace and paths outside of the workspace and allowed roots are rejected.
+/// `require` loads only Lua modules, found in the sandbox or by patterns of
+/// `package.path` at the time of installation.
+/// Commands of `io.popen` run in the workspace, but their arguments are not checked.
+pub fn install(lua: &Lua, workspace: Workspace, allow_popen: bool) -> mlua::Result<()> {
+    let prefix = cd_prefix(&workspace);
+    let resolve = lua.create_function(move |lua, path: String| {
+        let result = match workspace.resolve(&path) {
+            Ok(full) => Value::String(lua.create_string(full.to_string_lossy().as_bytes())?),
+            Err(err) => {
+                let msg = format!("sandbox: {}", err);
+                return Ok(MultiValue::from_vec(vec![
+                    Value::Nil,
+                    Value::String(lua.create_string(msg)?),
+                ]));
+            }
+        };
+        Ok(MultiValue::from_vec(vec![result]))
+    })?;
+    lua.load(SANDBOX_PRELUDE)
+        .set_name("onui-sandbox")
+        .call::<()>((resolve, prefix, allow_popen))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::config::Config;
+    use crate::lua::{LuaSetup, LuaVM};
+
+    fn sandboxed(workspace: &Path, package_path: Vec<String>) -> LuaVM {
+        LuaVM::with_setup(LuaSetup {
+            workspace: Some(workspace.to_path_buf()),
+            sandbox: true,
+            package_path,
+            ..LuaSetup::default()
+        })
+        .unwrap()
+    }
+
+    fn run(vm: &LuaVM, code: &str) -> (Vec<String>, Option<String>) {
+        let exec = vm.execute_script(code, Some(5)).unwrap();
+        (exec.returns, exec.error)
+    }
+
+    #[test]
+    fn files_outside_are_rejected() {
+        let workspace_dir = tempfile::tempdir().unwrap();
+        let workspace = workspace_dir.path();
+        let outside_dir = tempfile::tempdir().unwrap();
+        let outside = outside_dir.path();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        fs::write(workspace.join("a.txt"), "inside").unwrap();
+        let vm = sandboxed(workspace, Vec::new());
+
+        let (returns, _) = run(&vm, "return io.open('a.txt'):read('*a')");
+        assert_eq!(returns, vec!["inside"]);
+        let secret = outside.join("secret.txt").display().to_string();
+        for code in [
+            format!("return io.open('{}')", secret),
+            "return io.open('../x.txt', 'w')".to_string(),
+            format!("return loadfile('{}')", secret),
+            format!("return os.remove('{}')", secret),
+        ] {
+            let (returns, error) = run(&vm, &code);
+            assert!(error.is_none(), "{}: {:?}", code, error);
+            assert_eq!(returns[0], "nil", "{}", code);
+            assert!(returns[1].contains("outside of the workspace"), "{}", code);
+        }
+        let (_, error) = run(&vm, &format!("return dofile('{}')", secret));
+        assert!(error.unwrap().contains("outside of the workspace"));
+        let (_, error) = run(
+            &vm,
+            "package.cpath = '/tmp/?.so'; return package.loadlib('x', 'y')",
+        );
+        assert!(error.is_some());
+        assert!(outside.join("secret.txt").exists());
+    }
+
+    #[test]
+    fn commands_are_disabled_by_default() {
+        let workspace_dir = tempfile::tempdir().unwrap();
+        let workspace = workspace_dir.path();
+        let mut config = Config {
+            path: Some(workspace.to_path_buf()),
+            ..Config::default()
+        };
+        config.lua.sandbox = true;
+        let vm = LuaVM::with_setup(config.lua_setup()).unwrap();
+
+        // Luau has no io.popen.
+        if cfg!(not(feature = "luau")) {
+            let (returns, error) = run(&vm, "return io.popen('echo hi')");
+            assert!(error.is_none(), "{:?}", error);
+            assert_eq!(returns, vec!["nil", "sandbox: io.popen is disabled"]);
+        }
+        let (returns, error) = run(&vm, "return onui.exec('echo hi')");
+        assert!(error.is_none(), "{:?}", error);
+        assert_eq!(returns[0], "nil");
+        assert!(returns[1].contains("disabled"), "{:?}", returns);
+    }
+
+    #[test]
+    fn require_is_limited_to_the_sandbox() {
+        let workspace_dir = tempfile::tempdir().unwrap();
+        let workspace = workspace_dir.path();
+        let outside_dir = tempfile::tempdir().unwrap();
+        let outside = outside_dir.path();
+        let lib_dir = tempfile::tempdir().unwrap();
+        let lib = lib_dir.path();
+        fs::write(outside.join("rv_outside.lua"), "return 'escaped'").unwrap();
+        fs::write(lib.join("rv_lib.lua"), "return 'lib'").unwrap();
+        fs::write(workspace.join("rv_inside.lua"), "return 'inside'").unwrap();
+        let lib_path = format!("{}/?.lua", lib.display());
+        let vm = sandboxed(workspace, vec![lib_path]);
+
+        // Paths of the setup are trusted.
+        let (returns, error) = run(&vm, "return (require('rv_lib'))");
+        assert_eq!(returns, vec!["lib"], "{:?}", error);
+        let ws_path = format!("{}/?.lua", workspace.display());
+        let (returns, error) = run(
+            &vm,
+            &format!(
+                "package.path = '{}'; return (require('rv_inside'))",
+                ws_path
+            ),
+        );
+        assert_eq!(returns, vec!["inside"], "{:?}", error);
+
+        let out_path = format!("{}/?.lua", outside.display());
+        let (_, error) = run(
+            &vm,
+            &format!(
+                "package.path = '{}'; return require('rv_outside')",
+                out_path
+            ),
+        );
+        assert!(error.unwrap().contains("outside of the workspace"));
+        let (_, error) = run(
+            &vm,
+            &format!(
+                "package.cpath = '{}/?.so'; return require('rv_c')",
+                outside.display()
+            ),
+        );
+        assert!(error.is_some());
+    }
+}
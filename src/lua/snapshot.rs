@@ -0,0 +1,478 @@
+//! Snapshot of Lua globals, serialized as Lua source.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, c_void};
+
+use mlua::{Function, Lua, Table, Value, ffi};
+
+/// Maximum depth of nested tables in a snapshot.
+const MAX_DEPTH: usize = 64;
+
+/// Snapshot of the global environment.
+pub struct Snapshot {
+    /// Lua source, which restores the globals when executed.
+    pub source: String,
+    /// Globals which could not be serialized, with the reason.
+    pub skipped: Vec<(String, String)>,
+    /// Globals saved without the metatables of their tables.
+    pub metatables: Vec<String>,
+}
+
+/// Context of serialization.
+struct Dumper<'a> {
+    lua: &'a Lua,
+    /// Source of executed chunks, by chunk name.
+    sources: &'a HashMap<String, String>,
+    /// Tables being serialized, to detect cycles.
+    path: Vec<*const c_void>,
+    /// Whether a table with a metatable is found in the current global.
+    has_metatable: bool,
+}
+
+/// Serialize globals not in builtins.
+/// Plain data (tables, strings, numbers and booleans) is kept,
+/// and functions whose source is recorded in sources, without upvalues
+/// except `_ENV` of the globals in Lua 5.2+.
+pub fn dump_globals(
+    lua: &Lua,
+    builtins: &HashSet<String>,
+    sources: &HashMap<String, String>,
+) -> mlua::Result<Snapshot> {
+    let mut globals = Vec::new();
+    for pair in lua.globals().pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let Value::String(name) = key else {
+            continue;
+        };
+        let name = name.to_string_lossy();
+        if builtins.contains(&name) {
+            continue;
+        }
+        globals.push((name, value));
+    }
+    globals.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut dumper = Dumper {
+        lua,
+        sources,
+        path: Vec::new(),
+        has_metatable: false,
+    };
+    let mut source = String::from("-- onui VM snapshot\n");
+    let mut skipped = Vec::new();
+    let mut metatables = Vec::new();
+    for (name, value) in globals {
+        dumper.has_metatable = false;
+        match dumper.value(&value) {
+            Ok(text) => {
+                let lhs = format!("_G[{}]", quote(name.as_bytes()));
+                source.push_str(&assign(&lhs, &text));
+                source.push('\n');
+                if dumper.has_metatable {
+                    metatables.push(name);
+                }
+            }
+            Err(reason) => skipped.push((name, reason)),
+        }
+    }
+    Ok(Snapshot {
+        source,
+        skipped,
+        metatables,
+    })
+}
+
+impl Dumper<'_> {
+    /// Serialize a value as a Lua expression, or returns why it cannot be.
+    fn value(&mut self, value: &Value) -> Result<String, String> {
+        match value {
+            Value::Nil => Ok("nil".to_string()),
+            Value::Boolean(b) => Ok(b.to_string()),
+            Value::Integer(i) => Ok(i.to_string()),
+            Value::Number(n) => Ok(dump_number(*n)),
+            Value::String(s) => Ok(quote(&s.as_bytes())),
+            Value::Table(table) => self.table(table),
+            // Functions start on a new line, to be found by line numbers when restored.
+            Value::Function(function) => Ok(format!("\n{}", self.function(function)?)),
+            other => Err(other.type_name().to_string()),
+        }
+    }
+
+    fn table(&mut self, table: &Table) -> Result<String, String> {
+        let ptr = table.to_pointer();
+        if self.path.contains(&ptr) {
+            return Err("cyclic table".to_string());
+        }
+        if self.path.len() >= MAX_DEPTH {
+            return Err("too deeply nested table".to_string());
+        }
+        // Metatables may hold functions of any kind, so they are not kept.
+        if table.metatable().is_some() {
+            self.has_metatable = true;
+        }
+        self.path.push(ptr);
+        let result = self.table_entries(table);
+        self.path.pop();
+        result
+    }
+
+    fn table_entries(&mut self, table: &Table) -> Result<String, String> {
+        // Sequence part is written without keys.
+        let len = table.raw_len();
+        let mut items = Vec::new();
+        for i in 1..=len {
+            let value: Value = table.raw_get(i).map_err(|err| err.to_string())?;
+            items.push(self.value(&value)?);
+        }
+
+        let mut entries = Vec::new();
+        for pair in table.clone().pairs::<Value, Value>() {
+            let (key, value) = pair.map_err(|err| err.to_string())?;
+            if let Value::Integer(i) = key
+                && i >= 1
+                && (i as usize) <= len
+            {
+                continue;
+            }
+            if let Value::Number(n) = key
+                && n.fract() == 0.0
+                && n >= 1.0
+                && (n as usize) <= len
+            {
+                continue;
+            }
+            let key = match key {
+                Value::Boolean(_) | Value::Integer(_) | Value::Number(_) | Value::String(_) => {
+                    self.value(&key)?
+                }
+                other => return Err(format!("table with {} key", other.type_name())),
+            };
+            entries.push((key, self.value(&value)?));
+        }
+        entries.sort();
+        items.extend(
+            entries
+                .into_iter()
+                .map(|(key, value)| assign(&format!("[{}]", key), &value)),
+        );
+
+        if items.is_empty() {
+            Ok("{}".to_string())
+        } else {
+            Ok(format!("{{ {} }}", items.join(", ")))
+        }
+    }
+
+    /// Recover the source of a function from the recorded chunk.
+    fn function(&self, function: &Function) -> Result<String, String> {
+        let info = function.info();
+        if info.what != "Lua" {
+            return Err("builtin function".to_string());
+        }
+        if info.num_upvalues > 1 || (info.num_upvalues == 1 && !self.only_globals_env(function)) {
+            return Err("function with upvalues".to_string());
+        }
+        let not_recorded = || "function of unknown source".to_string();
+        let chunk = info
+            .source
+            .as_ref()
+            .and_then(|name| self.sources.get(name))
+            .ok_or_else(not_recorded)?;
+        let (Some(first), Some(last)) = (info.line_defined, info.last_line_defined) else {
+            return Err(not_recorded());
+        };
+        let lines: Vec<&str> = chunk
+            .lines()
+            .skip(first.saturating_sub(1))
+            .take(last.saturating_sub(first) + 1)
+            .collect();
+        if lines.is_empty() {
+            return Err(not_recorded());
+        }
+        self.find_function_expr(&lines)
+            .ok_or_else(|| "function whose source cannot be found".to_string())
+    }
+
+    /// Check whether the single upvalue of the function is `_ENV` of the globals,
+    /// which functions using globals have in Lua 5.2+.
+    fn only_globals_env(&self, function: &Function) -> bool {
+        let globals = self.lua.globals().to_pointer();
+        let mut is_env = false;
+        // SAFETY: the function is the only argument, at index 1. The upvalue pushed by
+        // lua_getupvalue is popped, and the name is copied before that.
+        let result = unsafe {
+            self.lua.exec_raw::<()>(function, |state| {
+                let name = ffi::lua_getupvalue(state, 1, 1);
+                if name.is_null() {
+                    return;
+                }
+                is_env = CStr::from_ptr(name).to_bytes() == b"_ENV"
+                    && ffi::lua_topointer(state, -1) == globals;
+                ffi::lua_pop(state, 1);
+            })
+        };
+        result.is_ok() && is_env
+    }
+
+    /// Find the function definition in lines, as an anonymous function expression.
+    /// The definition starts at some `function` of the first line, and ends at
+    /// some `end` of the last line. Candidates are checked by compiling them.
+    fn find_function_expr(&self, lines: &[&str]) -> Option<String> {
+        let first = lines[0];
+        let last = lines[lines.len() - 1];
+        let starts: Vec<usize> = first.match_indices("function").map(|(i, _)| i).collect();
+        let ends: Vec<usize> = last.match_indices("end").map(|(i, _)| i + 3).collect();
+
+        // A multi-line function likely starts at the last `function` of the line.
+        let starts: Vec<usize> = if lines.len() > 1 {
+            starts.into_iter().rev().collect()
+        } else {
+            starts
+        };
+        for &start in &starts {
+            for &end in &ends {
+                let text = if lines.len() == 1 {
+                    if end <= start {
+                        continue;
+                    }
+                    first[start..end].to_string()
+                } else {
+                    let mut text = first[start..].to_string();
+                    for line in &lines[1..lines.len() - 1] {
+                        text.push('\n');
+                        text.push_str(line);
+                    }
+                    text.push('\n');
+                    text.push_str(&last[..end]);
+                    text
+                };
+                let Some(expr) = anonymous_function(&text) else {
+                    continue;
+                };
+                let compiles = self
+                    .lua
+                    .load(format!("return {}", expr))
+                    .into_function()
+                    .is_ok();
+                if compiles {
+                    return Some(expr);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Returns `lhs = value`, without trailing spaces before a new line.
+fn assign(lhs: &str, value: &str) -> String {
+    if value.starts_with('\n') {
+        format!("{} ={}", lhs, value)
+    } else {
+        format!("{} = {}", lhs, value)
+    }
+}
+
+/// Convert `function name(args) ... end` to `function(args) ... end`.
+/// Methods `function a:b(args)` take `self` explicitly.
+fn anonymous_function(text: &str) -> Option<String> {
+    let rest = text.strip_prefix("function")?;
+    let paren = rest.find('(')?;
+    let name = rest[..paren].trim();
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == ':')
+    {
+        return None;
+    }
+    let params = &rest[paren + 1..];
+    if name.contains(':') {
+        let sep = if params.trim_start().starts_with(')') {
+            ""
+        } else {
+            ", "
+        };
+        Some(format!("function(self{}{}", sep, params))
+    } else {
+        Some(format!("function({}", params))
+    }
+}
+
+pub(super) fn dump_number(n: f64) -> String {
+    if n.is_nan() {
+        "(0/0)".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "math.huge" } else { "-math.huge" }.to_string()
+    } else if n.fract() == 0.0 && n.abs() < 1e15 {
+        // Keep integers as integers, also in Lua 5.3+.
+        format!("{}", n as i64)
+    } else {
+        format!("{:?}", n)
+    }
+}
+
+/// Quote bytes as a Lua string literal.
+pub(super) fn quote(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() + 2);
+    out.push('"');
+    for &b in bytes {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(b as char),
+            // Decimal escapes are valid for all Lua versions.
+            _ => out.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua::{LuaSetup, LuaVM};
+
+    fn vm() -> LuaVM {
+        LuaVM::with_setup(LuaSetup::default()).unwrap()
+    }
+
+    fn run(vm: &LuaVM, code: &str) -> Vec<String> {
+        let exec = vm.execute_script(code, Some(5)).unwrap();
+        assert!(exec.error.is_none(), "{:?}", exec.error);
+        exec.returns
+    }
+
+    #[test]
+    fn restores_data_and_functions() {
+        let old = vm();
+        run(
+            &old,
+            "config = { name = 'a\\n\"b\"', list = { 1, 2.5, true }, [10] = 'x' }\n\
+             function greet(n) return 'hi ' .. tostring(n) end\n\
+             local t = {}\n\
+             function t.add(a, b) return a + b end\n\
+             adder = t.add\n\
+             function t:name() return 'n' end\n\
+             named = t.name",
+        );
+        let snapshot = old.snapshot().unwrap();
+        assert!(snapshot.skipped.is_empty(), "{:?}", snapshot.skipped);
+
+        let new = vm();
+        new.restore(&snapshot.source).unwrap();
+        let returns = run(
+            &new,
+            "return config.name, #config.list, config.list[2], config[10], \
+             greet(1), adder(1, 2), named({})",
+        );
+        assert_eq!(returns, vec!["a\n\"b\"", "3", "2.5", "x", "hi 1", "3", "n"]);
+    }
+
+    #[test]
+    fn skips_unserializable_values() {
+        let old = vm();
+        run(
+            &old,
+            "local count = 0\n\
+             function counter() count = count + 1 return count end\n\
+             cyclic = {}\n\
+             cyclic.self = cyclic\n\
+             builtin = print\n\
+             kept = 1",
+        );
+        let snapshot = old.snapshot().unwrap();
+        let mut skipped: Vec<(&str, &str)> = snapshot
+            .skipped
+            .iter()
+            .map(|(name, reason)| (name.as_str(), reason.as_str()))
+            .collect();
+        skipped.sort();
+        assert_eq!(
+            skipped,
+            vec![
+                ("builtin", "builtin function"),
+                ("counter", "function with upvalues"),
+                ("cyclic", "cyclic table"),
+            ]
+        );
+        assert!(snapshot.source.contains("_G[\"kept\"] = 1"));
+        assert!(snapshot.metatables.is_empty());
+    }
+
+    #[test]
+    fn reports_dropped_metatables() {
+        let old = vm();
+        run(
+            &old,
+            "point = setmetatable({ x = 1 }, { __index = function() return 0 end })\n\
+             nested = { inner = setmetatable({}, {}) }\n\
+             plain = { 1, 2 }",
+        );
+        let snapshot = old.snapshot().unwrap();
+        assert_eq!(snapshot.metatables, vec!["nested", "point"]);
+
+        let new = vm();
+        new.restore(&snapshot.source).unwrap();
+        let returns = run(&new, "return point.x, point.y, getmetatable(point)");
+        assert_eq!(returns, vec!["1", "nil", "nil"]);
+    }
+
+    #[test]
+    fn restores_under_the_timeout() {
+        let vm = vm();
+        let start = std::time::Instant::now();
+        let err = vm.restore("while true do end").unwrap_err();
+        assert!(start.elapsed().as_secs() < 30);
+        assert!(err.to_string().contains("time limit"), "{}", err);
+        // The VM is usable after that.
+        assert_eq!(run(&vm, "return 1"), vec!["1"]);
+    }
+
+    #[test]
+    fn globals_of_tool_files_are_builtins() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("t.lua"),
+            "tool_helper = {}\nreturn { handler = function() end }",
+        )
+        .unwrap();
+        let vm = LuaVM::with_setup(LuaSetup {
+            tool_dir: Some(tmp.path().to_path_buf()),
+            ..LuaSetup::default()
+        })
+        .unwrap();
+        run(&vm, "mine = 1");
+        let snapshot = vm.snapshot().unwrap();
+        assert!(!snapshot.source.contains("tool_helper"));
+        assert!(snapshot.source.contains("_G[\"mine\"] = 1"));
+    }
+
+    #[test]
+    fn converts_named_functions() {
+        assert_eq!(
+            anonymous_function("function a.b(x) end").as_deref(),
+            Some("function(x) end")
+        );
+        assert_eq!(
+            anonymous_function("function a:b() end").as_deref(),
+            Some("function(self) end")
+        );
+        assert_eq!(
+            anonymous_function("function a:b(x) end").as_deref(),
+            Some("function(self, x) end")
+        );
+        assert_eq!(anonymous_function("function a[1](x) end"), None);
+    }
+
+    #[test]
+    fn dumps_numbers_and_strings() {
+        assert_eq!(dump_number(3.0), "3");
+        assert_eq!(dump_number(0.5), "0.5");
+        assert_eq!(dump_number(f64::INFINITY), "math.huge");
+        assert_eq!(dump_number(f64::NAN), "(0/0)");
+        assert_eq!(quote(b"a\"\\\n\x01\xff"), "\"a\\\"\\\\\\n\\001\\255\"");
+    }
+}
@@ -0,0 +1,185 @@
+//! Concurrent host calls. `onui.await_all` runs functions in coroutines,
+//! which wait for calls like `onui.exec` together, bridged to tokio.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures_util::future::join_all;
+use mlua::{FromLuaMulti, Function, IntoLuaMulti, Lua, MultiValue, Table, Value};
+
+use super::is_fatal;
+
+/// Registry key of the set of task threads, whose keys are weak.
+const TASKS_KEY: &str = "onui_tasks";
+
+/// Selects the async variant of a host function in tasks, and the blocking one elsewhere.
+const DUAL: &str = r#"
+local tasks, blocking, async = ...
+local running = coroutine.running
+return function(...)
+  if tasks[running()] then
+    return async(...)
+  end
+  return blocking(...)
+end
+"#;
+
+/// Deadline of the running script, shared with host calls to bound their waits,
+/// which the timeout hook cannot interrupt.
+#[derive(Clone, Debug, Default)]
+pub struct Deadline(Rc<Cell<Option<Instant>>>);
+
+impl Deadline {
+    pub fn set(&self, deadline: Option<Instant>) {
+        self.0.set(deadline);
+    }
+
+    /// Bound the timeout of a host call by the time left to the script.
+    /// Without both, the call has no timeout.
+    pub fn limit(&self, timeout: Option<Duration>) -> Option<Duration> {
+        let remaining = self
+            .0
+            .get()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match (timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        }
+    }
+}
+
+/// Run the future to the end, from the Lua VM which runs synchronously.
+pub fn block_on<F: Future>(future: F) -> Result<F::Output> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => Ok(tokio::task::block_in_place(|| handle.block_on(future))),
+        Err(_) => Ok(tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(future)),
+    }
+}
+
+/// Create a host function, which blocks until the future ends,
+/// or lets other tasks run while waiting in `onui.await_all`.
+pub fn create_function<F, A, FR, R>(lua: &Lua, func: F) -> mlua::Result<Function>
+where
+    F: Fn(Lua, A) -> FR + Clone + 'static,
+    A: FromLuaMulti,
+    FR: Future<Output = mlua::Result<R>> + 'static,
+    R: IntoLuaMulti,
+{
+    let blocking_func = func.clone();
+    let blocking = lua.create_function(move |lua, args: A| {
+        block_on(blocking_func(lua.clone(), args)).map_err(mlua::Error::external)?
+    })?;
+    let async_func = lua.create_async_function(func)?;
+    let tasks: Table = lua.named_registry_value(TASKS_KEY)?;
+    lua.load(DUAL)
+        .set_name("onui-tasks")
+        .call((tasks, blocking, async_func))
+}
+
+/// Returns the message of the error raised in a task, without the traceback.
+fn error_message(err: &mlua::Error) -> String {
+    let msg = match err {
+        mlua::Error::RuntimeError(msg) => msg.clone(),
+        mlua::Error::CallbackError { cause, .. } => cause.to_string(),
+        other => other.to_string(),
+    };
+    match msg.split_once("\nstack traceback:") {
+        Some((msg, _)) => msg.to_string(),
+        None => msg,
+    }
+}
+
+/// Add `onui.await_all` to the `onui` table.
+/// It must be called before creating functions with `create_function`.
+pub fn register(lua: &Lua) -> mlua::Result<()> {
+    let tasks = lua.create_table()?;
+    let weak = lua.create_table()?;
+    weak.set("__mode", "k")?;
+    tasks.set_metatable(Some(weak))?;
+    lua.set_named_registry_value(TASKS_KEY, &tasks)?;
+
+    // onui.await_all{ f1, f2, ... } runs the functions as tasks, which wait for
+    // host calls like onui.exec and onui.http.request together.
+    // Returns the first values of the functions, and errors by index if any.
+    // Errors are raised by the functions, or returned as `nil, reason`.
+    let await_all = lua.create_function(move |lua, list: Table| {
+        if tasks.contains_key(lua.current_thread())? {
+            return Err(mlua::Error::RuntimeError(
+                "onui.await_all cannot be called in tasks".to_string(),
+            ));
+        }
+        let mut threads = Vec::new();
+        for value in list.sequence_values::<Value>() {
+            let Value::Function(func) = value? else {
+                return Err(mlua::Error::RuntimeError(
+                    "onui.await_all takes a list of functions".to_string(),
+                ));
+            };
+            let thread = lua.create_thread(func)?;
+            tasks.raw_set(&thread, true)?;
+            threads.push(thread.into_async::<MultiValue>(())?);
+        }
+        let outcomes = block_on(join_all(threads)).map_err(mlua::Error::external)?;
+
+        let results = lua.create_table()?;
+        let errors = lua.create_table()?;
+        let mut failed = false;
+        for (idx, outcome) in outcomes.into_iter().enumerate() {
+            let index = idx + 1;
+            match outcome {
+                Ok(values) => {
+                    let mut values = values.into_iter();
+                    let first = values.next().unwrap_or(Value::Nil);
+                    if first.is_nil()
+                        && let Some(reason) = values.next()
+                        && !reason.is_nil()
+                    {
+                        errors.raw_set(index, reason)?;
+                        failed = true;
+                    }
+                    results.raw_set(index, first)?;
+                }
+                Err(err) if is_fatal(&err) => return Err(err),
+                Err(err) => {
+                    errors.raw_set(index, error_message(&err))?;
+                    failed = true;
+                }
+            }
+        }
+        let errors = if failed {
+            Value::Table(errors)
+        } else {
+            Value::Nil
+        };
+        Ok((results, errors))
+    })?;
+
+    let onui: Table = lua.globals().get("onui")?;
+    onui.set("await_all", await_all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_limits_timeouts() {
+        let deadline = Deadline::default();
+        let sec = Duration::from_secs;
+        assert_eq!(deadline.limit(None), None);
+        assert_eq!(deadline.limit(Some(sec(5))), Some(sec(5)));
+
+        deadline.set(Some(Instant::now() + sec(2)));
+        assert!(deadline.limit(None).unwrap() <= sec(2));
+        assert!(deadline.limit(Some(sec(5))).unwrap() <= sec(2));
+        assert!(deadline.limit(Some(Duration::from_millis(10))).unwrap() <= sec(1));
+
+        deadline.set(Some(Instant::now() - sec(1)));
+        assert_eq!(deadline.limit(Some(sec(5))), Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,199 @@
+//! User-defined tools, whose handlers are Lua functions.
+
+use std::{fs, path::Path};
+
+use mlua::{Function, Lua, LuaSerdeExt, Table, Value};
+use serde_json::json;
+
+use crate::config::ToolConfig;
+use crate::llm::ToolSpec;
+use crate::workspace;
+
+/// Name of the builtin tool, which cannot be redefined.
+const RESERVED_NAME: &str = "lua";
+
+/// A tool registered to a LuaVM.
+pub struct LuaTool {
+    pub spec: ToolSpec,
+    /// Whether calls run without asking the user.
+    pub auto_approve: bool,
+    pub timeout_sec: Option<u64>,
+    pub(super) handler: Function,
+}
+
+/// Tool names are sent to the LLM as function names.
+fn check_name(name: &str, tools: &[LuaTool]) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(format!("invalid tool name '{}'", name));
+    }
+    if name == RESERVED_NAME || workspace::is_tool(name) {
+        return Err(format!("tool name '{}' is reserved", name));
+    }
+    if tools.iter().any(|t| t.spec.name == name) {
+        return Err(format!("tool '{}' is defined twice", name));
+    }
+    Ok(())
+}
+
+fn default_parameters() -> serde_json::Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+/// Load a tool from `[tools.<name>]` in the config.
+/// The handler is the body of a function, which takes `args`.
+pub fn load_config_tool(
+    lua: &Lua,
+    name: &str,
+    config: &ToolConfig,
+    tools: &[LuaTool],
+) -> Result<LuaTool, String> {
+    check_name(name, tools)?;
+    let source = format!("return function(args)\n{}\nend", config.handler);
+    let handler: Function = lua
+        .load(source)
+        .set_name(format!("onui-tool-{}", name))
+        .eval()
+        .map_err(|err| format!("tool '{}': {}", name, err))?;
+    Ok(LuaTool {
+        spec: ToolSpec {
+            name: name.to_string(),
+            description: config.description.clone(),
+            parameters: config.parameters.clone().unwrap_or_else(default_parameters),
+        },
+        auto_approve: config.auto_approve,
+        timeout_sec: config.timeout_sec,
+        handler,
+    })
+}
+
+/// Load a tool from a Lua file, which returns a table of
+/// `name` (file name by default), `description`, `parameters`,
+/// `timeout_sec` and `handler`.
+/// Calls always need approval, since files in the workspace may be written by anyone,
+/// including the LLM; `auto_approve` is honoured only in the config.
+pub fn load_file_tool(lua: &Lua, path: &Path, tools: &[LuaTool]) -> Result<LuaTool, String> {
+    let fail = |err: String| format!("{}: {}", path.display(), err);
+    let source = fs::read_to_string(path).map_err(|err| fail(err.to_string()))?;
+    let def: Table = lua
+        .load(source)
+        .set_name(format!("@{}", path.display()))
+        .eval()
+        .map_err(|err| fail(err.to_string()))?;
+
+    let file_name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = def
+        .get::<Option<String>>("name")
+        .map_err(|err| fail(err.to_string()))?
+        .unwrap_or(file_name);
+    check_name(&name, tools).map_err(fail)?;
+
+    let description = def
+        .get::<Option<String>>("description")
+        .map_err(|err| fail(err.to_string()))?
+        .unwrap_or_default();
+    let parameters = match def.get::<Value>("parameters") {
+        Ok(Value::Nil) => default_parameters(),
+        Ok(value) => lua
+            .from_value(value)
+            .map_err(|err| fail(format!("invalid parameters: {}", err)))?,
+        Err(err) => return Err(fail(err.to_string())),
+    };
+    let handler: Function = def
+        .get("handler")
+        .map_err(|_| fail("handler function is required".to_string()))?;
+
+    Ok(LuaTool {
+        spec: ToolSpec {
+            name,
+            description,
+            parameters,
+        },
+        auto_approve: false,
+        timeout_sec: def.get("timeout_sec").unwrap_or(None),
+        handler,
+    })
+}
+
+/// List `*.lua` files in the directory, sorted by name.
+pub fn tool_files(dir: &Path) -> Vec<std::path::PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "lua"))
+        .collect();
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::lua::{LuaSetup, LuaVM};
+
+    fn load(dir: &Path) -> LuaVM {
+        LuaVM::with_setup(LuaSetup {
+            tool_dir: Some(dir.to_path_buf()),
+            ..LuaSetup::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn checks_names() {
+        assert!(check_name("add_1-b", &[]).is_ok());
+        assert!(check_name("", &[]).is_err());
+        assert!(check_name("a b", &[]).is_err());
+        assert!(check_name("lua", &[]).is_err());
+        assert!(check_name("read_file", &[]).is_err());
+    }
+
+    #[test]
+    fn file_tools_are_not_auto_approved() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(
+            dir.join("add.lua"),
+            "return { description = 'Add', auto_approve = true, timeout_sec = 3,\n\
+             handler = function(args) return { sum = args.a + args.b } end }",
+        )
+        .unwrap();
+        let vm = load(dir);
+        assert!(vm.tool_errors().is_empty(), "{:?}", vm.tool_errors());
+        let tool = &vm.tools()[0];
+        assert_eq!(tool.spec.name, "add");
+        assert!(!tool.auto_approve);
+        assert_eq!(tool.timeout_sec, Some(3));
+        let exec = vm.call_tool("add", &json!({ "a": 1, "b": 2 })).unwrap();
+        assert_eq!(exec.returns, vec![r#"{"sum":3}"#]);
+    }
+
+    #[test]
+    fn looping_tool_files_time_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("a_loop.lua"), "while true do end").unwrap();
+        fs::write(dir.join("b_ok.lua"), "return { handler = function() end }").unwrap();
+        let start = Instant::now();
+        let vm = load(dir);
+        assert!(start.elapsed().as_secs() < 30);
+        assert_eq!(vm.tool_errors().len(), 1);
+        assert!(vm.tool_errors()[0].contains("a_loop.lua"));
+        assert_eq!(vm.tools().len(), 1);
+        // Scripts run without the hook of loading.
+        let exec = vm.execute_script("return 1", None).unwrap();
+        assert_eq!(exec.returns, vec!["1"]);
+    }
+}
@@ -0,0 +1,260 @@
+//! Error reports of scripts, with tracebacks mapped to the submitted source.
+
+use std::collections::HashMap;
+
+use mlua::{Function, Lua, MultiValue, Value};
+
+/// Chunk name of the runner.
+const RUNNER_NAME: &str = "onui-runner";
+
+/// Runs a chunk with `xpcall`, returning `true, ...` or `false, traceback`.
+/// Errors of Rust functions are kept as they are, to be classified.
+/// It takes `debug.traceback`, which is not available to scripts.
+const RUNNER: &str = r#"
+local traceback = ...
+local function handler(err)
+  if type(err) ~= "string" then
+    return err
+  end
+  return traceback(err, 2)
+end
+return function(chunk, ...)
+  return xpcall(chunk, handler, ...)
+end
+"#;
+
+/// Number of source lines shown around an error line.
+const CONTEXT_LINES: usize = 1;
+
+/// Message of Lua when an allocation fails.
+const MEMORY_ERROR_MESSAGE: &str = "not enough memory";
+
+/// Create the runner, which is kept out of globals.
+pub fn create_runner(lua: &Lua) -> mlua::Result<Function> {
+    let traceback =
+        lua.create_function(|lua, (msg, level): (String, usize)| lua.traceback(Some(&msg), level))?;
+    lua.load(RUNNER).set_name(RUNNER_NAME).call(traceback)
+}
+
+/// Convert results of the runner to the returned values or the error.
+pub fn runner_result(mut values: MultiValue) -> mlua::Result<MultiValue> {
+    match values.pop_front() {
+        Some(Value::Boolean(true)) => Ok(values),
+        _ => Err(match values.pop_front() {
+            Some(Value::Error(err)) => *err,
+            Some(Value::String(msg)) if msg.to_string_lossy() == MEMORY_ERROR_MESSAGE => {
+                mlua::Error::MemoryError(MEMORY_ERROR_MESSAGE.to_string())
+            }
+            Some(Value::String(msg)) => mlua::Error::RuntimeError(msg.to_string_lossy()),
+            Some(other) => mlua::Error::RuntimeError(format!(
+                "(error object is a {} value)",
+                other.type_name()
+            )),
+            None => mlua::Error::RuntimeError("unknown error".to_string()),
+        }),
+    }
+}
+
+/// A location `[string "name"]:line` in an error message.
+struct Location {
+    start: usize,
+    end: usize,
+    chunk: String,
+    line: usize,
+}
+
+fn find_locations(text: &str) -> Vec<Location> {
+    const PREFIX: &str = "[string \"";
+    let mut locations = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = text[pos..].find(PREFIX) {
+        let start = pos + found;
+        let name_start = start + PREFIX.len();
+        pos = name_start;
+        let Some(name_len) = text[name_start..].find("\"]:") else {
+            continue;
+        };
+        let chunk = &text[name_start..name_start + name_len];
+        let digits_start = name_start + name_len + 3;
+        let digits_len = text[digits_start..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len() - digits_start);
+        let Ok(line) = text[digits_start..digits_start + digits_len].parse() else {
+            continue;
+        };
+        let end = digits_start + digits_len;
+        locations.push(Location {
+            start,
+            end,
+            chunk: chunk.to_string(),
+            line,
+        });
+        pos = end;
+    }
+    locations
+}
+
+/// Returns the label of a recorded chunk, e.g. `script` for the latest script.
+fn chunk_label(chunk: &str, latest: &str) -> String {
+    if chunk == latest {
+        return "script".to_string();
+    }
+    match chunk.split_once('#') {
+        Some(("onui-agent", n)) => format!("script#{}", n),
+        Some(("onui-init", _)) => "init.lua".to_string(),
+        Some(("onui-snapshot", _)) => "snapshot".to_string(),
+        _ => chunk.to_string(),
+    }
+}
+
+/// Describe the error for the model.
+/// Chunk names of recorded sources are replaced with short labels,
+/// frames of the runner are removed, and the failing source lines are appended.
+pub fn format_error(err: &mlua::Error, sources: &HashMap<String, String>) -> String {
+    let message = match err {
+        mlua::Error::RuntimeError(msg) => msg.clone(),
+        mlua::Error::SyntaxError { message, .. } => format!("syntax error: {}", message),
+        other => other.to_string(),
+    };
+    let message: Vec<&str> = message
+        .lines()
+        .filter(|line| !line.contains(RUNNER_NAME) && !line.contains("in function 'xpcall'"))
+        .collect();
+    let message = message.join("\n");
+
+    // Chunk names are `<prefix>#<n>`, where n counts all recorded chunks.
+    let latest = sources
+        .keys()
+        .filter(|name| name.ends_with(&format!("#{}", sources.len())))
+        .max()
+        .cloned()
+        .unwrap_or_default();
+
+    let mut out = String::new();
+    let mut shown: Vec<(String, usize)> = Vec::new();
+    let mut pos = 0;
+    for loc in find_locations(&message) {
+        if !sources.contains_key(&loc.chunk) {
+            continue;
+        }
+        out.push_str(&message[pos..loc.start]);
+        out.push_str(&format!(
+            "{}:{}",
+            chunk_label(&loc.chunk, &latest),
+            loc.line
+        ));
+        pos = loc.end;
+        if !shown.iter().any(|(chunk, _)| *chunk == loc.chunk) {
+            shown.push((loc.chunk, loc.line));
+        }
+    }
+    out.push_str(&message[pos..]);
+
+    for (chunk, line) in shown {
+        let source = &sources[&chunk];
+        let lines: Vec<&str> = source.lines().collect();
+        if line == 0 || line > lines.len() {
+            continue;
+        }
+        let first = line.saturating_sub(CONTEXT_LINES).max(1);
+        let last = (line + CONTEXT_LINES).min(lines.len());
+        out.push_str(&format!("\n{}:\n", chunk_label(&chunk, &latest)));
+        for n in first..=last {
+            let marker = if n == line { ">" } else { " " };
+            out.push_str(&format!("{} {:>4} | {}\n", marker, n, lines[n - 1]));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources() -> HashMap<String, String> {
+        HashMap::from([
+            ("onui-init#1".to_string(), "local a = 1\n".to_string()),
+            (
+                "onui-agent#2".to_string(),
+                "local x = 1\nerror('boom')\nreturn x\n".to_string(),
+            ),
+        ])
+    }
+
+    #[test]
+    fn labels_chunks_and_shows_lines() {
+        let err = mlua::Error::RuntimeError(
+            "[string \"onui-agent#2\"]:2: boom\nstack traceback:\n\
+             \t[C]: in function 'error'\n\
+             \t[string \"onui-agent#2\"]:2: in main chunk\n\
+             \t[C]: in function 'xpcall'\n\
+             \t[string \"onui-runner\"]:9: in function <[string \"onui-runner\"]:8>"
+                .to_string(),
+        );
+        let text = format_error(&err, &sources());
+        assert_eq!(
+            text,
+            [
+                "script:2: boom",
+                "stack traceback:",
+                "\t[C]: in function 'error'",
+                "\tscript:2: in main chunk",
+                "script:",
+                "     1 | local x = 1",
+                ">    2 | error('boom')",
+                "     3 | return x",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_chunks() {
+        let err = mlua::Error::RuntimeError(
+            "[string \"onui-init#1\"]:1: a\n[string \"other\"]:3: b".to_string(),
+        );
+        let text = format_error(&err, &sources());
+        assert!(
+            text.starts_with("init.lua:1: a\n[string \"other\"]:3: b\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.ends_with("init.lua:\n>    1 | local a = 1\n"),
+            "{}",
+            text
+        );
+
+        let err = mlua::Error::SyntaxError {
+            message: "[string \"onui-agent#9\"]:1: unexpected symbol".to_string(),
+            incomplete_input: false,
+        };
+        assert_eq!(
+            format_error(&err, &sources()),
+            "syntax error: [string \"onui-agent#9\"]:1: unexpected symbol"
+        );
+    }
+
+    #[test]
+    fn converts_runner_results() {
+        let lua = Lua::new();
+        let runner = create_runner(&lua).unwrap();
+        let ok = lua.load("return 1, 2").into_function().unwrap();
+        let values = runner_result(runner.call(ok).unwrap()).unwrap();
+        assert_eq!(values.len(), 2);
+
+        let failing = lua.load("error('bad')").into_function().unwrap();
+        let err = runner_result(runner.call(failing).unwrap()).unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.contains("bad") && msg.contains("stack traceback"),
+            "{}",
+            msg
+        );
+
+        let table = lua.load("error({})").into_function().unwrap();
+        let err = runner_result(runner.call(table).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("error object is a table value"));
+    }
+}
@@ -1,19 +1,44 @@
 mod agent;
+mod audit;
 mod config;
 mod consts;
+mod export;
+mod highlight;
 mod io;
 mod llm;
 mod lua;
+mod output;
+mod patch;
+mod review;
+mod session;
+mod utils;
+mod workspace;
 
 use agent::{Agent, AgentHandler, AgentResources};
 use anyhow::Context;
-use io::{IO, cli::CliIO};
+use audit::AuditLog;
+use config::{Config, LLMConfig};
+use io::{IO, cli::CliIO, tui::TuiIO};
 use lua::LuaVM;
-use std::{process::exit, sync::Arc};
+use std::{io::IsTerminal, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+/// Time given to tasks to finish at exit. The line editor of CliIO blocks
+/// in a thread until a line is entered, which cannot be cancelled.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn main() -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("starting runtime")?;
+    let result = runtime.block_on(async_main());
+    // Exit, even all tasks are not finished yet.
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    result
+}
+
+async fn async_main() -> anyhow::Result<()> {
     let config = config::load_from_cli().context("loading configuration")?;
 
     let llm_config = config.llm.get(&config.default_llm).unwrap_or_else(|| {
@@ -23,22 +48,31 @@ async fn main() -> anyhow::Result<()> {
         )
     });
 
-    let lua = LuaVM::new().context("creating Lua VM")?;
-    let mut io = CliIO::new();
+    if config.tui {
+        run(&config, llm_config, TuiIO::new()).await?;
+    } else {
+        // Markdown is rendered only for terminals.
+        let plain = config.plain || !std::io::stdout().is_terminal();
+        run(&config, llm_config, CliIO::new(plain)).await?;
+    }
+    Ok(())
+}
+
+async fn run<I: IO>(config: &Config, llm_config: &LLMConfig, mut io: I) -> anyhow::Result<()> {
+    let lua = LuaVM::with_setup(config.lua_setup()).context("creating Lua VM")?;
     let io_chan = io.open().context("opening IO")?;
 
-    let resources = AgentResources::new();
+    let audit = AuditLog::new(audit::audit_path(&config.workspace_dir()));
+    let resources = AgentResources::new(audit);
     let resources = Arc::new(Mutex::new(resources));
     let handler = Box::new(AgentHandler::new(
+        config,
         resources.clone(),
         io_chan.output_tx.clone(),
     ));
-    let llm = llm::instantiate(&llm_config, handler).context("instantiating LLM client")?;
+    let llm = llm::instantiate(llm_config, handler).context("instantiating LLM client")?;
 
-    let mut agent = Agent::new(&config, llm, lua, resources, io, io_chan);
+    let mut agent = Agent::new(config, llm, lua, resources, io, io_chan);
 
-    agent.run().await.context("running agent")?;
-
-    // Exit, even all tasks are not finished yet.
-    exit(0);
+    agent.run().await.context("running agent")
 }
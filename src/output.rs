@@ -0,0 +1,126 @@
+//! Truncation of large outputs, whose full text is saved in the workspace.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+/// Returns the directory of saved outputs, in the workspace.
+pub fn outputs_dir(workspace: &Path) -> PathBuf {
+    workspace.join(".onui").join("outputs")
+}
+
+/// Returns the path of the saved output.
+pub fn output_path(workspace: &Path, id: &str) -> PathBuf {
+    outputs_dir(workspace).join(file_name(id))
+}
+
+/// Returns the file name of the saved output. Characters not allowed in ids are replaced.
+pub fn file_name(id: &str) -> String {
+    let name: String = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}.txt", name)
+}
+
+/// Save the full output of the call.
+pub fn save(workspace: &Path, id: &str, text: &str) -> Result<PathBuf> {
+    let path = output_path(workspace, id);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, text)?;
+    Ok(path)
+}
+
+/// Returns the largest index not after max, at a char boundary.
+fn floor_boundary(text: &str, max: usize) -> usize {
+    let mut idx = max.min(text.len());
+    while !text.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+/// Keep the first head bytes and the last tail bytes, cut at line ends if possible.
+/// Returns None if the text is short enough.
+pub fn truncate(text: &str, head: usize, tail: usize) -> Option<(String, String, usize)> {
+    if text.len() <= head + tail {
+        return None;
+    }
+    let mut head_end = floor_boundary(text, head);
+    if let Some(pos) = text[..head_end].rfind('\n') {
+        head_end = pos + 1;
+    }
+    let mut tail_start = floor_boundary(text, text.len() - tail);
+    if let Some(pos) = text[tail_start..].find('\n')
+        && tail_start + pos + 1 < text.len()
+    {
+        tail_start += pos + 1;
+    }
+    let omitted = tail_start - head_end;
+    Some((
+        text[..head_end].to_string(),
+        text[tail_start..].to_string(),
+        omitted,
+    ))
+}
+
+/// Read lines of a saved output, returning the text and the total number of lines.
+/// start_line is 1-based.
+pub fn read_lines(path: &Path, start_line: usize, count: usize) -> Result<(String, usize)> {
+    let content = fs::read_to_string(path)?;
+    let total = content.lines().count();
+    let mut out = String::new();
+    for line in content.lines().skip(start_line.max(1) - 1).take(count) {
+        out.push_str(line);
+        out.push('\n');
+    }
+    Ok((out, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_not_truncated() {
+        assert_eq!(truncate("abc\n", 2, 2), None);
+        assert_eq!(truncate("", 0, 0), None);
+    }
+
+    #[test]
+    fn truncates_at_line_ends() {
+        let text = "line1\nline2\nline3\nline4\nline5\n";
+        let (head, tail, omitted) = truncate(text, 8, 8).unwrap();
+        assert_eq!(head, "line1\n");
+        assert_eq!(tail, "line5\n");
+        assert_eq!(head.len() + omitted + tail.len(), text.len());
+    }
+
+    #[test]
+    fn truncates_at_char_boundaries() {
+        let text = "가나다라마바사";
+        let (head, tail, omitted) = truncate(text, 4, 4).unwrap();
+        assert_eq!(head, "가");
+        assert_eq!(tail, "바사");
+        assert_eq!(head.len() + omitted + tail.len(), text.len());
+    }
+
+    #[test]
+    fn maps_ids_to_file_names() {
+        assert_eq!(file_name("call_1-a"), "call_1-a.txt");
+        assert_eq!(file_name("../x:y"), "___x_y.txt");
+        assert_eq!(
+            output_path(Path::new("/ws"), "id"),
+            Path::new("/ws/.onui/outputs/id.txt")
+        );
+    }
+}
@@ -0,0 +1,609 @@
+//! Parse and apply unified diffs.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::utils::{now_unix, timestamp_id};
+
+/// A line of a hunk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Hunk {
+    /// 1-based line of the old file, 0 for an empty file.
+    pub old_start: usize,
+    pub lines: Vec<HunkLine>,
+    /// The new file has no newline at the end, after this hunk.
+    pub no_newline: bool,
+}
+
+impl Hunk {
+    /// Lines expected in the old file.
+    fn old_lines(&self) -> Vec<&str> {
+        side(&self.lines, true)
+    }
+}
+
+/// Changes of a file. Paths are `None` for `/dev/null`, i.e. created or deleted files.
+#[derive(Clone, Debug)]
+pub struct FilePatch {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    /// Path of the file to be modified.
+    pub fn path(&self) -> &str {
+        self.new_path
+            .as_deref()
+            .or(self.old_path.as_deref())
+            .unwrap_or_default()
+    }
+
+    pub fn is_deletion(&self) -> bool {
+        self.new_path.is_none()
+    }
+}
+
+/// Parse `--- a/path`, `+++ b/path` headers, stripping `a/`, `b/` and timestamps.
+fn parse_path(header: &str) -> Option<String> {
+    let path = header.split('\t').next().unwrap_or_default().trim();
+    if path == "/dev/null" {
+        return None;
+    }
+    let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path);
+    Some(path.to_string())
+}
+
+/// Parse `@@ -l,s +l,s @@`, returning the old start and sizes of both sides.
+fn parse_hunk_header(line: &str) -> Option<(usize, usize, usize)> {
+    let rest = line.strip_prefix("@@ -")?;
+    let (ranges, _) = rest.split_once(" @@")?;
+    let (old, new) = ranges.split_once(" +")?;
+    let range = |text: &str| -> Option<(usize, usize)> {
+        match text.split_once(',') {
+            Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+            None => Some((text.parse().ok()?, 1)),
+        }
+    };
+    let (old_start, old_len) = range(old)?;
+    let (_, new_len) = range(new)?;
+    Some((old_start, old_len, new_len))
+}
+
+/// Parse a unified diff, which may change multiple files.
+pub fn parse(text: &str) -> Result<Vec<FilePatch>> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut patches: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if let Some(old) = line.strip_prefix("--- ")
+            && let Some(new) = lines.get(i + 1).and_then(|l| l.strip_prefix("+++ "))
+        {
+            patches.push(FilePatch {
+                old_path: parse_path(old),
+                new_path: parse_path(new),
+                hunks: Vec::new(),
+            });
+            i += 2;
+            continue;
+        }
+        if line.starts_with("@@") {
+            let (old_start, mut old_len, mut new_len) = parse_hunk_header(line)
+                .ok_or_else(|| anyhow!("invalid hunk header at line {}: {}", i + 1, line))?;
+            let patch = patches
+                .last_mut()
+                .ok_or_else(|| anyhow!("hunk without file header at line {}", i + 1))?;
+            let mut hunk = Hunk {
+                old_start,
+                lines: Vec::new(),
+                no_newline: false,
+            };
+            i += 1;
+            while i < lines.len() && (old_len > 0 || new_len > 0) {
+                let line = lines[i];
+                match line.chars().next() {
+                    Some('+') => {
+                        hunk.lines.push(HunkLine::Add(line[1..].to_string()));
+                        new_len = new_len.saturating_sub(1);
+                    }
+                    Some('-') => {
+                        hunk.lines.push(HunkLine::Remove(line[1..].to_string()));
+                        old_len = old_len.saturating_sub(1);
+                    }
+                    Some('\\') => {}
+                    // Some tools drop the space of empty context lines.
+                    _ => {
+                        let text = line.strip_prefix(' ').unwrap_or(line);
+                        hunk.lines.push(HunkLine::Context(text.to_string()));
+                        old_len = old_len.saturating_sub(1);
+                        new_len = new_len.saturating_sub(1);
+                    }
+                }
+                i += 1;
+            }
+            if old_len > 0 || new_len > 0 {
+                bail!("hunk at line {} ends early", i);
+            }
+            // `\ No newline at end of file` after the new side.
+            if let Some(next) = lines.get(i)
+                && next.starts_with('\\')
+            {
+                hunk.no_newline = !matches!(hunk.lines.last(), Some(HunkLine::Remove(_)));
+                i += 1;
+            }
+            patch.hunks.push(hunk);
+            continue;
+        }
+        // Other lines, e.g. `diff --git` or comments, are ignored.
+        i += 1;
+    }
+    if patches.is_empty() {
+        bail!("no file changes found in the patch");
+    }
+    Ok(patches)
+}
+
+/// Maximum number of context lines ignored at each end of a hunk.
+const MAX_FUZZ: usize = 2;
+
+/// Where a hunk was applied.
+#[derive(Clone, Debug)]
+pub struct HunkResult {
+    /// 1-based line of the patched file, where the hunk starts.
+    pub line: usize,
+    /// Difference from the line in the hunk header.
+    pub offset: isize,
+    /// Number of context lines ignored at each end.
+    pub fuzz: usize,
+}
+
+/// Result of applying hunks to a file.
+#[derive(Clone, Debug)]
+pub struct Applied {
+    pub content: String,
+    pub hunks: Vec<HunkResult>,
+}
+
+impl Applied {
+    /// Describe hunks which were not applied exactly as in the header.
+    pub fn notes(&self) -> Vec<String> {
+        self.hunks
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| h.offset != 0 || h.fuzz != 0)
+            .map(|(idx, h)| {
+                let mut note = format!("hunk #{} applied at line {}", idx + 1, h.line);
+                if h.offset != 0 {
+                    note.push_str(&format!(" (offset {} lines)", h.offset));
+                }
+                if h.fuzz != 0 {
+                    note.push_str(&format!(" with fuzz {}", h.fuzz));
+                }
+                note
+            })
+            .collect()
+    }
+}
+
+/// Returns hunk lines without up to fuzz context lines at each end,
+/// with the number of lines dropped at the beginning.
+fn trim_context(lines: &[HunkLine], fuzz: usize) -> (&[HunkLine], usize) {
+    let is_context = |line: &&HunkLine| matches!(line, HunkLine::Context(_));
+    let lead = lines.iter().take_while(is_context).count().min(fuzz);
+    let rest = &lines[lead..];
+    let trail = rest.iter().rev().take_while(is_context).count().min(fuzz);
+    (&rest[..rest.len() - trail], lead)
+}
+
+fn side(lines: &[HunkLine], old: bool) -> Vec<&str> {
+    lines
+        .iter()
+        .filter_map(|line| match line {
+            HunkLine::Context(text) => Some(text.as_str()),
+            HunkLine::Remove(text) if old => Some(text.as_str()),
+            HunkLine::Add(text) if !old => Some(text.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Find the nearest position from expected where old lines match,
+/// not before min_start.
+fn find_position(lines: &[&str], old: &[&str], expected: usize, min_start: usize) -> Option<usize> {
+    let matches_at = |pos: usize| {
+        pos >= min_start && pos + old.len() <= lines.len() && lines[pos..pos + old.len()] == *old
+    };
+    (0..=lines.len()).find_map(|distance| {
+        let after = expected + distance;
+        if matches_at(after) {
+            return Some(after);
+        }
+        let before = expected.checked_sub(distance)?;
+        matches_at(before).then_some(before)
+    })
+}
+
+/// Explain why the hunk does not match at the expected line.
+fn mismatch(lines: &[&str], hunk: &Hunk, index: usize, expected: usize) -> anyhow::Error {
+    let old = hunk.old_lines();
+    let detail = old
+        .iter()
+        .enumerate()
+        .find_map(|(i, want)| match lines.get(expected + i) {
+            Some(got) if got == want => None,
+            Some(got) => Some(format!(
+                "line {} is `{}`, but `{}` is expected",
+                expected + i + 1,
+                got,
+                want
+            )),
+            None => Some(format!(
+                "the file ends at line {}, but `{}` is expected",
+                lines.len(),
+                want
+            )),
+        })
+        .unwrap_or_else(|| "it overlaps the previous hunk".to_string());
+    anyhow!(
+        "hunk #{} (at line {}) failed: {}. No match found with offsets or fuzz {}.",
+        index + 1,
+        hunk.old_start,
+        detail,
+        MAX_FUZZ
+    )
+}
+
+/// Split the content into lines without line endings,
+/// with whether each line ends with `\r\n`.
+/// A last line without a line ending gets the ending used by most lines.
+fn split_lines(content: &str) -> (Vec<&str>, Vec<bool>, bool) {
+    let mut lines = Vec::new();
+    let mut crs = Vec::new();
+    for piece in content.split_inclusive('\n') {
+        match piece.strip_suffix("\r\n") {
+            Some(line) => {
+                lines.push(line);
+                crs.push(true);
+            }
+            None => {
+                lines.push(piece.strip_suffix('\n').unwrap_or(piece));
+                crs.push(false);
+            }
+        }
+    }
+    let crlf = crs.iter().filter(|cr| **cr).count() * 2 > crs.len();
+    if !content.is_empty()
+        && !content.ends_with('\n')
+        && let Some(last) = crs.last_mut()
+    {
+        *last = crlf;
+    }
+    (lines, crs, crlf)
+}
+
+/// Apply hunks to the content, in order.
+/// A hunk may be applied at other lines (offset), and with context lines
+/// at its ends ignored (fuzz), as `patch` does. Fails if any hunk fails.
+/// Line endings of the content are kept, and added lines get the ones used by most lines.
+pub fn apply(content: &str, hunks: &[Hunk]) -> Result<Applied> {
+    let (mut lines, mut crs, crlf) = split_lines(content);
+    let mut newline_at_end = content.is_empty() || content.ends_with('\n');
+    // Lines added or removed by previous hunks.
+    let mut shift: isize = 0;
+    // Hunks do not overlap.
+    let mut min_start = 0;
+    let mut results = Vec::new();
+    for (idx, hunk) in hunks.iter().enumerate() {
+        let header_start = if hunk.old_lines().is_empty() {
+            hunk.old_start
+        } else {
+            hunk.old_start.saturating_sub(1)
+        };
+        let expected = (header_start as isize + shift).max(0) as usize;
+
+        let mut found = None;
+        for fuzz in 0..=MAX_FUZZ {
+            let (body, lead) = trim_context(&hunk.lines, fuzz);
+            if fuzz > 0 && body.len() == trim_context(&hunk.lines, fuzz - 1).0.len() {
+                break;
+            }
+            let old = side(body, true);
+            let pos = if old.is_empty() {
+                Some((expected + lead).clamp(min_start, lines.len()))
+            } else {
+                find_position(&lines, &old, expected + lead, min_start)
+            };
+            if let Some(pos) = pos {
+                found = Some((pos, fuzz, lead, old.len(), body));
+                break;
+            }
+        }
+        let Some((pos, fuzz, lead, old_len, body)) = found else {
+            return Err(mismatch(&lines, hunk, idx, expected));
+        };
+
+        let start = pos - lead.min(pos);
+        results.push(HunkResult {
+            line: start + 1,
+            offset: start as isize - expected as isize,
+            fuzz,
+        });
+        // Context lines keep their endings.
+        let mut old_idx = pos;
+        let new_crs: Vec<bool> = body
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(_) => {
+                    old_idx += 1;
+                    Some(crs[old_idx - 1])
+                }
+                HunkLine::Remove(_) => {
+                    old_idx += 1;
+                    None
+                }
+                HunkLine::Add(_) => Some(crlf),
+            })
+            .collect();
+        let new = side(body, false);
+        let at_end = pos + old_len == lines.len();
+        min_start = pos + new.len();
+        shift += new.len() as isize - old_len as isize + (start as isize - expected as isize);
+        lines.splice(pos..pos + old_len, new);
+        crs.splice(pos..pos + old_len, new_crs);
+        if at_end {
+            newline_at_end = !hunk.no_newline;
+        }
+    }
+    let mut content = String::with_capacity(content.len());
+    for (idx, (line, cr)) in lines.iter().zip(&crs).enumerate() {
+        content.push_str(line);
+        if idx + 1 < lines.len() || newline_at_end {
+            content.push_str(if *cr { "\r\n" } else { "\n" });
+        }
+    }
+    Ok(Applied {
+        content,
+        hunks: results,
+    })
+}
+
+/// Parse hunks of a single file. File headers may be omitted.
+pub fn parse_hunks(text: &str) -> Result<Vec<Hunk>> {
+    let has_header = text.lines().any(|line| line.starts_with("+++ "));
+    let mut patches = if has_header {
+        parse(text)?
+    } else {
+        parse(&format!("--- a\n+++ b\n{}", text))?
+    };
+    if patches.len() != 1 {
+        bail!(
+            "the patch changes {} files, but one is expected",
+            patches.len()
+        );
+    }
+    let patch = patches.remove(0);
+    if patch.hunks.is_empty() {
+        bail!("no hunks found in the patch");
+    }
+    Ok(patch.hunks)
+}
+
+/// Save the content as the latest backup of the file,
+/// i.e. `<backup_dir>/<path>.<timestamp>`.
+pub fn backup(backup_dir: &Path, path: &str, content: &str) -> Result<PathBuf> {
+    let base = backup_dir.join(format!("{}.{}", path, timestamp_id(now_unix())));
+    let mut target = base.clone();
+    let mut n = 1;
+    while target.exists() {
+        target = PathBuf::from(format!("{}-{}", base.display(), n));
+        n += 1;
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&target, content)?;
+    Ok(target)
+}
+
+/// Restore the file from its latest backup, and remove the backup.
+/// Returns the backup path.
+pub fn restore_latest(backup_dir: &Path, path: &str, target: &Path) -> Result<PathBuf> {
+    let full = backup_dir.join(path);
+    let dir = full.parent().unwrap_or(backup_dir);
+    let prefix = format!(
+        "{}.",
+        full.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    );
+    let latest = fs::read_dir(dir)
+        .ok()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|p| {
+            p.file_name().is_some_and(|name| {
+                let name = name.to_string_lossy();
+                // Backups of other files, e.g. `a.txt.orig` of `a.txt`, are excluded.
+                name.strip_prefix(&prefix)
+                    .is_some_and(|stamp| stamp.chars().all(|c| c.is_ascii_digit() || c == '-'))
+            })
+        })
+        .max_by_key(|p| backup_order(&p.to_string_lossy()))
+        .ok_or_else(|| anyhow!("no backup of {}", path))?;
+    fs::copy(&latest, target)?;
+    fs::remove_file(&latest)?;
+    Ok(latest)
+}
+
+/// Sort key of backups `<path>.<timestamp>[-n]`.
+fn backup_order(name: &str) -> (String, usize) {
+    let (stamp, n) = match name.rsplit_once('.') {
+        Some((_, suffix)) => match suffix.split('-').collect::<Vec<_>>()[..] {
+            [date, time, n] => (format!("{}-{}", date, time), n.parse().unwrap_or(0)),
+            _ => (suffix.to_string(), 0),
+        },
+        None => (name.to_string(), 0),
+    };
+    (stamp, n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patched(content: &str, diff: &str) -> Result<Applied> {
+        apply(content, &parse_hunks(diff)?)
+    }
+
+    #[test]
+    fn parses_files_and_hunks() {
+        let diff = "diff --git a/x b/x\n--- a/src/x.rs\t2024-01-01\n+++ b/src/x.rs\n\
+                    @@ -1,2 +1,2 @@\n a\n-b\n+B\n--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1 @@\n+n\n";
+        let patches = parse(diff).unwrap();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].path(), "src/x.rs");
+        assert_eq!(
+            patches[0].hunks[0].lines,
+            vec![
+                HunkLine::Context("a".to_string()),
+                HunkLine::Remove("b".to_string()),
+                HunkLine::Add("B".to_string()),
+            ]
+        );
+        assert_eq!(patches[1].old_path, None);
+        assert_eq!(patches[1].path(), "new.txt");
+        assert_eq!(patches[1].hunks[0].old_start, 0);
+        assert!(!patches[1].is_deletion());
+
+        assert!(parse("just text").is_err());
+        assert!(parse("--- a\n+++ b\n@@ -1,3 +1,3 @@\n a\n").is_err());
+        assert!(parse_hunks("--- a\n+++ a\n@@ -1 +1 @@\n-a\n+b\n--- b\n+++ b\n").is_err());
+    }
+
+    #[test]
+    fn applies_exactly() {
+        let applied = patched("a\nb\nc\n", "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n").unwrap();
+        assert_eq!(applied.content, "a\nB\nc\n");
+        assert!(applied.notes().is_empty());
+    }
+
+    #[test]
+    fn applies_with_offset() {
+        let content = "x\ny\na\nb\nc\n";
+        let applied = patched(content, "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n").unwrap();
+        assert_eq!(applied.content, "x\ny\na\nB\nc\n");
+        assert_eq!(applied.hunks[0].line, 3);
+        assert_eq!(applied.hunks[0].offset, 2);
+        assert_eq!(
+            applied.notes(),
+            vec!["hunk #1 applied at line 3 (offset 2 lines)"]
+        );
+    }
+
+    #[test]
+    fn applies_with_fuzz() {
+        let content = "a\nb\nc\nd\ne\n";
+        let diff = "@@ -1,5 +1,5 @@\n A\n b\n-c\n+C\n d\n E\n";
+        let applied = patched(content, diff).unwrap();
+        assert_eq!(applied.content, "a\nb\nC\nd\ne\n");
+        assert_eq!(applied.hunks[0].fuzz, 1);
+        assert_eq!(applied.hunks[0].line, 1);
+    }
+
+    #[test]
+    fn applies_hunks_in_order() {
+        let content = "1\n2\n3\n4\n5\n6\n7\n8\n";
+        let diff = "@@ -2 +2,2 @@\n-2\n+2a\n+2b\n@@ -7 +8 @@\n-7\n+7a\n";
+        let applied = patched(content, diff).unwrap();
+        assert_eq!(applied.content, "1\n2a\n2b\n3\n4\n5\n6\n7a\n8\n");
+        assert!(applied.notes().is_empty());
+    }
+
+    #[test]
+    fn handles_missing_newlines() {
+        let diff = "@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+B\n";
+        assert_eq!(patched("a\nb", diff).unwrap().content, "a\nB\n");
+        let diff = "@@ -1,2 +1,2 @@\n a\n-b\n+B\n\\ No newline at end of file\n";
+        assert_eq!(patched("a\nb\n", diff).unwrap().content, "a\nB");
+        let diff = "@@ -1 +1,2 @@\n a\n+b\n";
+        assert_eq!(patched("a\n", diff).unwrap().content, "a\nb\n");
+    }
+
+    #[test]
+    fn creates_files() {
+        let applied = patched("", "@@ -0,0 +1,2 @@\n+a\n+b\n").unwrap();
+        assert_eq!(applied.content, "a\nb\n");
+    }
+
+    #[test]
+    fn keeps_line_endings() {
+        let diff = "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+        assert_eq!(
+            patched("a\r\nb\r\nc\r\n", diff).unwrap().content,
+            "a\r\nB\r\nc\r\n"
+        );
+        // Diffs with CRLF apply to files with LF, too.
+        let diff = "@@ -1,3 +1,3 @@\r\n a\r\n-b\r\n+B\r\n c\r\n";
+        assert_eq!(patched("a\nb\nc\n", diff).unwrap().content, "a\nB\nc\n");
+        // Other lines of mixed files are kept.
+        let diff = "@@ -1,3 +1,4 @@\n a\n b\n+x\n c\n";
+        assert_eq!(
+            patched("a\r\nb\nc\r\n", diff).unwrap().content,
+            "a\r\nb\nx\r\nc\r\n"
+        );
+        let diff = "@@ -1 +1,2 @@\n a\n+b\n";
+        assert_eq!(patched("a\r\n", diff).unwrap().content, "a\r\nb\r\n");
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let err = patched("a\nx\nc\n", "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n").unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("hunk #1"), "{}", msg);
+        assert!(
+            msg.contains("line 2 is `x`, but `b` is expected"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn orders_backups() {
+        assert!(backup_order("a.txt.20240101-120000") < backup_order("a.txt.20240101-120001"));
+        assert!(backup_order("a.txt.20240101-120000-1") > backup_order("a.txt.20240101-120000"));
+        assert!(backup_order("a.txt.20240101-120000-10") > backup_order("a.txt.20240101-120000-9"));
+    }
+
+    #[test]
+    fn restores_latest_backups() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let backups = dir.join("backups");
+        let target = dir.join("a.txt");
+        let first = backup(&backups, "a.txt", "one").unwrap();
+        let second = backup(&backups, "a.txt", "two").unwrap();
+        assert_ne!(first, second);
+        backup(&backups, "a.txt.orig", "other").unwrap();
+
+        assert_eq!(restore_latest(&backups, "a.txt", &target).unwrap(), second);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "two");
+        assert_eq!(restore_latest(&backups, "a.txt", &target).unwrap(), first);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "one");
+        assert!(restore_latest(&backups, "a.txt", &target).is_err());
+    }
+}
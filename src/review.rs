@@ -0,0 +1,373 @@
+//! Review helpers for Lua scripts waiting for approval.
+
+use std::thread;
+use std::time::Duration;
+use std::{env, fs};
+
+use similar::TextDiff;
+use tokio::sync::oneshot;
+
+use crate::highlight::{Lexer, Token};
+use crate::lua::{LuaSetup, LuaVM};
+use crate::workspace::Workspace;
+
+/// Timeout of the dry run, which computes file changes.
+const PREVIEW_TIMEOUT_SEC: u64 = 2;
+
+/// The dry run is abandoned after this, as the timeout cannot stop blocking calls,
+/// e.g. reading a FIFO.
+const PREVIEW_CUTOFF: Duration = Duration::from_secs(4);
+
+/// Memory limit of the dry run.
+const PREVIEW_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Files larger than this are not diffed.
+const MAX_DIFF_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Maximum number of diff lines shown per file.
+const MAX_DIFF_LINES: usize = 200;
+
+/// A line containing a call which may be dangerous.
+#[derive(Clone, Debug)]
+pub struct RiskyLine {
+    /// 1-based line number.
+    pub line: usize,
+    pub reasons: Vec<&'static str>,
+}
+
+/// A file which the script would write, with the unified diff.
+#[derive(Clone, Debug)]
+pub struct FileChange {
+    pub path: String,
+    pub diff: String,
+}
+
+/// ScriptReview is extra information shown when a script is approved.
+#[derive(Clone, Debug, Default)]
+pub struct ScriptReview {
+    pub risky: Vec<RiskyLine>,
+    pub changes: Vec<FileChange>,
+}
+
+impl ScriptReview {
+    /// Review the script: find risky calls, and preview file writes
+    /// in a VM of the setup, restricted to its workspace.
+    pub async fn new(code: &str, setup: &LuaSetup) -> Self {
+        let risky = find_risky_lines(code);
+        let changes = if risky.iter().any(|r| r.reasons.contains(&WRITES_FILE)) {
+            preview_changes(code, setup).await
+        } else {
+            Vec::new()
+        };
+        Self { risky, changes }
+    }
+
+    /// Reasons why the line is risky, joined by commas.
+    pub fn risky_reason(&self, line: usize) -> Option<String> {
+        self.risky
+            .iter()
+            .find(|r| r.line == line)
+            .map(|r| r.reasons.join(", "))
+    }
+}
+
+const WRITES_FILE: &str = "writes a file";
+
+/// Patterns of risky calls, with the reason shown to the user.
+const RISKY_CALLS: [(&str, &str); 9] = [
+    ("io.popen", "runs a shell command"),
+    ("os.remove", "removes a file"),
+    ("os.rename", "moves a file"),
+    ("os.execute", "runs a shell command"),
+    ("io.output", "redirects output to a file"),
+    ("onui.patch", "patches a file"),
+    ("onui.unpatch", "restores a file from a backup"),
+    ("onui.http", "sends an HTTP request"),
+    ("onui.exec", "runs a shell command"),
+];
+
+fn find_risky_lines(code: &str) -> Vec<RiskyLine> {
+    let mut risky = Vec::new();
+    // Comments are found by the lexer of the highlighter, as shown to the user.
+    let mut lexer = Lexer::new("lua");
+    for (idx, line) in code.lines().enumerate() {
+        let line: String = lexer
+            .tokens(line)
+            .into_iter()
+            .filter(|(token, _)| *token != Token::Comment)
+            .map(|(_, text)| text)
+            .collect();
+        let line = line.as_str();
+
+        let mut reasons: Vec<&'static str> = Vec::new();
+        let found = RISKY_CALLS
+            .iter()
+            .filter(|(pattern, _)| line.contains(pattern))
+            .map(|(_, reason)| *reason)
+            .chain(opens_for_write(line).then_some(WRITES_FILE));
+        for reason in found {
+            if !reasons.contains(&reason) {
+                reasons.push(reason);
+            }
+        }
+        if !reasons.is_empty() {
+            risky.push(RiskyLine {
+                line: idx + 1,
+                reasons,
+            });
+        }
+    }
+    risky
+}
+
+/// Check `io.open(..., "w")` or other modes which modify files.
+fn opens_for_write(line: &str) -> bool {
+    let Some(start) = line.find("io.open(") else {
+        return false;
+    };
+    let args = &line[start + "io.open(".len()..];
+    args.split([',', ')'])
+        .skip(1)
+        .map(|arg| arg.trim().trim_matches(['"', '\'']))
+        .any(|mode| {
+            !mode.is_empty()
+                && mode.len() <= 3
+                && mode.chars().all(|c| "rwab+".contains(c))
+                && mode.contains(['w', 'a', '+'])
+        })
+}
+
+/// Returns the setup of the dry run: sandboxed, limited, and without tools.
+fn preview_setup(setup: &LuaSetup) -> LuaSetup {
+    LuaSetup {
+        init_script: None,
+        tools: Vec::new(),
+        tool_dir: None,
+        sandbox: true,
+        allow_popen: false,
+        memory_limit: Some(setup.memory_limit.map_or(PREVIEW_MEMORY_LIMIT, |limit| {
+            limit.min(PREVIEW_MEMORY_LIMIT)
+        })),
+        http_allowed_hosts: Vec::new(),
+        ..setup.clone()
+    }
+}
+
+/// Run the dry run in its own thread, not to block the agent.
+/// The thread is left behind if it does not finish before the cutoff.
+async fn preview_changes(code: &str, setup: &LuaSetup) -> Vec<FileChange> {
+    let code = code.to_string();
+    let setup = preview_setup(setup);
+    let (tx, rx) = oneshot::channel();
+    let spawned = thread::Builder::new()
+        .name("onui-preview".to_string())
+        .spawn(move || {
+            let _ = tx.send(compute_changes(&code, setup));
+        });
+    if spawned.is_err() {
+        return Vec::new();
+    }
+    match tokio::time::timeout(PREVIEW_CUTOFF, rx).await {
+        Ok(Ok(changes)) => changes,
+        _ => Vec::new(),
+    }
+}
+
+/// Read the current content of a written file, if it is a small regular file.
+fn read_old_content(workspace: &Workspace, path: &str) -> Option<String> {
+    let full = workspace.resolve(path).ok()?;
+    match fs::metadata(&full) {
+        Ok(meta) if meta.is_file() && meta.len() <= MAX_DIFF_FILE_BYTES => {
+            fs::read_to_string(&full).ok()
+        }
+        Ok(_) => None,
+        Err(_) => Some(String::new()),
+    }
+}
+
+fn compute_changes(code: &str, setup: LuaSetup) -> Vec<FileChange> {
+    let root = setup
+        .workspace
+        .clone()
+        .or_else(|| env::current_dir().ok())
+        .unwrap_or_default();
+    let workspace = Workspace::new(root).with_allowed_roots(&setup.allowed_roots);
+    let writes = match LuaVM::preview_writes(code, setup, PREVIEW_TIMEOUT_SEC) {
+        Ok(writes) => writes,
+        Err(_) => return Vec::new(),
+    };
+
+    writes
+        .into_iter()
+        .filter_map(|(path, new_content)| {
+            let old_content = read_old_content(&workspace, &path)?;
+            if old_content == new_content {
+                return None;
+            }
+            let diff = unified_diff(&old_content, &new_content, &path);
+            Some(FileChange {
+                path,
+                diff: truncate_lines(&diff, MAX_DIFF_LINES),
+            })
+        })
+        .collect()
+}
+
+/// Escape control characters in a line of model code, e.g. `\r` or ESC,
+/// which could hide parts of the line when shown in the terminal.
+/// Bidirectional overrides are escaped too, as they reorder the shown text.
+pub fn escape_controls(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    for c in line.chars() {
+        match c {
+            '\t' => out.push(c),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' => {
+                out.push_str(&format!("\\u{{{:x}}}", c as u32))
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Returns the unified diff between two texts of the same file.
+pub fn unified_diff(old: &str, new: &str, path: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string()
+}
+
+fn truncate_lines(text: &str, max_lines: usize) -> String {
+    let total = text.lines().count();
+    if total <= max_lines {
+        return text.to_string();
+    }
+    let mut out: String = text
+        .lines()
+        .take(max_lines)
+        .flat_map(|line| [line, "\n"])
+        .collect();
+    out.push_str(&format!("... ({} more lines)\n", total - max_lines));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::time::Instant;
+
+    fn setup(dir: &Path) -> LuaSetup {
+        LuaSetup {
+            workspace: Some(dir.to_path_buf()),
+            ..LuaSetup::default()
+        }
+    }
+
+    fn risky_lines(code: &str) -> Vec<(usize, String)> {
+        find_risky_lines(code)
+            .into_iter()
+            .map(|r| (r.line, r.reasons.join(", ")))
+            .collect()
+    }
+
+    #[test]
+    fn finds_risky_calls_outside_comments() {
+        let code = "-- os.remove('a')\nlocal s = '--' os.remove('important.txt')\n\
+                    print('x') -- io.popen('ls')\n--[[\nonui.exec('rm')\n]] onui.exec('ls')";
+        assert_eq!(
+            risky_lines(code),
+            vec![
+                (2, "removes a file".to_string()),
+                (6, "runs a shell command".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_all_reasons_of_a_line() {
+        let code = "os.remove('a') io.open('b', 'w') io.popen('ls') os.execute('ls')";
+        assert_eq!(
+            risky_lines(code),
+            vec![(
+                1,
+                "runs a shell command, removes a file, writes a file".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn finds_writing_modes() {
+        assert!(opens_for_write("io.open('a', 'w')"));
+        assert!(opens_for_write("io.open(\"a\", \"a+\")"));
+        assert!(opens_for_write("io.open(path, 'rb+')"));
+        assert!(!opens_for_write("io.open('a', 'rb')"));
+        assert!(!opens_for_write("io.open('a')"));
+    }
+
+    #[test]
+    fn escapes_control_characters() {
+        assert_eq!(escape_controls("a\tb"), "a\tb");
+        assert_eq!(escape_controls("safe()\rrm()"), "safe()\\rrm()");
+        assert_eq!(escape_controls("\x1b[2K"), "\\x1b[2K");
+        assert_eq!(escape_controls("a\u{202e}b"), "a\\u{202e}b");
+        assert_eq!(escape_controls("한글"), "한글");
+    }
+
+    #[test]
+    fn truncates_long_diffs() {
+        assert_eq!(truncate_lines("a\nb\n", 2), "a\nb\n");
+        assert_eq!(truncate_lines("a\nb\nc\n", 2), "a\nb\n... (1 more lines)\n");
+    }
+
+    #[tokio::test]
+    async fn previews_writes_in_the_workspace() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("a.txt"), "old\n").unwrap();
+        let code = "local f = io.open('a.txt', 'w')\nf:write('new\\n')\nf:close()";
+        let review = ScriptReview::new(code, &setup(dir)).await;
+        assert_eq!(review.risky_reason(1).as_deref(), Some(WRITES_FILE));
+        assert_eq!(review.changes.len(), 1);
+        assert!(review.changes[0].diff.contains("-old"));
+        assert!(review.changes[0].diff.contains("+new"));
+        // The file is not written by the preview.
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "old\n");
+    }
+
+    #[tokio::test]
+    async fn preview_is_sandboxed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let tmp_outside = tempfile::tempdir().unwrap();
+        let outside = tmp_outside.path();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        let code = format!(
+            "local s = io.open('{}'):read('*a')\nio.open('a.txt', 'w'):write(s)",
+            outside.join("secret.txt").display()
+        );
+        let review = ScriptReview::new(&code, &setup(dir)).await;
+        assert!(review.changes.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn blocking_preview_is_abandoned() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let status = std::process::Command::new("mkfifo")
+            .arg(dir.join("fifo"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let code = "local s = io.open('fifo'):read('*a')\nio.open('a.txt', 'w'):write(s)";
+        let start = Instant::now();
+        let review = ScriptReview::new(code, &setup(dir)).await;
+        assert!(start.elapsed() < PREVIEW_CUTOFF + Duration::from_secs(1));
+        assert!(review.changes.is_empty());
+    }
+}
@@ -0,0 +1,433 @@
+//! Sessions saved under `.onui/sessions/<id>.json`, to be resumed later.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::agent::PendingLua;
+use crate::utils::{now_unix, rfc3339, timestamp_id};
+
+/// Maximum length of the session title, in characters.
+const MAX_TITLE_LEN: usize = 60;
+
+const MAIN_BRANCH: &str = "main";
+
+fn main_branch() -> String {
+    MAIN_BRANCH.to_string()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+
+    /// Name of the LLM configuration, `[llm.<name>]`.
+    pub llm: String,
+    pub model: String,
+    /// First user message, shown in the session list.
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub token_used: usize,
+
+    /// Conversation history, in the format of the LLM client.
+    pub transcript: Value,
+
+    /// All Lua scripts which were approved or rejected.
+    #[serde(default)]
+    pub lua_log: Vec<PendingLua>,
+    /// Scripts determined, but whose results are not sent to the LLM yet.
+    #[serde(default)]
+    pub determined_lua: Vec<PendingLua>,
+    /// Scripts waiting for approval.
+    #[serde(default)]
+    pub pending_lua: Vec<PendingLua>,
+
+    /// Name of the current branch. Fields above are of this branch.
+    #[serde(default = "main_branch")]
+    pub branch: String,
+    /// Checkpoint which the current branch was forked from.
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// Branches other than the current one.
+    #[serde(default)]
+    pub branches: Vec<Branch>,
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+/// A branch of the conversation, which is not active.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Branch {
+    pub name: String,
+    pub parent: Option<String>,
+    pub transcript: Value,
+    pub token_used: usize,
+    pub lua_log: Vec<PendingLua>,
+    pub determined_lua: Vec<PendingLua>,
+    pub pending_lua: Vec<PendingLua>,
+}
+
+/// A point of the conversation to fork from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub name: String,
+    /// Branch where the checkpoint was made.
+    pub branch: String,
+    pub created_at: u64,
+    pub transcript: Value,
+    pub token_used: usize,
+    pub lua_log: Vec<PendingLua>,
+    /// Snapshot of Lua VM globals, as Lua source.
+    pub vm: Option<String>,
+}
+
+impl Session {
+    /// Create an empty session, with an id not used in the directory.
+    pub fn new(dir: &Path, llm: &str, model: &str) -> Self {
+        let now = now_unix();
+        let base = timestamp_id(now);
+        let mut id = base.clone();
+        let mut n = 1;
+        while dir.join(format!("{}.json", id)).exists() {
+            n += 1;
+            id = format!("{}-{}", base, n);
+        }
+        Self {
+            id,
+            created_at: now,
+            updated_at: now,
+            llm: llm.to_string(),
+            model: model.to_string(),
+            title: String::new(),
+            token_used: 0,
+            transcript: Value::Null,
+            lua_log: Vec::new(),
+            determined_lua: Vec::new(),
+            pending_lua: Vec::new(),
+            branch: main_branch(),
+            parent: None,
+            branches: Vec::new(),
+            checkpoints: Vec::new(),
+        }
+    }
+
+    /// Set the title from the first user message, if not set yet.
+    pub fn set_title(&mut self, msg: &str) {
+        if !self.title.is_empty() {
+            return;
+        }
+        let line = msg.lines().map(str::trim).find(|l| !l.is_empty());
+        let Some(line) = line else {
+            return;
+        };
+        self.title = if line.chars().count() > MAX_TITLE_LEN {
+            let cut: String = line.chars().take(MAX_TITLE_LEN - 3).collect();
+            format!("{}...", cut)
+        } else {
+            line.to_string()
+        };
+    }
+
+    /// Session is worth to be saved, only after the user said something
+    /// or made a checkpoint.
+    pub fn is_empty(&self) -> bool {
+        self.title.is_empty() && self.checkpoints.is_empty() && self.branches.is_empty()
+    }
+
+    /// Number of messages in the transcript.
+    pub fn message_count(&self) -> usize {
+        self.transcript.as_array().map_or(0, Vec::len)
+    }
+
+    /// Mark the current point of the conversation as a checkpoint.
+    pub fn add_checkpoint(&mut self, name: &str, vm: Option<String>) -> Result<()> {
+        if self.checkpoint(name).is_some() {
+            return Err(anyhow!("Checkpoint '{}' already exists", name));
+        }
+        self.checkpoints.push(Checkpoint {
+            name: name.to_string(),
+            branch: self.branch.clone(),
+            created_at: now_unix(),
+            transcript: self.transcript.clone(),
+            token_used: self.token_used,
+            lua_log: self.lua_log.clone(),
+            vm,
+        });
+        Ok(())
+    }
+
+    pub fn checkpoint(&self, name: &str) -> Option<&Checkpoint> {
+        self.checkpoints.iter().find(|c| c.name == name)
+    }
+
+    /// Keep the current branch, and start a new branch from the checkpoint.
+    /// Returns the name of the new branch.
+    pub fn fork(&mut self, checkpoint: &str) -> Result<String> {
+        let cp = self
+            .checkpoint(checkpoint)
+            .cloned()
+            .ok_or_else(|| anyhow!("No checkpoint '{}'", checkpoint))?;
+        let mut n = 1;
+        let name = loop {
+            let name = format!("{}-{}", cp.name, n);
+            if name != self.branch && !self.branches.iter().any(|b| b.name == name) {
+                break name;
+            }
+            n += 1;
+        };
+
+        self.stash_branch();
+        self.set_branch(Branch {
+            name: name.clone(),
+            parent: Some(cp.name),
+            transcript: cp.transcript,
+            token_used: cp.token_used,
+            lua_log: cp.lua_log,
+            determined_lua: Vec::new(),
+            pending_lua: Vec::new(),
+        });
+        Ok(name)
+    }
+
+    /// Keep the current branch, and switch to the other branch.
+    pub fn switch(&mut self, name: &str) -> Result<()> {
+        if name == self.branch {
+            return Err(anyhow!("Already on branch '{}'", name));
+        }
+        let index = self
+            .branches
+            .iter()
+            .position(|b| b.name == name)
+            .ok_or_else(|| anyhow!("No branch '{}'", name))?;
+        let branch = self.branches.remove(index);
+        self.stash_branch();
+        self.set_branch(branch);
+        Ok(())
+    }
+
+    fn stash_branch(&mut self) {
+        self.branches.push(Branch {
+            name: self.branch.clone(),
+            parent: self.parent.clone(),
+            transcript: self.transcript.take(),
+            token_used: self.token_used,
+            lua_log: std::mem::take(&mut self.lua_log),
+            determined_lua: std::mem::take(&mut self.determined_lua),
+            pending_lua: std::mem::take(&mut self.pending_lua),
+        });
+    }
+
+    fn set_branch(&mut self, branch: Branch) {
+        self.branch = branch.name;
+        self.parent = branch.parent;
+        self.transcript = branch.transcript;
+        self.token_used = branch.token_used;
+        self.lua_log = branch.lua_log;
+        self.determined_lua = branch.determined_lua;
+        self.pending_lua = branch.pending_lua;
+    }
+
+    /// Describe branches and checkpoints of the session.
+    pub fn tree(&self) -> String {
+        let count = |transcript: &Value| transcript.as_array().map_or(0, Vec::len);
+        let from = |parent: &Option<String>| match parent {
+            Some(parent) => format!(" from {}", parent),
+            None => String::new(),
+        };
+
+        let mut out = String::from("Branches:");
+        out.push_str(&format!(
+            "\n* {}{} ({} msgs)",
+            self.branch,
+            from(&self.parent),
+            self.message_count()
+        ));
+        for b in &self.branches {
+            out.push_str(&format!(
+                "\n  {}{} ({} msgs)",
+                b.name,
+                from(&b.parent),
+                count(&b.transcript)
+            ));
+        }
+
+        out.push_str("\nCheckpoints:");
+        if self.checkpoints.is_empty() {
+            out.push_str(" (none)");
+        }
+        for c in &self.checkpoints {
+            out.push_str(&format!(
+                "\n  {} on {} ({} msgs, {}{})",
+                c.name,
+                c.branch,
+                count(&c.transcript),
+                rfc3339(c.created_at),
+                if c.vm.is_some() { ", VM saved" } else { "" }
+            ));
+        }
+        out
+    }
+
+    pub fn save(&mut self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        self.updated_at = now_unix();
+
+        let path = session_path(dir, &self.id)?;
+        let content = serde_json::to_string_pretty(self)?;
+        // Write to a temporary file first, not to break the old one.
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, content)
+            .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Load the session by id, or the latest one if id is empty.
+    pub fn load(dir: &Path, id: &str) -> Result<Self> {
+        if id.is_empty() {
+            return list(dir)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("No saved sessions in {}", dir.display()));
+        }
+
+        let path = session_path(dir, id)?;
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("No session '{}' in {}", id, dir.display()))?;
+        serde_json::from_str(&content).with_context(|| format!("parse error: {}", path.display()))
+    }
+}
+
+/// Returns the directory of sessions in the workspace.
+pub fn sessions_dir(workspace: &Path) -> PathBuf {
+    workspace.join(".onui").join("sessions")
+}
+
+/// Session ids are used as file names, so only letters, digits, `-` and `_`
+/// are allowed, not to escape the directory.
+fn session_path(dir: &Path, id: &str) -> Result<PathBuf> {
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(anyhow!(
+            "Invalid session id '{}'. Use letters, digits, `-` and `_`.",
+            id
+        ));
+    }
+    Ok(dir.join(format!("{}.json", id)))
+}
+
+/// List saved sessions, the most recently updated first.
+/// Files which cannot be parsed are skipped.
+pub fn list(dir: &Path) -> Result<Vec<Session>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut sessions: Vec<Session> = fs::read_dir(dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .filter_map(|content| serde_json::from_str(&content).ok())
+        .collect();
+    sessions
+        .sort_by(|a: &Session, b: &Session| b.updated_at.cmp(&a.updated_at).then(b.id.cmp(&a.id)));
+    Ok(sessions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_and_loads_sessions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut session = Session::new(dir, "default", "gpt");
+        session.set_title("hello\nworld");
+        session.transcript = serde_json::json!([{ "role": "user", "content": "hello" }]);
+        session.token_used = 12;
+        session
+            .add_checkpoint("cp", Some("x = 1".to_string()))
+            .unwrap();
+        session.fork("cp").unwrap();
+        session.save(dir).unwrap();
+
+        let loaded = Session::load(dir, &session.id).unwrap();
+        assert_eq!(loaded.title, "hello");
+        assert_eq!(loaded.transcript, session.transcript);
+        assert_eq!(loaded.token_used, 12);
+        assert_eq!(loaded.branch, "cp-1");
+        assert_eq!(loaded.parent.as_deref(), Some("cp"));
+        assert_eq!(loaded.branches.len(), 1);
+        assert_eq!(loaded.branches[0].name, "main");
+        assert_eq!(
+            loaded.checkpoint("cp").unwrap().vm.as_deref(),
+            Some("x = 1")
+        );
+    }
+
+    #[test]
+    fn resumes_the_latest_session() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        assert!(Session::load(dir, "").is_err());
+
+        let mut old = Session::new(dir, "default", "gpt");
+        old.set_title("old");
+        old.save(dir).unwrap();
+        let mut new = Session::new(dir, "default", "gpt");
+        assert_ne!(new.id, old.id);
+        new.set_title("new");
+        new.save(dir).unwrap();
+        new.updated_at = old.updated_at + 1;
+        fs::write(
+            session_path(dir, &new.id).unwrap(),
+            serde_json::to_string(&new).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(Session::load(dir, "").unwrap().id, new.id);
+        assert_eq!(Session::load(dir, &old.id).unwrap().title, "old");
+        assert!(Session::load(dir, "missing").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_ids() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("sessions");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(tmp.path().join("outside.json"), "{}").unwrap();
+        for id in ["../outside", "a/b", "a.b", "/tmp/x", " "] {
+            let err = Session::load(&dir, id).unwrap_err();
+            assert!(err.to_string().contains("Invalid session id"), "{}", err);
+        }
+
+        let mut session = Session::new(&dir, "default", "gpt");
+        session.id = "../outside".to_string();
+        assert!(session.save(&dir).is_err());
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("outside.json")).unwrap(),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn keeps_checkpoints_before_the_first_message() {
+        let mut session = Session::new(Path::new("/nonexistent"), "default", "gpt");
+        assert!(session.is_empty());
+        session.add_checkpoint("start", None).unwrap();
+        assert!(!session.is_empty());
+    }
+}
@@ -0,0 +1,722 @@
+//! Native file-system tools, restricted to the workspace directory.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Result, anyhow, bail};
+use regex::Regex;
+use serde_json::{Value, json};
+
+use crate::llm::ToolSpec;
+use crate::patch;
+use crate::review::unified_diff;
+
+/// Names of the native tools.
+pub const TOOL_NAMES: [&str; 5] = [
+    "read_file",
+    "write_file",
+    "list_dir",
+    "search",
+    "apply_patch",
+];
+
+/// Tools which do not modify files, and run without approval.
+pub const READ_ONLY_TOOLS: [&str; 3] = ["read_file", "list_dir", "search"];
+
+/// Maximum size of a file read at once.
+const MAX_READ_BYTES: u64 = 256 * 1024;
+
+/// Files larger than this are not searched.
+const MAX_SEARCH_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Maximum number of entries walked by search.
+const MAX_SEARCH_ENTRIES: usize = 10_000;
+
+/// Maximum bytes of all files read by search.
+const MAX_SEARCH_TOTAL_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Maximum number of entries of list_dir.
+const MAX_LIST_ENTRIES: usize = 1000;
+
+/// Default maximum number of matches of search.
+const DEFAULT_MAX_RESULTS: usize = 100;
+
+/// Maximum length of a matched line shown by search.
+const MAX_MATCH_LINE_CHARS: usize = 200;
+
+/// Directories skipped when walking into subdirectories.
+/// They can still be given as the path explicitly.
+const SKIPPED_DIRS: [&str; 2] = [".git", ".onui"];
+
+pub fn is_tool(name: &str) -> bool {
+    TOOL_NAMES.contains(&name)
+}
+
+/// Returns specs of the native tools.
+pub fn tool_specs() -> Vec<ToolSpec> {
+    let spec = |name: &str, description: &str, parameters: Value| ToolSpec {
+        name: name.to_string(),
+        description: description.to_string(),
+        parameters,
+    };
+    vec![
+        spec(
+            "read_file",
+            "Read a text file in the workspace. Use start_line and end_line (1-based, inclusive) for large files.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Path relative to the workspace" },
+                    "start_line": { "type": "integer" },
+                    "end_line": { "type": "integer" }
+                },
+                "required": ["path"]
+            }),
+        ),
+        spec(
+            "write_file",
+            "Create or overwrite a file in the workspace with the content. Prefer apply_patch for small changes of existing files.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Path relative to the workspace" },
+                    "content": { "type": "string" }
+                },
+                "required": ["path", "content"]
+            }),
+        ),
+        spec(
+            "list_dir",
+            "List entries of a directory in the workspace. Directories end with '/'.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Path relative to the workspace, '.' by default" },
+                    "recursive": { "type": "boolean" }
+                }
+            }),
+        ),
+        spec(
+            "search",
+            "Search files in the workspace by a regex, returning 'path:line: text' for each matching line.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "pattern": { "type": "string", "description": "Rust regex syntax" },
+                    "path": { "type": "string", "description": "File or directory to search, '.' by default" },
+                    "max_results": { "type": "integer" }
+                },
+                "required": ["pattern"]
+            }),
+        ),
+        spec(
+            "apply_patch",
+            "Apply a unified diff (with '--- a/path' and '+++ b/path' headers) to files in the workspace.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "patch": { "type": "string" }
+                },
+                "required": ["patch"]
+            }),
+        ),
+    ]
+}
+
+/// Result of `Workspace::patch_file`. Paths are relative to the workspace.
+pub struct PatchReport {
+    pub path: String,
+    pub backup: Option<String>,
+    pub applied: patch::Applied,
+}
+
+/// Workspace is the directory which native tools may access.
+#[derive(Clone, Debug)]
+pub struct Workspace {
+    root: PathBuf,
+    /// Directories accessible besides the root.
+    allowed_roots: Vec<PathBuf>,
+}
+
+impl Workspace {
+    pub fn new(root: PathBuf) -> Self {
+        let root = root.canonicalize().unwrap_or(root);
+        Self {
+            root,
+            allowed_roots: Vec::new(),
+        }
+    }
+
+    /// Allow access to the directories, besides the workspace.
+    pub fn with_allowed_roots(mut self, roots: &[PathBuf]) -> Self {
+        self.allowed_roots = roots
+            .iter()
+            .map(|root| root.canonicalize().unwrap_or_else(|_| normalize(root)))
+            .collect();
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolve the path against the workspace.
+    /// Paths outside of the workspace and allowed roots, also through symlinks, are rejected.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let joined = self.root.join(path);
+        let normal = normalize(&joined);
+        // Broken symlinks are existing entries, which would be followed by writes.
+        let mut existing = normal.as_path();
+        while existing.symlink_metadata().is_err() {
+            existing = match existing.parent() {
+                Some(parent) => parent,
+                None => break,
+            };
+        }
+        let real = existing
+            .canonicalize()
+            .map_err(|err| anyhow!("{} cannot be resolved: {}", path, err))?;
+        let inside = |root: &PathBuf| normal.starts_with(root) && real.starts_with(root);
+        if !inside(&self.root) && !self.allowed_roots.iter().any(inside) {
+            if self.allowed_roots.is_empty() {
+                bail!("{} is outside of the workspace", path);
+            }
+            bail!("{} is outside of the workspace and allowed roots", path);
+        }
+        Ok(normal)
+    }
+
+    /// Returns the path relative to the workspace, for display.
+    fn relative(&self, path: &Path) -> String {
+        let rel = path.strip_prefix(&self.root).unwrap_or(path);
+        if rel.as_os_str().is_empty() {
+            ".".to_string()
+        } else {
+            rel.to_string_lossy().into_owned()
+        }
+    }
+
+    /// Call a native tool with the arguments, returning the text sent to the LLM.
+    pub fn call(&self, name: &str, args: &Value) -> Result<String> {
+        match name {
+            "read_file" => self.read_file(args),
+            "write_file" => self.write_file(args),
+            "list_dir" => self.list_dir(args),
+            "search" => self.search(args),
+            "apply_patch" => self.apply_patch(args),
+            _ => Err(anyhow!("Unknown tool '{}'", name)),
+        }
+    }
+
+    /// Returns the unified diff to review the call, instead of raw arguments, for writes.
+    /// Old files larger than `MAX_READ_BYTES` are not read, and diffed as empty.
+    pub fn describe(&self, name: &str, args: &Value) -> Option<String> {
+        match name {
+            "write_file" => {
+                let path = str_arg(args, "path").ok()?;
+                let content = str_arg(args, "content").ok()?;
+                let full = self.resolve(path).ok();
+                let size = full
+                    .as_ref()
+                    .and_then(|full| fs::metadata(full).ok())
+                    .map_or(0, |meta| meta.len());
+                if size > MAX_READ_BYTES {
+                    let note = format!(
+                        "Note: {} has {} bytes, too large to diff. It is replaced with:\n",
+                        path, size
+                    );
+                    return Some(note + &unified_diff("", content, path));
+                }
+                let old = full
+                    .and_then(|full| fs::read_to_string(full).ok())
+                    .unwrap_or_default();
+                Some(unified_diff(&old, content, path))
+            }
+            "apply_patch" => str_arg(args, "patch").ok().map(str::to_string),
+            _ => None,
+        }
+    }
+
+    fn read_file(&self, args: &Value) -> Result<String> {
+        let path = self.resolve(str_arg(args, "path")?)?;
+        let start_line = args.get("start_line").and_then(Value::as_u64);
+        let end_line = args.get("end_line").and_then(Value::as_u64);
+        let size = fs::metadata(&path)
+            .map_err(|err| anyhow!("{}: {}", self.relative(&path), err))?
+            .len();
+        if size > MAX_READ_BYTES && start_line.is_none() && end_line.is_none() {
+            bail!(
+                "{} has {} bytes, more than {}. Read a range with start_line and end_line.",
+                self.relative(&path),
+                size,
+                MAX_READ_BYTES
+            );
+        }
+        if start_line.is_none() && end_line.is_none() {
+            return fs::read_to_string(&path)
+                .map_err(|err| anyhow!("{}: {}", self.relative(&path), err));
+        }
+
+        let start = start_line.unwrap_or(1).max(1) as usize;
+        let end = end_line.map_or(usize::MAX, |end| end as usize);
+        let (lines, total) = read_line_range(&path, start, end)
+            .map_err(|err| anyhow!("{}: {}", self.relative(&path), err))?;
+        let end = end.min(total);
+        let shown = start + lines.len();
+        let mut out = format!("-- Lines {}-{} of {}\n", start, end, total);
+        for line in lines {
+            out.push_str(&line);
+            out.push('\n');
+        }
+        if shown <= end {
+            out.push_str(&format!(
+                "-- Stopped before line {} at {} bytes. Read the rest with start_line.\n",
+                shown, MAX_READ_BYTES
+            ));
+        }
+        Ok(out)
+    }
+
+    fn write_file(&self, args: &Value) -> Result<String> {
+        let path = self.resolve(str_arg(args, "path")?)?;
+        let content = str_arg(args, "content")?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, content).map_err(|err| anyhow!("{}: {}", self.relative(&path), err))?;
+        Ok(format!(
+            "Wrote {} bytes to {}",
+            content.len(),
+            self.relative(&path)
+        ))
+    }
+
+    fn list_dir(&self, args: &Value) -> Result<String> {
+        let path = self.resolve(args.get("path").and_then(Value::as_str).unwrap_or("."))?;
+        let recursive = args
+            .get("recursive")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let mut entries = Vec::new();
+        let complete = walk(&path, recursive, MAX_LIST_ENTRIES, &mut |entry, is_dir| {
+            let rel = entry.strip_prefix(&path).unwrap_or(entry);
+            let mut name = rel.to_string_lossy().into_owned();
+            if is_dir {
+                name.push('/');
+            }
+            entries.push(name);
+        })
+        .map_err(|err| anyhow!("{}: {}", self.relative(&path), err))?;
+        let mut out = entries.join("\n");
+        if !complete {
+            out.push_str(&format!("\n... (stopped at {} entries)", MAX_LIST_ENTRIES));
+        }
+        if out.is_empty() {
+            out.push_str("(empty directory)");
+        }
+        Ok(out)
+    }
+
+    fn search(&self, args: &Value) -> Result<String> {
+        let pattern = Regex::new(str_arg(args, "pattern")?)?;
+        let path = self.resolve(args.get("path").and_then(Value::as_str).unwrap_or("."))?;
+        let max_results = args
+            .get("max_results")
+            .and_then(Value::as_u64)
+            .map_or(DEFAULT_MAX_RESULTS, |n| n as usize);
+
+        let mut files = Vec::new();
+        let mut complete = true;
+        if path.is_file() {
+            files.push(path.clone());
+        } else {
+            complete = walk(&path, true, MAX_SEARCH_ENTRIES, &mut |entry, is_dir| {
+                if !is_dir {
+                    files.push(entry.to_path_buf());
+                }
+            })
+            .map_err(|err| anyhow!("{}: {}", self.relative(&path), err))?;
+        }
+
+        let mut matches = Vec::new();
+        let mut notes = Vec::new();
+        if !complete {
+            notes.push(format!(
+                "... (stopped walking at {} entries; search a subdirectory)",
+                MAX_SEARCH_ENTRIES
+            ));
+        }
+        let mut skipped = 0;
+        let mut read_bytes = 0;
+        'files: for file in files {
+            let size = fs::metadata(&file).map_or(u64::MAX, |m| m.len());
+            if size > MAX_SEARCH_FILE_BYTES {
+                skipped += 1;
+                continue;
+            }
+            if read_bytes + size > MAX_SEARCH_TOTAL_BYTES {
+                notes.push(format!(
+                    "... (stopped after reading {} bytes; search a subdirectory)",
+                    read_bytes
+                ));
+                break;
+            }
+            read_bytes += size;
+            // Binary or non UTF-8 files are skipped.
+            let content = match fs::read_to_string(&file) {
+                Ok(content) if !content.contains('\0') => content,
+                _ => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            for (idx, line) in content.lines().enumerate() {
+                if pattern.is_match(line) {
+                    if matches.len() >= max_results {
+                        matches.push(format!("... (stopped at {} matches)", max_results));
+                        break 'files;
+                    }
+                    let line = match line.char_indices().nth(MAX_MATCH_LINE_CHARS) {
+                        Some((end, _)) => format!("{}...", &line[..end]),
+                        None => line.to_string(),
+                    };
+                    matches.push(format!("{}:{}: {}", self.relative(&file), idx + 1, line));
+                }
+            }
+        }
+        if skipped > 0 {
+            notes.push(format!("... ({} large or binary files skipped)", skipped));
+        }
+        if matches.is_empty() {
+            matches.push("No matches.".to_string());
+        }
+        matches.extend(notes);
+        Ok(matches.join("\n"))
+    }
+
+    fn apply_patch(&self, args: &Value) -> Result<String> {
+        let patches = patch::parse(str_arg(args, "patch")?)?;
+
+        // All files are checked before writing any.
+        let mut changes = Vec::new();
+        for file in &patches {
+            let path = self.resolve(file.path())?;
+            let old = match file.old_path {
+                Some(_) => {
+                    fs::read_to_string(&path).map_err(|err| anyhow!("{}: {}", file.path(), err))?
+                }
+                None => String::new(),
+            };
+            let applied = patch::apply(&old, &file.hunks)
+                .map_err(|err| anyhow!("{}: {}", file.path(), err))?;
+            changes.push((path, file, old, applied));
+        }
+
+        let mut out = Vec::new();
+        for (path, file, old, applied) in changes {
+            let rel = self.relative(&path);
+            // Existing files are backed up, to be restored by `onui.unpatch`.
+            let backup = match file.old_path {
+                Some(_) => Some(patch::backup(&self.backup_dir(), &rel, &old)?),
+                None => None,
+            };
+            if file.is_deletion() {
+                fs::remove_file(&path)?;
+                out.push(format!("Deleted {}", rel));
+            } else {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, &applied.content)?;
+                out.push(format!("Patched {} ({} hunks)", rel, file.hunks.len()));
+            }
+            for note in applied.notes() {
+                out.push(format!("  {}", note));
+            }
+            if let Some(backup) = backup {
+                out.push(format!("  backup: {}", self.relative(&backup)));
+            }
+        }
+        Ok(out.join("\n"))
+    }
+
+    /// Directory of backups made by patches.
+    pub fn backup_dir(&self) -> PathBuf {
+        backup_dir(&self.root)
+    }
+
+    /// Apply a unified diff of a single file, backing up the old content.
+    /// The file is created if it does not exist.
+    pub fn patch_file(&self, path: &str, diff: &str) -> Result<PatchReport> {
+        let full = self.resolve(path)?;
+        let rel = self.relative(&full);
+        let old = if full.exists() {
+            Some(fs::read_to_string(&full).map_err(|err| anyhow!("{}: {}", rel, err))?)
+        } else {
+            None
+        };
+        let hunks = patch::parse_hunks(diff)?;
+        let applied = patch::apply(old.as_deref().unwrap_or_default(), &hunks)?;
+        let backup = match old {
+            Some(ref old) => {
+                let backup = patch::backup(&self.backup_dir(), &rel, old)?;
+                Some(self.relative(&backup))
+            }
+            None => None,
+        };
+        if let Some(parent) = full.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&full, &applied.content).map_err(|err| anyhow!("{}: {}", rel, err))?;
+        Ok(PatchReport {
+            path: rel,
+            backup,
+            applied,
+        })
+    }
+
+    /// Restore the file from the latest backup made by patches.
+    /// Returns the path of the backup, which is removed.
+    pub fn unpatch(&self, path: &str) -> Result<String> {
+        let full = self.resolve(path)?;
+        let rel = self.relative(&full);
+        let backup = patch::restore_latest(&self.backup_dir(), &rel, &full)?;
+        Ok(self.relative(&backup))
+    }
+}
+
+/// Returns the directory of backups made by patches, in the workspace.
+pub fn backup_dir(root: &Path) -> PathBuf {
+    root.join(".onui").join("backups")
+}
+
+fn str_arg<'a>(args: &'a Value, key: &str) -> Result<&'a str> {
+    args.get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("the `{}` argument is required", key))
+}
+
+/// Remove `.` and `..` components without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Visit entries of the directory sorted by name, with whether it is a directory.
+/// Returns false if stopped at the limit.
+/// Read lines from start to end (1-based, inclusive), up to `MAX_READ_BYTES`,
+/// streaming the file. Returns the lines and the total number of lines.
+fn read_line_range(path: &Path, start: usize, end: usize) -> std::io::Result<(Vec<String>, usize)> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut lines = Vec::new();
+    let mut budget = MAX_READ_BYTES as usize;
+    let mut full = false;
+    let mut total = 0;
+    loop {
+        let line_no = total + 1;
+        // Lines not returned are skipped without being kept in memory.
+        if line_no < start || line_no > end || full {
+            if reader.skip_until(b'\n')? == 0 {
+                break;
+            }
+            total += 1;
+            continue;
+        }
+        let mut buf = Vec::new();
+        let read = (&mut reader)
+            .take(budget as u64 + 1)
+            .read_until(b'\n', &mut buf)?;
+        if read == 0 {
+            break;
+        }
+        total += 1;
+        if read > budget {
+            full = true;
+            if !buf.ends_with(b"\n") {
+                reader.skip_until(b'\n')?;
+            }
+            continue;
+        }
+        budget -= read;
+        let text = String::from_utf8(buf).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("line {} is not valid UTF-8", line_no),
+            )
+        })?;
+        let text = text.strip_suffix('\n').unwrap_or(&text);
+        lines.push(text.strip_suffix('\r').unwrap_or(text).to_string());
+    }
+    Ok((lines, total))
+}
+
+fn walk(
+    dir: &Path,
+    recursive: bool,
+    limit: usize,
+    visit: &mut dyn FnMut(&Path, bool),
+) -> std::io::Result<bool> {
+    let mut count = 0;
+    let mut stack = vec![dir.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let mut entries: Vec<_> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect();
+        entries.sort();
+        let mut subdirs = Vec::new();
+        for entry in entries {
+            if count >= limit {
+                return Ok(false);
+            }
+            // Symlinks are not followed, to stay in the workspace.
+            let is_dir = entry.symlink_metadata().is_ok_and(|m| m.is_dir());
+            let skipped = entry
+                .file_name()
+                .is_some_and(|name| SKIPPED_DIRS.iter().any(|skip| name == *skip));
+            if is_dir && skipped {
+                continue;
+            }
+            visit(&entry, is_dir);
+            count += 1;
+            if is_dir && recursive {
+                subdirs.push(entry);
+            }
+        }
+        stack.extend(subdirs.into_iter().rev());
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Returns the directory, removed when the guard is dropped, and its real path.
+    fn temp_dir() -> (TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn resolves_paths_inside() {
+        let (_root_guard, root) = temp_dir();
+        let ws = Workspace::new(root.clone());
+        assert_eq!(ws.resolve("a.txt").unwrap(), root.join("a.txt"));
+        assert_eq!(ws.resolve("./b/../a.txt").unwrap(), root.join("a.txt"));
+        assert_eq!(
+            ws.resolve("new/dir/c.txt").unwrap(),
+            root.join("new/dir/c.txt")
+        );
+        assert_eq!(ws.resolve(".").unwrap(), root);
+        let absolute = root.join("a.txt").display().to_string();
+        assert_eq!(ws.resolve(&absolute).unwrap(), root.join("a.txt"));
+    }
+
+    #[test]
+    fn rejects_paths_outside() {
+        let (_root_guard, root) = temp_dir();
+        let ws = Workspace::new(root.clone());
+        assert!(ws.resolve("../x.txt").is_err());
+        assert!(ws.resolve("a/../../x.txt").is_err());
+        assert!(ws.resolve("/etc/passwd").is_err());
+        let sibling = format!("{}-sibling/x", root.display());
+        assert!(ws.resolve(&sibling).is_err());
+    }
+
+    #[test]
+    fn allows_allowed_roots() {
+        let (_root_guard, root) = temp_dir();
+        let (_other_guard, other) = temp_dir();
+        let ws = Workspace::new(root.clone()).with_allowed_roots(std::slice::from_ref(&other));
+        let path = other.join("x.txt").display().to_string();
+        assert_eq!(ws.resolve(&path).unwrap(), other.join("x.txt"));
+        assert!(ws.resolve("/etc/passwd").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_outside() {
+        use std::os::unix::fs::symlink;
+        let (_root_guard, root) = temp_dir();
+        let (_outside_guard, outside) = temp_dir();
+        symlink(&outside, root.join("link")).unwrap();
+        symlink(outside.join("missing.txt"), root.join("dangling")).unwrap();
+        symlink(root.join("a.txt"), root.join("local")).unwrap();
+        let ws = Workspace::new(root.clone());
+        assert!(ws.resolve("link").is_err());
+        assert!(ws.resolve("link/new.txt").is_err());
+        assert!(ws.resolve("dangling").is_err());
+        fs::write(root.join("a.txt"), "a").unwrap();
+        assert!(ws.resolve("local").is_ok());
+    }
+
+    #[test]
+    fn describes_writes_as_diffs() {
+        let (_dir_guard, dir) = temp_dir();
+        let ws = Workspace::new(dir.clone());
+        fs::write(dir.join("a.txt"), "one\ntwo\n").unwrap();
+        let args = serde_json::json!({ "path": "a.txt", "content": "one\nthree\n" });
+        let diff = ws.describe("write_file", &args).unwrap();
+        assert!(diff.contains("-two\n+three\n"), "{}", diff);
+
+        fs::write(dir.join("big.txt"), "x".repeat(MAX_READ_BYTES as usize + 1)).unwrap();
+        let args = serde_json::json!({ "path": "big.txt", "content": "small\n" });
+        let diff = ws.describe("write_file", &args).unwrap();
+        assert!(diff.starts_with("Note: big.txt has"), "{}", diff);
+        assert!(diff.contains("+small\n"), "{}", diff);
+        assert!(ws.describe("read_file", &args).is_none());
+    }
+
+    #[test]
+    fn reads_line_ranges() {
+        let (_dir_guard, dir) = temp_dir();
+        let ws = Workspace::new(dir.clone());
+        fs::write(dir.join("a.txt"), "one\r\ntwo\nthree\nfour").unwrap();
+        let read = |args: Value| ws.call("read_file", &args).unwrap();
+
+        let out = read(json!({ "path": "a.txt", "start_line": 2, "end_line": 3 }));
+        assert_eq!(out, "-- Lines 2-3 of 4\ntwo\nthree\n");
+        let out = read(json!({ "path": "a.txt", "start_line": 4 }));
+        assert_eq!(out, "-- Lines 4-4 of 4\nfour\n");
+        let out = read(json!({ "path": "a.txt", "end_line": 1 }));
+        assert_eq!(out, "-- Lines 1-1 of 4\none\n");
+
+        let line = "x".repeat(1000);
+        let big = vec![line.as_str(); 1000].join("\n");
+        fs::write(dir.join("big.txt"), &big).unwrap();
+        assert!(ws.call("read_file", &json!({ "path": "big.txt" })).is_err());
+        let out = read(json!({ "path": "big.txt", "start_line": 1, "end_line": 1000 }));
+        assert!(out.starts_with("-- Lines 1-1000 of 1000\n"));
+        assert!(
+            out.ends_with("Read the rest with start_line.\n"),
+            "{}",
+            &out[out.len() - 100..]
+        );
+        assert!(out.len() < MAX_READ_BYTES as usize + 200);
+    }
+
+    #[test]
+    fn search_skips_binary_files() {
+        let (_dir_guard, dir) = temp_dir();
+        let ws = Workspace::new(dir.clone());
+        fs::write(dir.join("a.txt"), "hello\nworld\n").unwrap();
+        fs::write(dir.join("b.bin"), b"hello\0world").unwrap();
+        let out = ws.call("search", &json!({ "pattern": "hel+o" })).unwrap();
+        assert_eq!(out, "a.txt:1: hello\n... (1 large or binary files skipped)");
+        let out = ws.call("search", &json!({ "pattern": "nothing" })).unwrap();
+        assert!(out.starts_with("No matches.\n"), "{}", out);
+    }
+}